@@ -0,0 +1,221 @@
+use crate::map_3D::Map3D;
+use crate::render::resources::MONO_BIT_LEVELS;
+
+use super::bit_voxels::BitVoxels;
+
+const CHUNK_LENGTH: usize = 32;
+
+// CPU copy of the mono bit map mip chain of a single 32^3 chunk.
+// Level 0 matches fill_bit_volume.comp and every level after matches halve_bit_volume.comp,
+// texels are laid out like the R8Uint texture (x, then y, then z).
+// Bit n of a texel is the child at (n & 1, (n >> 1) & 1, (n >> 2) & 1)
+#[derive(Clone)]
+pub struct BitPyramid
+{
+    levels : Vec<Vec<u8>>
+}
+
+#[allow(dead_code)]
+impl BitPyramid
+{
+    // a voxel is present when its index is not u16::MAX
+    pub fn from_map(map : &Map3D<u16>)
+        -> BitPyramid
+    {
+        assert!(map.dims() == [CHUNK_LENGTH ; 3], "bit pyramids are built from 32^3 chunks");
+
+        let base_length = BitPyramid::level_length(0);
+        let mut base = vec![0u8 ; base_length.pow(3)];
+
+        for (i, texel) in base.iter_mut().enumerate()
+        {
+            let invoc = BitPyramid::texel_coords(i, base_length);
+            for bit in 0..8
+            {
+                let child = BitPyramid::child_coords(invoc, bit);
+                if map.get(child) != Some(u16::MAX)
+                {
+                    *texel |= 1 << bit;
+                }
+            }
+        }
+
+        BitPyramid::from_base(base)
+    }
+
+    // a 32^3 BitVoxels already shares the level 0 layout
+    pub fn from_bit_voxels(bit_voxels : &BitVoxels)
+        -> BitPyramid
+    {
+        assert!(bit_voxels.dims() == [CHUNK_LENGTH ; 3], "bit pyramids are built from 32^3 chunks");
+
+        BitPyramid::from_base(bit_voxels.data.clone())
+    }
+
+    fn from_base(base : Vec<u8>)
+        -> BitPyramid
+    {
+        let mut levels = vec![base];
+
+        for lod in 1..(MONO_BIT_LEVELS as usize)
+        {
+            let source = &levels[lod - 1];
+            let source_length = BitPyramid::level_length(lod - 1);
+            let length = BitPyramid::level_length(lod);
+
+            let halved = 
+                (0..length.pow(3))
+                .map(|i| {
+                    let invoc = BitPyramid::texel_coords(i, length);
+                    (0..8)
+                        .filter(|&bit| {
+                            let child = BitPyramid::child_coords(invoc, bit);
+                            source[BitPyramid::texel_index(child, source_length)] != 0
+                        })
+                        .fold(0u8, |value, bit| value | (1 << bit))
+                })
+                .collect();
+
+            levels.push(halved);
+        }
+
+        BitPyramid {levels}
+    }
+
+    // texels along one axis of a level
+    pub fn level_length(lod : usize)
+        -> usize
+    {
+        (CHUNK_LENGTH / 2) >> lod
+    }
+
+    // raw R8Uint texels of a level
+    pub fn level(&self, lod : usize)
+        -> &[u8]
+    {
+        &self.levels[lod]
+    }
+
+    pub fn texel(&self, lod : usize, coords : [usize ; 3])
+        -> u8
+    {
+        self.levels[lod][BitPyramid::texel_index(coords, BitPyramid::level_length(lod))]
+    }
+
+    // same as checkVoxel in primary_march.comp
+    // true when any voxel is present in the aligned 2^lod cube holding coords
+    pub fn check_voxel(&self, coords : [i32 ; 3], lod : usize)
+        -> bool
+    {
+        if coords.iter().any(|&c| c < 0 || c >= CHUNK_LENGTH as i32)
+        {
+            return false;
+        }
+
+        let lod_coords = [coords[0] >> lod, coords[1] >> lod, coords[2] >> lod];
+        let bit_index = 
+            (lod_coords[0] & 1) 
+            | ((lod_coords[1] & 1) << 1) 
+            | ((lod_coords[2] & 1) << 2);
+        let texel = self.texel(lod, 
+            [(lod_coords[0] >> 1) as usize, (lod_coords[1] >> 1) as usize, (lod_coords[2] >> 1) as usize]);
+
+        (texel >> bit_index) & 1 == 1
+    }
+
+    pub fn is_empty(&self)
+        -> bool
+    {
+        self.levels[self.levels.len() - 1].iter().all(|&texel| texel == 0)
+    }
+
+
+    fn texel_index(coords : [usize ; 3], length : usize)
+        -> usize
+    {
+        coords[0] + coords[1] * length + coords[2] * length * length
+    }
+
+    fn texel_coords(index : usize, length : usize)
+        -> [usize ; 3]
+    {
+        [index % length, (index / length) % length, index / (length * length)]
+    }
+
+    // posFromIndex in the bit volume shaders
+    fn child_coords(invoc : [usize ; 3], bit : usize)
+        -> [usize ; 3]
+    {
+        [
+            invoc[0] * 2 + (bit & 1),
+            invoc[1] * 2 + ((bit >> 1) & 1),
+            invoc[2] * 2 + ((bit >> 2) & 1),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::collections::HashSet;
+
+    use super::*;
+
+    // corners, neighbours across texel and level boundaries, and a lone voxel in the middle
+    const PRESENT : [[usize ; 3] ; 7] = [
+        [0, 0, 0], [31, 31, 31], [1, 0, 0], [2, 0, 0], [15, 16, 15], [7, 8, 23], [31, 0, 16],
+    ];
+
+    fn pyramids()
+        -> [BitPyramid ; 2]
+    {
+        let mut map = Map3D::new_with_default([CHUNK_LENGTH ; 3], u16::MAX);
+        let mut bit_voxels = BitVoxels::empty([CHUNK_LENGTH ; 3]);
+        for (i, &coords) in PRESENT.iter().enumerate()
+        {
+            map.set(coords, i as u16).unwrap();
+            bit_voxels.set_voxel(coords, true);
+        }
+        [BitPyramid::from_map(&map), BitPyramid::from_bit_voxels(&bit_voxels)]
+    }
+
+    #[test]
+    fn levels_hold_the_cubes_of_present_voxels()
+    {
+        for pyramid in pyramids().iter()
+        {
+            for lod in 0..MONO_BIT_LEVELS as usize
+            {
+                let occupied : HashSet<[usize ; 3]> = 
+                    PRESENT.iter().map(|c| [c[0] >> lod, c[1] >> lod, c[2] >> lod]).collect();
+
+                let bit_count : u32 = pyramid.level(lod).iter().map(|texel| texel.count_ones()).sum();
+                assert_eq!(bit_count as usize, occupied.len(), "lod {}", lod);
+                assert_eq!(pyramid.level(lod).len(), BitPyramid::level_length(lod).pow(3));
+
+                for x in 0..CHUNK_LENGTH { for y in 0..CHUNK_LENGTH { for z in 0..CHUNK_LENGTH {
+                    let expected = occupied.contains(&[x >> lod, y >> lod, z >> lod]);
+                    assert_eq!(pyramid.check_voxel([x as i32, y as i32, z as i32], lod), expected, 
+                        "{:?} at lod {}", [x, y, z], lod);
+                }}}
+            }
+
+            assert!(!pyramid.is_empty());
+            assert!(!pyramid.check_voxel([-1, 0, 0], 0));
+            assert!(!pyramid.check_voxel([0, 32, 0], 0));
+        }
+    }
+
+    #[test]
+    fn map_and_bit_voxels_build_the_same_levels()
+    {
+        let [from_map, from_bit_voxels] = pyramids();
+        for lod in 0..MONO_BIT_LEVELS as usize
+        {
+            assert_eq!(from_map.level(lod), from_bit_voxels.level(lod), "lod {}", lod);
+        }
+
+        let empty = BitPyramid::from_map(&Map3D::new_with_default([CHUNK_LENGTH ; 3], u16::MAX));
+        assert!(empty.is_empty());
+    }
+}
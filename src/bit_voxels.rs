@@ -5,24 +5,23 @@ pub struct BitVoxels
     pub data : Vec<u8>
 }
 
-use std::ops::{BitAnd, BitAndAssign, BitOrAssign};
+use std::ops::{BitAnd, BitAndAssign, BitOrAssign, BitXor, Not};
 // use dot_vox as dv;
 use super::dot_vox_wrapper::DotVoxWrapper;
+use crate::map_3D::Map3D;
 
+// bits of a byte in the near half along each axis, laid out as in bit_pos_from_bool_coords
+const NEAR_HALF_BITS : [u8 ; 3] = [0b0101_0101, 0b0011_0011, 0b0000_1111];
 
 // Data dims represents the extent to which a coordinate can be converted to an index for the data vec
 // data_dims = (5, 6, 3) => max_coords = (4, 5, 2)
+#[allow(dead_code)]
 impl BitVoxels
 {
     pub fn new(vox_data : &DotVoxWrapper, model_index : usize)
         -> BitVoxels
     {
-        let dims = vox_data.dims(model_index);
-        let data_dims = [(dims[0] + 1) / 2, (dims[1] + 1) / 2, (dims[2] + 1) / 2];
-        
-        let data = vec![0 ; data_dims[0] * data_dims[1] * data_dims[2]];
-
-        let mut b_voxels = BitVoxels {dims, data};
+        let mut b_voxels = BitVoxels::empty(vox_data.dims(model_index));
 
 
         for vox in vox_data.voxel_slice(model_index)
@@ -56,6 +55,33 @@ impl BitVoxels
             .bitand(1 << BitVoxels::bit_pos_from_coords(coords)) != 0
     }
 
+    // a voxel is present wherever the palette index is not u16::MAX
+    pub fn from_palette_volume(palette_volume : &Map3D<u16>)
+        -> BitVoxels
+    {
+        let mut b_voxels = BitVoxels::empty(palette_volume.dims());
+
+        for (coords, index) in palette_volume.iter()
+        {
+            if index != u16::MAX
+            {
+                b_voxels.set_voxel(coords, true);
+            }
+        }
+
+        b_voxels
+    }
+
+    // no voxels present
+    pub fn empty(dims : [usize ; 3])
+        -> BitVoxels
+    {
+        let data_dims = BitVoxels::data_dims(dims);
+        let data = vec![0 ; data_dims[0] * data_dims[1] * data_dims[2]];
+
+        BitVoxels {dims, data}
+    }
+
     pub fn dims(&self)
         -> [usize ; 3]
     {
@@ -63,10 +89,198 @@ impl BitVoxels
     }
 
 
+    // Byte-wise set algebra, both operands must share dims
+    pub fn union(&self, other : &BitVoxels)
+        -> BitVoxels
+    {
+        let mut result = self.clone();
+        result.union_with(other);
+        result
+    }
+
+    pub fn intersection(&self, other : &BitVoxels)
+        -> BitVoxels
+    {
+        let mut result = self.clone();
+        result.intersect_with(other);
+        result
+    }
+
+    // voxels present in self but not in other
+    pub fn difference(&self, other : &BitVoxels)
+        -> BitVoxels
+    {
+        let mut result = self.clone();
+        result.difference_with(other);
+        result
+    }
+
+    pub fn xor(&self, other : &BitVoxels)
+        -> BitVoxels
+    {
+        let mut result = self.clone();
+        result.xor_with(other);
+        result
+    }
+
+    pub fn union_with(&mut self, other : &BitVoxels)
+    {
+        self.combine_with(other, |a, b| a | b);
+    }
+
+    pub fn intersect_with(&mut self, other : &BitVoxels)
+    {
+        self.combine_with(other, |a, b| a.bitand(b));
+    }
+
+    pub fn difference_with(&mut self, other : &BitVoxels)
+    {
+        self.combine_with(other, |a, b| a.bitand(b.not()));
+    }
+
+    pub fn xor_with(&mut self, other : &BitVoxels)
+    {
+        self.combine_with(other, |a, b| a.bitxor(b));
+    }
+
+    fn combine_with(&mut self, other : &BitVoxels, op : impl Fn(u8, u8) -> u8)
+    {
+        assert!(self.dims == other.dims, "BitVoxels must share dims to be combined!");
+
+        self.data
+            .iter_mut()
+            .zip(other.data.iter())
+            .for_each(|(a, &b)| *a = op(*a, b));
+    }
+
+
+    // number of voxels present
+    pub fn count(&self)
+        -> usize
+    {
+        self.valid_bytes()
+            .map(|(byte, _)| byte.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn is_empty(&self)
+        -> bool
+    {
+        self.valid_bytes().all(|(byte, _)| byte == 0)
+    }
+
+    // every voxel within dims is present
+    pub fn is_full(&self)
+        -> bool
+    {
+        self.valid_bytes().all(|(byte, valid)| byte == valid)
+    }
+
+    // tight bounds of the present voxels as (inclusive min, exclusive max)
+    // None when no voxel is present
+    pub fn bounds(&self)
+        -> Option<([usize ; 3], [usize ; 3])>
+    {
+        let mut min = [usize::MAX ; 3];
+        let mut max = [0 ; 3];
+
+        for (i, (byte, _)) in self.valid_bytes().enumerate()
+        {
+            if byte == 0
+            {
+                continue;
+            }
+
+            let byte_coords = self.byte_coords(i);
+            for bit in 0..8
+            {
+                if byte & (1 << bit) == 0
+                {
+                    continue;
+                }
+                let coords = BitVoxels::coords_from_byte_bit(byte_coords, bit);
+                for axis in 0..3
+                {
+                    min[axis] = min[axis].min(coords[axis]);
+                    max[axis] = max[axis].max(coords[axis] + 1);
+                }
+            }
+        }
+
+        if min[0] == usize::MAX
+        {
+            None
+        }
+        else
+        {
+            Some((min, max))
+        }
+    }
+
+
+    fn data_dims(dims : [usize ; 3])
+        -> [usize ; 3]
+    {
+        [(dims[0] + 1) / 2, (dims[1] + 1) / 2, (dims[2] + 1) / 2]
+    }
+
+    // coordinates of a byte in the data vec (each byte covers 2x2x2 voxels)
+    fn byte_coords(&self, index : usize)
+        -> [usize ; 3]
+    {
+        let data_dims = BitVoxels::data_dims(self.dims);
+
+        [
+            index % data_dims[0],
+            (index / data_dims[0]) % data_dims[1],
+            index / (data_dims[0] * data_dims[1]),
+        ]
+    }
+
+    fn coords_from_byte_bit(byte_coords : [usize ; 3], bit : usize)
+        -> [usize ; 3]
+    {
+        [
+            byte_coords[0] * 2 + (bit & 1),
+            byte_coords[1] * 2 + ((bit >> 1) & 1),
+            byte_coords[2] * 2 + ((bit >> 2) & 1),
+        ]
+    }
+
+    // mask of the bits of a byte that lie within dims
+    // only bytes on the far edges of odd dims have invalid bits, the far half along those axes
+    fn valid_bits(&self, index : usize)
+        -> u8
+    {
+        let byte_coords = self.byte_coords(index);
+        let data_dims = BitVoxels::data_dims(self.dims);
+
+        (0..3)
+            .filter(|&axis| self.dims[axis] % 2 == 1 && byte_coords[axis] + 1 == data_dims[axis])
+            .fold(u8::MAX, |mask, axis| mask & NEAR_HALF_BITS[axis])
+    }
+
+    // each byte with the bits outside dims cleared, along with its valid bits
+    // bytes are whole when every dim is even, so their masks are skipped
+    fn valid_bytes(&self)
+        -> impl Iterator<Item = (u8, u8)> + '_
+    {
+        let whole = self.dims.iter().all(|d| d % 2 == 0);
+        self.data
+            .iter()
+            .enumerate()
+            .map(move |(i, &byte)| 
+            {
+                let valid = if whole { u8::MAX } else { self.valid_bits(i) };
+                (byte & valid, valid)
+            })
+    }
+
+
     fn index_from_coords(coords : [usize ; 3], dims : [usize ; 3])
         -> usize
     {
-        let data_dims = [(dims[0] + 1) / 2, (dims[1] + 1) / 2];
+        let data_dims = BitVoxels::data_dims(dims);
 
         (coords[0] / 2)
         + (coords[1] / 2) * data_dims[0]
@@ -86,3 +300,114 @@ impl BitVoxels
             [(coords[0] % 2) != 0, (coords[1] % 2) != 0, (coords[2] % 2) != 0])
     }
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::BitVoxels;
+
+    // every byte fully set, bits outside dims included
+    fn all_bits_set(dims : [usize ; 3])
+        -> BitVoxels
+    {
+        let mut bit_voxels = BitVoxels::empty(dims);
+        bit_voxels.data.iter_mut().for_each(|byte| *byte = u8::MAX);
+        bit_voxels
+    }
+
+    #[test]
+    fn bits_outside_dims_are_ignored()
+    {
+        for &dims in &[[32, 32, 32], [3, 5, 4], [1, 1, 1], [7, 2, 9], [4, 4, 3]]
+        {
+            let volume = dims[0] * dims[1] * dims[2];
+
+            let full = all_bits_set(dims);
+            assert_eq!(full.count(), volume, "{:?}", dims);
+            assert!(full.is_full());
+            assert!(!full.is_empty());
+            assert_eq!(full.bounds(), Some(([0 ; 3], dims)));
+
+            // only bits outside dims are left set
+            let mut corner = all_bits_set(dims);
+            for x in 0..dims[0] { for y in 0..dims[1] { for z in 0..dims[2] {
+                corner.set_voxel([x, y, z], false);
+            }}}
+            assert_eq!(corner.count(), 0, "{:?}", dims);
+            assert!(corner.is_empty());
+            assert_eq!(corner.bounds(), None);
+
+            let last = [dims[0] - 1, dims[1] - 1, dims[2] - 1];
+            corner.set_voxel(last, true);
+            assert_eq!(corner.count(), 1);
+            assert_eq!(corner.is_full(), volume == 1);
+            assert_eq!(corner.bounds(), Some((last, dims)));
+        }
+    }
+
+    fn from_fn(dims : [usize ; 3], present : impl Fn([usize ; 3]) -> bool)
+        -> BitVoxels
+    {
+        let mut bit_voxels = BitVoxels::empty(dims);
+        for x in 0..dims[0] { for y in 0..dims[1] { for z in 0..dims[2] {
+            bit_voxels.set_voxel([x, y, z], present([x, y, z]));
+        }}}
+        bit_voxels
+    }
+
+    // two overlapping slabs of a non cubic volume, the op's result checked voxel by voxel
+    fn check_op(
+        op : impl Fn(&BitVoxels, &BitVoxels) -> BitVoxels,
+        expected : impl Fn(bool, bool) -> bool,
+        count : usize)
+    {
+        let dims = [3, 5, 2];
+        let low_x = |c : [usize ; 3]| c[0] < 2;
+        let low_y = |c : [usize ; 3]| c[1] < 2;
+        let result = op(&from_fn(dims, low_x), &from_fn(dims, low_y));
+
+        assert_eq!(result.dims(), dims);
+        assert_eq!(result.count(), count);
+        for x in 0..dims[0] { for y in 0..dims[1] { for z in 0..dims[2] {
+            let c = [x, y, z];
+            assert_eq!(result.get_voxel(c), expected(low_x(c), low_y(c)), "{:?}", c);
+        }}}
+    }
+
+    // 20 voxels have x < 2, 12 have y < 2 and 8 have both, out of 30
+
+    #[test]
+    fn union_holds_either()
+    {
+        check_op(|a, b| a.union(b), |a, b| a || b, 24);
+        check_op(|a, b| { let mut a = a.clone(); a.union_with(b); a }, |a, b| a || b, 24);
+    }
+
+    #[test]
+    fn intersection_holds_both()
+    {
+        check_op(|a, b| a.intersection(b), |a, b| a && b, 8);
+        check_op(|a, b| { let mut a = a.clone(); a.intersect_with(b); a }, |a, b| a && b, 8);
+    }
+
+    #[test]
+    fn difference_holds_the_first_only()
+    {
+        check_op(|a, b| a.difference(b), |a, b| a && !b, 12);
+        check_op(|a, b| b.difference(a), |a, b| b && !a, 4);
+    }
+
+    #[test]
+    fn xor_holds_exactly_one()
+    {
+        check_op(|a, b| a.xor(b), |a, b| a != b, 16);
+        check_op(|a, b| { let mut a = a.clone(); a.xor_with(b); a }, |a, b| a != b, 16);
+    }
+
+    #[test]
+    #[should_panic(expected = "share dims")]
+    fn combining_different_dims_panics()
+    {
+        BitVoxels::empty([3, 5, 2]).union(&BitVoxels::empty([5, 3, 2]));
+    }
+}
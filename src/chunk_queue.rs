@@ -0,0 +1,313 @@
+use nalgebra as na;
+
+type VectorInt = na::Vector3<i32>;
+type Priority = Box<dyn Fn(&ChunkJob) -> i32 + Send>;
+
+// A chunk waiting to be generated, lower priorities are taken first
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkJob {
+    pub priority: i32,
+    pub partition_id: usize,
+    pub partition_coords: VectorInt,
+    // epoch of the partition id when the job was made
+    pub epoch: u64,
+}
+
+impl ChunkJob {
+    // ties go to the lower partition id so the order is total
+    fn key(&self)
+    -> (i32, usize) {
+        (self.priority, self.partition_id)
+    }
+}
+
+// Min heap of jobs with at most one job per partition id.
+// Knowing where each partition id sits lets a job be replaced or removed in O(log n).
+// Reprioritizing only swaps how jobs are scored, jobs scored before it are rescored
+// as they reach the top, so each costs O(log n) once per reprioritization.
+// Until then a job keeps its old place, which leaves the order approximate right after
+pub struct ChunkQueue {
+    heap: Vec<ChunkJob>,
+    // heap index of each partition id's job
+    positions: Vec<Option<usize>>,
+    // the reprioritization each partition id's job was last scored under
+    scored: Vec<u64>,
+    // bumped by every reprioritization
+    generation: u64,
+    // scores jobs from before the latest reprioritization, None until the first
+    priority: Option<Priority>,
+}
+
+impl ChunkQueue {
+    pub fn new(partition_count: usize)
+    -> ChunkQueue {
+        ChunkQueue {
+            heap: Vec::new(),
+            positions: vec![None ; partition_count],
+            scored: vec![0 ; partition_count],
+            generation: 0,
+            priority: None,
+        }
+    }
+
+    // Adds the job, replacing any job already queued for its partition id.
+    // Its priority must come from the latest reprioritization's scoring
+    pub fn push(&mut self, job: ChunkJob) {
+        self.scored[job.partition_id] = self.generation;
+        match self.positions[job.partition_id] {
+            Some(index) => {
+                self.heap[index] = job;
+                self.restore(index);
+            },
+            None => {
+                self.heap.push(job);
+                let index = self.heap.len() - 1;
+                self.positions[job.partition_id] = Some(index);
+                self.sift_up(index);
+            },
+        }
+    }
+
+    pub fn pop(&mut self)
+    -> Option<ChunkJob> {
+        // every pass rescores one stale job, so this ends once the top is current
+        while let Some(&top) = self.heap.first() {
+            if self.scored[top.partition_id] == self.generation {
+                return Some(self.remove_at(0));
+            }
+            let priority = self.priority.as_ref().expect("stale jobs come from a reprioritization");
+            self.heap[0].priority = priority(&top);
+            self.scored[top.partition_id] = self.generation;
+            self.sift_down(0);
+        }
+        None
+    }
+
+    pub fn remove(&mut self, partition_id: usize)
+    -> Option<ChunkJob> {
+        let index = self.positions[partition_id]?;
+        Some(self.remove_at(index))
+    }
+
+    // Queued jobs take their priorities from this as they come up, O(1).
+    // For when whatever the priorities are measured from has moved
+    pub fn reprioritize(&mut self, priority: impl Fn(&ChunkJob) -> i32 + Send + 'static) {
+        self.generation += 1;
+        self.priority = Some(Box::new(priority));
+    }
+
+    fn remove_at(&mut self, index: usize)
+    -> ChunkJob {
+        let last = self.heap.len() - 1;
+        self.swap(index, last);
+        let job = self.heap.pop().unwrap();
+        self.positions[job.partition_id] = None;
+        if index < self.heap.len() {
+            self.restore(index);
+        }
+        job
+    }
+
+    // moves the job at index to wherever its priority puts it
+    fn restore(&mut self, index: usize) {
+        if index > 0 && self.heap[index].key() < self.heap[(index - 1) / 2].key() {
+            self.sift_up(index);
+        } else {
+            self.sift_down(index);
+        }
+    }
+
+    fn sift_up(&mut self, mut index: usize) {
+        while index > 0 {
+            let parent = (index - 1) / 2;
+            if self.heap[index].key() >= self.heap[parent].key() {
+                break;
+            }
+            self.swap(index, parent);
+            index = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut index: usize) {
+        loop {
+            let (left, right) = (2 * index + 1, 2 * index + 2);
+            let mut smallest = index;
+            if left < self.heap.len() && self.heap[left].key() < self.heap[smallest].key() {
+                smallest = left;
+            }
+            if right < self.heap.len() && self.heap[right].key() < self.heap[smallest].key() {
+                smallest = right;
+            }
+            if smallest == index {
+                break;
+            }
+            self.swap(index, smallest);
+            index = smallest;
+        }
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a, b);
+        self.positions[self.heap[a].partition_id] = Some(a);
+        self.positions[self.heap[b].partition_id] = Some(b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{ChunkJob, ChunkQueue, VectorInt};
+
+    const PARTITION_COUNT: usize = 64;
+
+    fn job(partition_id: usize, priority: i32)
+    -> ChunkJob {
+        ChunkJob { priority, partition_id, partition_coords: VectorInt::new(partition_id as i32, 0, 0), epoch: 0 }
+    }
+
+    // positions must point at each queued job and at nothing else, and parents come first
+    fn check(queue: &ChunkQueue) {
+        for (index, job) in queue.heap.iter().enumerate() {
+            assert_eq!(queue.positions[job.partition_id], Some(index));
+            if index > 0 {
+                assert!(queue.heap[(index - 1) / 2].key() <= job.key());
+            }
+        }
+        assert_eq!(queue.positions.iter().filter(|p| p.is_some()).count(), queue.heap.len());
+    }
+
+    // A plain model of the queue, each job with whether it was scored under the latest reprioritization
+    struct Reference {
+        queued: HashMap<usize, (ChunkJob, bool)>,
+    }
+
+    impl Reference {
+        // what the queue should pop, stale jobs are rescored as they come to the front
+        fn pop(&mut self, priority: &dyn Fn(&ChunkJob) -> i32)
+        -> Option<ChunkJob> {
+            loop {
+                let (&partition_id, &(job, current)) = self.queued.iter().min_by_key(|(_, (job, _))| job.key())?;
+                if current {
+                    self.queued.remove(&partition_id);
+                    return Some(job);
+                }
+                self.queued.insert(partition_id, (ChunkJob { priority: priority(&job), ..job }, true));
+            }
+        }
+    }
+
+    // priorities that shuffle the ids differently for each shift
+    fn scoring(shift: i32)
+    -> impl Fn(&ChunkJob) -> i32 + Send + 'static {
+        move |job: &ChunkJob| (job.partition_id as i32 * 7 + shift).rem_euclid(23)
+    }
+
+    // xorshift, so the sequence is the same every run
+    fn next(state: &mut u64)
+    -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+
+    #[test]
+    fn pops_in_priority_order() {
+        let mut queue = ChunkQueue::new(PARTITION_COUNT);
+        for (partition_id, priority) in [(3, 5), (1, 5), (7, -2), (2, 9), (5, 0)].iter().copied() {
+            queue.push(job(partition_id, priority));
+            check(&queue);
+        }
+
+        let mut popped = Vec::new();
+        while let Some(job) = queue.pop() {
+            check(&queue);
+            popped.push(job.partition_id);
+        }
+        // ties go to the lower id
+        assert_eq!(popped, vec![7, 5, 1, 3, 2]);
+    }
+
+    #[test]
+    fn replacing_push_moves_the_job() {
+        let mut queue = ChunkQueue::new(PARTITION_COUNT);
+        for partition_id in 0..8 {
+            queue.push(job(partition_id, partition_id as i32 * 10));
+        }
+        queue.push(job(6, -1));
+        check(&queue);
+        queue.push(job(0, 100));
+        check(&queue);
+
+        assert_eq!(queue.heap.len(), 8);
+        let popped: Vec<_> = std::iter::from_fn(|| queue.pop()).map(|job| job.partition_id).collect();
+        assert_eq!(popped, vec![6, 1, 2, 3, 4, 5, 7, 0]);
+    }
+
+    #[test]
+    fn reprioritize_rescores_jobs_as_they_come_up() {
+        let mut queue = ChunkQueue::new(PARTITION_COUNT);
+        for partition_id in 0..8 {
+            queue.push(job(partition_id, partition_id as i32));
+        }
+        // nothing moves until a pop
+        let heap_before = queue.heap.clone();
+        queue.reprioritize(|job| if job.partition_id == 0 { 100 } else { job.priority });
+        assert_eq!(queue.heap, heap_before);
+
+        // the front is rescored until a job scored under the new priorities is on top
+        assert_eq!(queue.pop().map(|job| (job.partition_id, job.priority)), Some((1, 1)));
+        assert_eq!(queue.heap[queue.positions[0].unwrap()].priority, 100);
+        check(&queue);
+
+        // pushes are taken as scored under the new priorities
+        queue.push(job(9, -20));
+        assert_eq!(queue.pop().map(|job| job.partition_id), Some(9));
+        check(&queue);
+
+        let popped: Vec<_> = std::iter::from_fn(|| queue.pop()).map(|job| job.partition_id).collect();
+        assert_eq!(popped, vec![2, 3, 4, 5, 6, 7, 0]);
+    }
+
+    #[test]
+    fn mixed_operations_match_a_reference() {
+        let mut queue = ChunkQueue::new(PARTITION_COUNT);
+        let mut reference = Reference { queued: HashMap::new() };
+        let mut shift = 0;
+        let mut state = 0x2545_f491_4f6c_dd1d;
+
+        for step in 0..4000 {
+            let partition_id = (next(&mut state) % PARTITION_COUNT as u64) as usize;
+            let priority = (next(&mut state) % 50) as i32 - 10;
+            match next(&mut state) % 10 {
+                // plain pushes and replacing ones, depending on whether the id is queued
+                0..=3 => {
+                    queue.push(job(partition_id, priority));
+                    reference.queued.insert(partition_id, (job(partition_id, priority), true));
+                },
+                4..=5 => {
+                    let expected = reference.queued.remove(&partition_id).map(|(job, _)| job);
+                    assert_eq!(queue.remove(partition_id), expected, "step {}", step);
+                },
+                6..=8 => {
+                    assert_eq!(queue.pop(), reference.pop(&scoring(shift)), "step {}", step);
+                },
+                _ => {
+                    shift = priority;
+                    queue.reprioritize(scoring(shift));
+                    for (_, current) in reference.queued.values_mut() {
+                        *current = false;
+                    }
+                },
+            }
+            check(&queue);
+        }
+
+        while let Some(expected) = reference.pop(&scoring(shift)) {
+            assert_eq!(queue.pop(), Some(expected));
+            check(&queue);
+        }
+        assert_eq!(queue.pop(), None);
+    }
+}
@@ -0,0 +1,200 @@
+use std::sync::atomic::{self, AtomicU64};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+
+use crate::chunk_queue::{ChunkJob, ChunkQueue};
+use crate::displaced_chunks::ChunkData;
+use crate::world_generator::{GenerateContext, WorldGenerator};
+
+pub struct GeneratedChunk<T> {
+    pub partition_id: usize,
+    pub epoch: u64,
+    pub data: T,
+}
+
+struct JobQueue {
+    state: Mutex<QueueState>,
+    // signalled when jobs are added or the workers should stop
+    available: Condvar,
+}
+
+struct QueueState {
+    jobs: ChunkQueue,
+    shut_down: bool,
+}
+
+// Generates chunks on background threads.
+// Every partition id has an epoch, bumped when the id is given other coords,
+// jobs and results from an older epoch are dropped instead of generated or returned
+pub struct ChunkWorkers<T: ChunkData> {
+    queue: Arc<JobQueue>,
+    epochs: Arc<Vec<AtomicU64>>,
+    results: mpsc::Receiver<GeneratedChunk<T>>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+impl<T: ChunkData> ChunkWorkers<T> {
+    pub fn new(partition_count: usize, thread_count: usize, generator: Arc<dyn WorldGenerator>, generate_context: Arc<GenerateContext>)
+    -> ChunkWorkers<T> {
+        let queue = Arc::new(JobQueue {
+            state: Mutex::new(QueueState { jobs: ChunkQueue::new(partition_count), shut_down: false }),
+            available: Condvar::new(),
+        });
+        let epochs: Arc<Vec<AtomicU64>> = Arc::new((0..partition_count).map(|_| AtomicU64::new(0)).collect());
+        let (sender, results) = mpsc::channel();
+
+        let threads =
+            (0..thread_count.max(1))
+            .map(|i| {
+                let (queue, epochs, sender) = (queue.clone(), epochs.clone(), sender.clone());
+                let (generator, generate_context) = (generator.clone(), generate_context.clone());
+                thread::Builder::new()
+                    .name(format!("chunk worker {}", i))
+                    .spawn(move || work(&queue, &epochs, &sender, generator.as_ref(), &generate_context))
+                    .expect("failed to start chunk worker")
+            })
+            .collect();
+
+        ChunkWorkers { queue, epochs, results, threads }
+    }
+
+    pub fn epoch(&self, partition_id: usize)
+    -> u64 {
+        self.epochs[partition_id].load(atomic::Ordering::Acquire)
+    }
+
+    // drops queued and running work for the partition id
+    pub fn cancel(&self, partition_id: usize) {
+        self.queue.state.lock().unwrap().jobs.remove(partition_id);
+        self.epochs[partition_id].fetch_add(1, atomic::Ordering::AcqRel);
+    }
+
+    // queues the job, replacing any queued for its partition id
+    pub fn push(&self, job: ChunkJob) {
+        self.queue.state.lock().unwrap().jobs.push(job);
+        self.queue.available.notify_one();
+    }
+
+    // queued jobs take their priorities from this as they come up, jobs already running are left alone
+    pub fn reprioritize(&self, priority: impl Fn(&ChunkJob) -> i32 + Send + 'static) {
+        self.queue.state.lock().unwrap().jobs.reprioritize(priority);
+    }
+
+    // a finished chunk of the current epoch, None when none is waiting
+    pub fn try_recv(&self)
+    -> Option<GeneratedChunk<T>> {
+        loop {
+            let generated = self.results.try_recv().ok()?;
+            if generated.epoch == self.epoch(generated.partition_id) {
+                return Some(generated);
+            }
+        }
+    }
+}
+
+impl<T: ChunkData> Drop for ChunkWorkers<T> {
+    fn drop(&mut self) {
+        self.queue.state.lock().unwrap().shut_down = true;
+        self.queue.available.notify_all();
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn work<T: ChunkData>(queue: &JobQueue, epochs: &[AtomicU64], sender: &mpsc::Sender<GeneratedChunk<T>>,
+    generator: &dyn WorldGenerator, generate_context: &GenerateContext)
+{
+    loop {
+        let job = {
+            let mut state = queue.state.lock().unwrap();
+            loop {
+                if state.shut_down {
+                    return;
+                }
+                if let Some(job) = state.jobs.pop() {
+                    break job;
+                }
+                state = queue.available.wait(state).unwrap();
+            }
+        };
+
+        // reassigned since it was queued
+        if epochs[job.partition_id].load(atomic::Ordering::Acquire) != job.epoch {
+            continue;
+        }
+
+        let mut data = T::allocate();
+        data.initialize(job.partition_coords, generator, generate_context);
+        let generated = GeneratedChunk { partition_id: job.partition_id, epoch: job.epoch, data };
+        if sender.send(generated).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use nalgebra as na;
+
+    use super::ChunkWorkers;
+    use crate::chunk_queue::ChunkJob;
+    use crate::map_3D::Map3D;
+    use crate::prefab_registry::PrefabRegistry;
+    use crate::world_generator::{GenerateContext, WorldGenerator};
+
+    // Says when it starts a chunk, then holds it until let go,
+    // so a test can act while a chunk is being generated
+    struct Gate {
+        started: Mutex<mpsc::Sender<()>>,
+        release: Mutex<mpsc::Receiver<()>>,
+    }
+
+    impl WorldGenerator for Gate {
+        fn generate(&self, partition_coords: na::Vector3<i32>, _context: &GenerateContext, partition: &mut Map3D<u16>) {
+            self.started.lock().unwrap().send(()).unwrap();
+            self.release.lock().unwrap().recv().unwrap();
+            partition.set_all(&|_| partition_coords.x as u16);
+        }
+    }
+
+    fn job(workers: &ChunkWorkers<Map3D<u16>>, partition_id: usize, x: i32)
+    -> ChunkJob {
+        ChunkJob { priority: 0, partition_id, partition_coords: na::Vector3::new(x, 0, 0), epoch: workers.epoch(partition_id) }
+    }
+
+    #[test]
+    fn cancelled_chunk_is_never_received() {
+        let (started_sender, started) = mpsc::channel();
+        let (release, release_receiver) = mpsc::channel();
+        let gate = Gate { started: Mutex::new(started_sender), release: Mutex::new(release_receiver) };
+        let context = GenerateContext::new(&PrefabRegistry::placeholder_only(), 0);
+        let workers = ChunkWorkers::<Map3D<u16>>::new(1, 1, Arc::new(gate), Arc::new(context));
+
+        workers.push(job(&workers, 0, 1));
+        started.recv_timeout(Duration::from_secs(10)).unwrap();
+
+        // reassigned while its old coords are being generated
+        workers.cancel(0);
+        workers.push(job(&workers, 0, 2));
+        release.send(()).unwrap();
+        started.recv_timeout(Duration::from_secs(10)).unwrap();
+        release.send(()).unwrap();
+
+        // the one worker sends in order, so the stale chunk went out before this one
+        let start = Instant::now();
+        let generated = loop {
+            if let Some(generated) = workers.try_recv() {
+                break generated;
+            }
+            assert!(start.elapsed() < Duration::from_secs(10), "the new chunk never arrived");
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert_eq!(generated.epoch, workers.epoch(0));
+        assert_eq!(generated.data.get([0 ; 3]), Some(2));
+        assert!(workers.try_recv().is_none());
+    }
+}
@@ -0,0 +1,334 @@
+// CPU port of the traversal in primary_march.comp.
+// dda_march walks the partitions of the layer index map,
+// plane_march then mip-skips through the partition and prefab bit pyramids.
+// Constants, epsilons and iteration limits follow the shader so results can be compared against it
+
+use nalgebra as na;
+
+type Vec3 = na::Vector3<f32>;
+type IVec3 = na::Vector3<i32>;
+
+const MAX_MIP_LEVEL: i32 = 3;
+
+// anything the marcher reads, keyed the same way as the textures
+pub trait MarchSource {
+    // chunk id stored in the layer index map at the displacement from the view partition
+    fn layer_chunk_id(&self, displacement: [i32 ; 3]) -> u16;
+    // checkVoxel on the bit pyramid of a chunk
+    fn check_voxel(&self, chunk_id: u32, coords: [i32 ; 3], lod: usize) -> bool;
+    // value of the index map of a chunk
+    fn chunk_index(&self, chunk_id: u32, coords: [i32 ; 3]) -> u16;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MarchResult {
+    pub hit: bool,
+    pub normal: Vec3,
+    // cube coordinates relative to the view partition, 32 * 32 cubes per partition
+    pub cube_coords: IVec3,
+}
+
+impl MarchResult {
+    fn invalid() 
+    -> Self {
+        Self {
+            hit: false,
+            normal: Vec3::zeros(),
+            cube_coords: IVec3::zeros(),
+        }
+    }
+}
+
+struct DirContext {
+    dir: Vec3,
+    inv_dir: Vec3,
+    corner: Vec3,
+}
+
+// splits a position in prefab units relative to the view partition
+// the same way as main() in primary_march.comp
+pub fn split_position(pos: Vec3) 
+-> (Vec3, IVec3) {
+    (
+        pos.map(|v| glsl_mod(v, 32.0)),
+        pos.map(|v| (v / 32.0).floor() as i32),
+    )
+}
+
+pub fn dda_march(source: &dyn MarchSource, dir: Vec3, pos_in_partition: Vec3, displacement_coords: IVec3) 
+-> MarchResult {
+    let init_map_pos = displacement_coords;
+    let mut map_pos = init_map_pos;
+
+    let dist_step = dir.map(|d| (dir.norm() / d).abs());
+    let ray_sign = dir.map(|d| glsl_sign(d) as i32);
+    let sign_dir = dir.map(glsl_sign);
+
+    let init_dists = 
+        (sign_dir.component_mul(&-(pos_in_partition / 32.0)) + sign_dir * 0.5 + Vec3::repeat(0.5))
+        .component_mul(&dist_step);
+
+    let mut dists = Vec3::zeros();
+    let mut mask = [false ; 3];
+
+    let dir_context = DirContext {
+        dir,
+        inv_dir: dir.map(|d| 1.0 / d),
+        corner: dir.map(|d| glsl_step(0.0, d)),
+    };
+
+    for _ in 0..200 {
+        if map_pos.x < -21 || map_pos.y < -7 || map_pos.z < -21
+            || map_pos.x > 21 || map_pos.y > 7 || map_pos.z > 21 {
+            break;
+        }
+
+        let chunk_index = source.layer_chunk_id(map_pos.into());
+        if chunk_index != u16::MAX {
+            let current_dist = dists.x.min(dists.y).min(dists.z);
+
+            let chunk_pos = pos_in_partition + 32.0 * ((displacement_coords - map_pos).cast::<f32>() + current_dist * dir);
+            let towards_chunk_middle = (Vec3::repeat(16.0) - chunk_pos).normalize();
+            let adjusted_chunk_pos = chunk_pos + towards_chunk_middle * 0.00001;
+
+            let result = plane_march(source, adjusted_chunk_pos, chunk_index as u32, &mut mask, &dir_context);
+            if result.hit {
+                return MarchResult {
+                    hit: true,
+                    normal: result.normal,
+                    cube_coords: result.cube_coords + 32 * 32 * map_pos,
+                };
+            }
+        }
+
+        dists = init_dists + (map_pos - init_map_pos).abs().cast::<f32>().component_mul(&dist_step);
+        mask = less_than_equal_min(dists);
+
+        map_pos += IVec3::new(mask[0] as i32, mask[1] as i32, mask[2] as i32).component_mul(&ray_sign);
+    }
+
+    MarchResult::invalid()
+}
+
+// PlaneMarch in the shader,
+// first loop is in the partition, second loop is in the prefab
+fn plane_march(source: &dyn MarchSource, p0: Vec3, partition_chunk_id: u32, mask: &mut [bool ; 3], dir_context: &DirContext) 
+-> MarchResult {
+    let mut mip_level = MAX_MIP_LEVEL;
+
+    let mut p = p0;
+    let mut is_in_box_one = true;
+    let mut i = 0;
+    while i < 200 && is_in_box_one {
+        let mut voxel_found = source.check_voxel(partition_chunk_id, truncate(p), mip_level as usize);
+
+        if voxel_found && mip_level == 0 {
+            let prefab_chunk_id = source.chunk_index(partition_chunk_id, truncate(p)) as u32;
+            let chunk_pos = p.map(|v| v - v.floor()) * 32.0;
+            let towards_chunk_middle = (Vec3::repeat(16.0) - chunk_pos).normalize();
+            let adjusted_chunk_pos = chunk_pos + towards_chunk_middle * 0.00001;
+
+            mip_level = MAX_MIP_LEVEL;
+            {
+                let mut p_two = adjusted_chunk_pos;
+                let mut is_in_box_two = true;
+                let mut j = 0;
+                while j < 100 && is_in_box_two {
+                    let voxel_found_two = source.check_voxel(prefab_chunk_id, truncate(p_two), mip_level as usize);
+
+                    if voxel_found_two && mip_level == 0 {
+                        let normal = -dir_context.dir.map(glsl_sign)
+                            .component_mul(&Vec3::new(mask[0] as i32 as f32, mask[1] as i32 as f32, mask[2] as i32 as f32));
+                        return MarchResult {
+                            hit: true,
+                            normal,
+                            cube_coords: IVec3::from(truncate(p_two)) + IVec3::from(truncate(p)) * 32,
+                        };
+                    }
+
+                    is_in_box_two = perform_march(voxel_found_two, &mut mip_level, dir_context, &mut p_two, mask);
+                    j += 1;
+                }
+            }
+            mip_level = 0;
+            voxel_found = false;
+        }
+
+        is_in_box_one = perform_march(voxel_found, &mut mip_level, dir_context, &mut p, mask);
+        i += 1;
+    }
+
+    MarchResult::invalid()
+}
+
+// steps to the next cell of the current mip level when nothing was found,
+// returns whether the new position is in the box
+fn perform_march(voxel_found: bool, mip_level: &mut i32, dir_context: &DirContext, pos: &mut Vec3, mask: &mut [bool ; 3]) 
+-> bool {
+    if !voxel_found {
+        let width = 2f32.powi(*mip_level);
+        let deltas = 
+            (dir_context.corner * width - pos.map(|v| glsl_mod(v, width)))
+            .component_mul(&dir_context.inv_dir);
+        *mask = less_than_equal_min(deltas);
+        let min_delta = 
+            (0..3)
+            .map(|axis| (mask[axis] as i32 as f32) * deltas[axis])
+            .sum::<f32>();
+        *pos += (min_delta + 0.001) * dir_context.dir;
+    }
+
+    *mip_level += (!voxel_found) as i32 * 2 - 1;
+    *mip_level = (*mip_level).max(0).min(MAX_MIP_LEVEL);
+
+    inside_box_3d(*pos, 0.0, 32.0)
+}
+
+// getCubePosIntersect, distance along dir to the hit face in cube units
+pub fn cube_pos_intersect(pos_in_partition: Vec3, displacement_coords: IVec3, 
+    dir: Vec3, normal: Vec3, cube_coords: IVec3) 
+-> f32 {
+    let global_pos = pos_in_partition * 32.0 + displacement_coords.cast::<f32>() * 32.0 * 32.0;
+
+    let deltas = (normal.map(|n| glsl_step(0.0, n)) + cube_coords.cast::<f32>()) - global_pos;
+
+    if normal.x != 0.0 {
+        (deltas.x / dir.x).abs()
+    } else if normal.y != 0.0 {
+        (deltas.y / dir.y).abs()
+    } else {
+        (deltas.z / dir.z).abs()
+    }
+}
+
+// lessThanEqual(v.xyz, min(v.yzx, v.zxy))
+fn less_than_equal_min(v: Vec3) 
+-> [bool ; 3] {
+    [
+        v.x <= glsl_min(v.y, v.z),
+        v.y <= glsl_min(v.z, v.x),
+        v.z <= glsl_min(v.x, v.y),
+    ]
+}
+
+fn inside_box_3d(v: Vec3, bottom_left: f32, top_right: f32) 
+-> bool {
+    v.iter().all(|&c| glsl_step(bottom_left, c) - glsl_step(top_right, c) == 1.0)
+}
+
+// ivec3(v) truncates toward zero
+fn truncate(v: Vec3) 
+-> [i32 ; 3] {
+    [v.x as i32, v.y as i32, v.z as i32]
+}
+
+fn glsl_mod(x: f32, y: f32) 
+-> f32 {
+    x - y * (x / y).floor()
+}
+
+fn glsl_sign(x: f32) 
+-> f32 {
+    if x > 0.0 { 1.0 } else if x < 0.0 { -1.0 } else { 0.0 }
+}
+
+fn glsl_step(edge: f32, x: f32) 
+-> f32 {
+    if x < edge { 0.0 } else { 1.0 }
+}
+
+fn glsl_min(a: f32, b: f32) 
+-> f32 {
+    if b < a { b } else { a }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bit_pyramid::BitPyramid;
+    use crate::map_3D::Map3D;
+
+    const PARTITION_ID: u32 = 0;
+    const PREFAB_ID: u16 = 1;
+
+    // the view partition alone, holding one prefab at [5, 3, 7] with one voxel at [10, 20, 4],
+    // the cube [170, 116, 228] of the partition
+    struct OneVoxel {
+        partition_map: Map3D<u16>,
+        partition: BitPyramid,
+        prefab: BitPyramid,
+    }
+
+    impl OneVoxel {
+        fn new() 
+        -> Self {
+            let mut partition_map = Map3D::new_with_default([32 ; 3], u16::MAX);
+            partition_map.set([5, 3, 7], PREFAB_ID).unwrap();
+            let mut prefab_map = Map3D::new_with_default([32 ; 3], u16::MAX);
+            prefab_map.set([10, 20, 4], 0).unwrap();
+            Self {
+                partition: BitPyramid::from_map(&partition_map),
+                partition_map,
+                prefab: BitPyramid::from_map(&prefab_map),
+            }
+        }
+    }
+
+    impl MarchSource for OneVoxel {
+        fn layer_chunk_id(&self, displacement: [i32 ; 3]) 
+        -> u16 {
+            if displacement == [0 ; 3] { PARTITION_ID as u16 } else { u16::MAX }
+        }
+
+        fn check_voxel(&self, chunk_id: u32, coords: [i32 ; 3], lod: usize) 
+        -> bool {
+            match chunk_id {
+                PARTITION_ID => self.partition.check_voxel(coords, lod),
+                id if id == PREFAB_ID as u32 => self.prefab.check_voxel(coords, lod),
+                _ => false,
+            }
+        }
+
+        fn chunk_index(&self, chunk_id: u32, coords: [i32 ; 3]) 
+        -> u16 {
+            assert_eq!(chunk_id, PARTITION_ID);
+            self.partition_map.get([coords[0] as usize, coords[1] as usize, coords[2] as usize]).unwrap()
+        }
+    }
+
+    // origin in cubes, the result and its distance in cubes
+    fn march(origin: [f32 ; 3], dir: [f32 ; 3]) 
+    -> (MarchResult, f32) {
+        let dir = Vec3::from(dir).normalize();
+        let (pos_in_partition, displacement_coords) = split_position(Vec3::from(origin) / 32.0);
+        let result = dda_march(&OneVoxel::new(), dir, pos_in_partition, displacement_coords);
+        let distance = cube_pos_intersect(pos_in_partition, displacement_coords, dir, result.normal, result.cube_coords);
+        (result, distance)
+    }
+
+    // exactly axis aligned rays divide by zero, the renderer never casts those either
+    #[test]
+    fn ray_along_x_hits_the_positive_x_face() {
+        let (result, distance) = march([640.0, 116.5, 228.5], [-1.0, 0.0001, 0.0001]);
+        assert!(result.hit);
+        assert_eq!(result.cube_coords, IVec3::new(170, 116, 228));
+        assert_eq!(result.normal, Vec3::new(1.0, 0.0, 0.0));
+        assert!((distance - 469.0).abs() < 0.1, "{}", distance);
+    }
+
+    #[test]
+    fn ray_from_below_hits_the_negative_y_face() {
+        let (result, distance) = march([170.5, 10.0, 228.5], [0.0001, 1.0, -0.0001]);
+        assert!(result.hit);
+        assert_eq!(result.cube_coords, IVec3::new(170, 116, 228));
+        assert_eq!(result.normal, Vec3::new(0.0, -1.0, 0.0));
+        assert!((distance - 106.0).abs() < 0.1, "{}", distance);
+    }
+
+    #[test]
+    fn ray_through_the_prefab_beside_the_voxel_misses() {
+        let (result, _) = march([640.0, 116.5, 229.5], [-1.0, 0.0001, 0.0001]);
+        assert!(!result.hit);
+        assert_eq!(result, MarchResult::invalid());
+    }
+}
@@ -43,9 +43,13 @@ fn displacement_valid(displacement : na::Vector3<i32>)
 }
 
 use std::collections::HashSet;
+use std::sync::Arc;
 
 use nalgebra as na;
 
+use crate::chunk_queue::ChunkJob;
+use crate::chunk_workers::ChunkWorkers;
+use crate::world_generator::{GenerateContext, WorldGenerator};
 use crate::render::resources::ChunkIDVariant;
 
 type VectorInt = na::Vector3<i32>;
@@ -55,6 +59,8 @@ struct Chunk<T: ChunkData> {
     partition_coords: VectorInt,
     initialized: bool,
     dirty: bool,
+    // bumped with every data the chunk receives, copies made from an older revision are stale
+    revision: u64,
 }
 
 pub struct DisplacedChunks<T : ChunkData>
@@ -66,59 +72,190 @@ pub struct DisplacedChunks<T : ChunkData>
     // The set of all possible partition displacements from view_partition_coords
     // this is constant
     displacement_set : HashSet<VectorInt>,
-    
-    
+    // partition ids by displacement from view_partition_coords, DISPLACEMENT_MAP_DIMS centered on the view.
+    // usize::MAX outside of displacement_set
+    displacement_map : Vec<usize>,
+
+    // generates chunks off the render thread
+    workers : ChunkWorkers<T>,
+    // what the queued jobs were last prioritized with
+    view_priority : ViewPriority,
+}
+
+// Chunks further away fill later, chunks outside the view cone count as up to
+// twice as far and chunks ahead of the camera's movement count as nearer
+#[derive(Clone, Copy)]
+struct ViewPriority
+{
+    view_partition_coords : VectorInt,
+    // unit vectors, heading is zero while the camera is still
+    forward : na::Vector3<f32>,
+    heading : na::Vector3<f32>,
+}
+
+// the view cone is widened by this much past the field of view
+const VIEW_CONE_MARGIN_DEGREES : f32 = 10.;
+// how much nearer a chunk straight ahead of the camera's movement counts as
+const HEADING_WEIGHT : f32 = 0.5;
+// turning further than this since the last reprioritization reorders the queue
+const REPRIORITIZE_DEGREES : f32 = 10.;
+// slower movement than this (cubes per second) doesn't count as heading anywhere
+const MIN_HEADING_SPEED : f32 = 1.;
+// fixed point scale of priorities, which are in partitions
+const PRIORITY_SCALE : f32 = 64.;
+
+impl ViewPriority
+{
+    fn priority(&self, partition_coords : VectorInt)
+        -> i32
+    {
+        let displacement = (partition_coords - self.view_partition_coords).map(|c| c as f32);
+        let distance = displacement.norm();
+        if distance == 0.
+        {
+            return 0;
+        }
+        let direction = displacement / distance;
+
+        // a chunk reaches into the cone once any of it is inside, half its diagonal is ~0.87 partitions
+        let chunk_radius = (0.87 / distance).min(1.).asin();
+        let cone = (crate::FOV * 0.5 + VIEW_CONE_MARGIN_DEGREES).to_radians() + chunk_radius;
+        let cos_angle = direction.dot(&self.forward);
+        let view_factor = if cos_angle >= cone.min(std::f32::consts::PI).cos() { 1. } else { 1.5 - 0.5 * cos_angle };
+
+        let heading_factor = 1. - HEADING_WEIGHT * direction.dot(&self.heading).max(0.);
+
+        (distance * view_factor * heading_factor * PRIORITY_SCALE) as i32
+    }
+
+    // far enough from other that the queue order is stale
+    fn turned_from(&self, other : &ViewPriority)
+        -> bool
+    {
+        let threshold = REPRIORITIZE_DEGREES.to_radians().cos();
+        let turned = |a : &na::Vector3<f32>, b : &na::Vector3<f32>|
+            (a.norm() == 0.) != (b.norm() == 0.) || (a.norm() > 0. && a.dot(b) < threshold);
+        turned(&self.forward, &other.forward) || turned(&self.heading, &other.heading)
+    }
 }
 
-pub trait ChunkData {
-    fn initialize(&mut self, world_chunk_coord: VectorInt);
+// Chunks are generated on worker threads and sent back whole
+pub trait ChunkData: Send + 'static {
+    fn initialize(&mut self, world_chunk_coord: VectorInt, generator: &dyn WorldGenerator, generate_context: &GenerateContext);
     fn allocate() -> Self;
+    // value of the partition index map at coords, u16::MAX is empty
+    fn voxel_index(&self, coords: [usize ; 3]) -> u16;
 }
 
-const DISPLACEMENT_MAP_DIMS: [usize ; 3] = [45, 15, 45];
+pub const DISPLACEMENT_MAP_DIMS: [usize ; 3] = [45, 15, 45];
+
+// index of a displacement from the view in maps of DISPLACEMENT_MAP_DIMS
+fn displacement_map_index(displacement: VectorInt)
+    -> Option<usize>
+{
+    let dims = DISPLACEMENT_MAP_DIMS;
+    let map_coords = [
+        displacement.x + (dims[0] / 2) as i32,
+        displacement.y + (dims[1] / 2) as i32,
+        displacement.z + (dims[2] / 2) as i32,
+    ];
+    if (0..3).any(|axis| map_coords[axis] < 0 || map_coords[axis] >= dims[axis] as i32) {
+        return None;
+    }
+    Some(map_coords[0] as usize + map_coords[1] as usize * dims[0] + map_coords[2] as usize * dims[0] * dims[1])
+}
 
 impl<T: ChunkData>  DisplacedChunks<T> {
-    pub fn new(view_partition_coords: VectorInt)
+    pub fn new(view_partition_coords: VectorInt, generator: Box<dyn WorldGenerator>, generate_context: GenerateContext, worker_count: usize)
         -> DisplacedChunks<T>
     {
         let displacement_set = radius_displacement_set();
 
 
-        let chunks = 
+        let chunks: Vec<Chunk<T>> = 
             displacement_set
             .iter()
-            .map(|&displacement| Chunk { data: T::allocate(), partition_coords: displacement + view_partition_coords, initialized: false, dirty: false})
+            .map(|&displacement| Chunk { data: T::allocate(), partition_coords: displacement + view_partition_coords, initialized: false, dirty: false, revision: 0 })
             .collect();
 
-        DisplacedChunks 
+        let workers = ChunkWorkers::new(chunks.len(), worker_count, Arc::from(generator), Arc::new(generate_context));
+
+        let view_priority = ViewPriority 
+        {
+            view_partition_coords,
+            forward : na::Vector3::z(),
+            heading : na::Vector3::zeros(),
+        };
+
+        let mut displaced_chunks = DisplacedChunks 
         {
             chunks,
             view_partition_coords,
             displacement_set,
+            displacement_map: Vec::new(),
+            workers,
+            view_priority,
+        };
+        displaced_chunks.update_displacement_map();
+        for partition_id in 0..displaced_chunks.len() {
+            displaced_chunks.queue(partition_id);
         }
+        displaced_chunks
     }
-    fn closest_uninitialized_chunk_index(&self)
-        -> Option<usize>
-    {
-        (0..self.len())
-        .filter(|&index| 
-            !self.chunks[index].initialized)
-        .min_by_key(|&index| 
-            Self::mag_squared(self.chunks[index].partition_coords - self.view_partition_coords))
+
+    // queues a chunk for generation with its current epoch, O(log n)
+    fn queue(&self, partition_id: usize) {
+        let partition_coords = self.chunks[partition_id].partition_coords;
+        self.workers.push(ChunkJob {
+            priority: self.view_priority.priority(partition_coords),
+            partition_id,
+            partition_coords,
+            epoch: self.workers.epoch(partition_id),
+        });
     }
-    fn mag_squared(disp: VectorInt)
-        -> i32
-    {
-        disp.iter().fold(0, |acc, i| acc + i * i)
+
+    // Points the camera, velocity in cubes per second.
+    // Queued chunks are only rescored once the view or heading has turned enough,
+    // and then as they come up for generation rather than all at once
+    pub fn set_view_direction(&mut self, forward: na::Vector3<f32>, velocity: na::Vector3<f32>) {
+        let view_priority = ViewPriority 
+        {
+            view_partition_coords: self.view_partition_coords,
+            forward: forward.try_normalize(0.).unwrap_or(self.view_priority.forward),
+            heading: 
+                if velocity.norm() >= MIN_HEADING_SPEED { velocity.normalize() }
+                else { na::Vector3::zeros() },
+        };
+
+        if view_priority.turned_from(&self.view_priority) {
+            self.view_priority = view_priority;
+            self.workers.reprioritize(move |job| view_priority.priority(job.partition_coords));
+        }
     }
     
-    pub fn try_initialize(&mut self) {
-        if let Some(index) = self.closest_uninitialized_chunk_index() {
-            let chunk = &mut self.chunks[index];
-            chunk.data.initialize(chunk.partition_coords);
+    // Takes up to limit chunks the workers finished, marking them dirty for upload.
+    // Returns how many were taken, the rest wait for the next call
+    pub fn receive_generated(&mut self, limit: usize) 
+        -> usize
+    {
+        let mut received = 0;
+        while received < limit {
+            let generated = match self.workers.try_recv() {
+                Some(generated) => generated,
+                None => break,
+            };
+            let chunk = &mut self.chunks[generated.partition_id];
+            // each epoch is only queued once, this is a guard rather than something expected
+            if chunk.initialized {
+                continue;
+            }
+            chunk.data = generated.data;
             chunk.initialized = true;
             chunk.dirty = true;
+            chunk.revision += 1;
+            received += 1;
         }
+        received
     }
 
     // obtains mutable reference to dirty chunks
@@ -155,6 +292,55 @@ impl<T: ChunkData>  DisplacedChunks<T> {
         self.chunks.len()
     }
 
+    pub fn view_partition_coords(&self)
+        -> VectorInt
+    {
+        self.view_partition_coords
+    }
+
+    // data of a partition id, None until the chunk is initialized
+    pub fn chunk_data(&self, partition_id: usize)
+        -> Option<&T>
+    {
+        self.chunks
+        .get(partition_id)
+        .filter(|c| c.initialized)
+        .map(|c| &c.data)
+    }
+
+    pub fn partition_coords(&self, partition_id: usize)
+        -> Option<VectorInt>
+    {
+        self.chunks.get(partition_id).map(|c| c.partition_coords)
+    }
+
+    // partition id holding world partition coords, None when they are out of view
+    pub fn partition_id(&self, partition_coords: VectorInt)
+        -> Option<usize>
+    {
+        displacement_map_index(partition_coords - self.view_partition_coords)
+        .map(|i| self.displacement_map[i])
+        .filter(|&partition_id| partition_id != usize::MAX)
+    }
+
+    // changes whenever the partition id receives new data
+    pub fn chunk_revision(&self, partition_id: usize)
+        -> u64
+    {
+        self.chunks[partition_id].revision
+    }
+
+    fn update_displacement_map(&mut self) {
+        let dims = DISPLACEMENT_MAP_DIMS;
+        let mut displacement_map = vec![usize::MAX ; dims[0] * dims[1] * dims[2]];
+        for (partition_id, chunk) in self.chunks.iter().enumerate() {
+            let i = displacement_map_index(chunk.partition_coords - self.view_partition_coords)
+                .expect("partition is outside the displacement map");
+            displacement_map[i] = partition_id;
+        }
+        self.displacement_map = displacement_map;
+    }
+
     // set field and update chunk partition coords
     pub fn set_view_partition_coords(&mut self, coords: VectorInt) {
         self.view_partition_coords = coords;
@@ -167,19 +353,27 @@ impl<T: ChunkData>  DisplacedChunks<T> {
             let partition_displacement = chunk.partition_coords - self.view_partition_coords;
             if !displacement_valid(partition_displacement) {
                 chunk.initialized = false;
+                self.workers.cancel(partition_id);
                 invalid_partition_ids.push(partition_id);
             } else {
                 filled_displacements.insert(partition_displacement);
             }
         }
 
+        // priorities are measured from the view, which just moved
+        self.view_priority.view_partition_coords = coords;
+        let view_priority = self.view_priority;
+        self.workers.reprioritize(move |job| view_priority.priority(job.partition_coords));
+
         // assign new partition coordinate to every invalidated partition
         for unfilled_displacement in self.displacement_set.difference(&filled_displacements) {
             let invalid_partition_id = invalid_partition_ids.pop().expect("Not enough invalid partition ids!");
             self.chunks[invalid_partition_id].partition_coords = unfilled_displacement + self.view_partition_coords;
+            self.queue(invalid_partition_id);
         }
 
         assert!(invalid_partition_ids.len() == 0);
+        self.update_displacement_map();
     }
 
     pub fn get_index_map(&self) -> Vec<u16> {
@@ -1,18 +1,38 @@
 use dot_vox as dv;
 use dv::DotVoxData;
 use std::mem;
+
+use super::prefab_load_error::PrefabLoadError;
+use super::vox_scene::{self, ModelInstance};
+use super::voxel_material::VoxelMaterial;
+
 pub struct DotVoxWrapper
 {
-    vox_data : DotVoxData
+    path : String,
+    vox_data : DotVoxData,
+    // every shape of the scene graph, flattened
+    instances : Vec<ModelInstance>
 }
 
 impl DotVoxWrapper
 {
 
     pub fn new(file : &str)
-        -> DotVoxWrapper
+        -> Result<DotVoxWrapper, PrefabLoadError>
     {
-        let mut vox_data = dv::load(file).unwrap();
+        let path = file.to_string();
+
+        let bytes = std::fs::read(file)
+            .map_err(|source| PrefabLoadError::MissingFile { path : path.clone(), source })?;
+        let mut vox_data = dv::load_bytes(&bytes)
+            .map_err(|message| PrefabLoadError::Parse { path : path.clone(), message : message.to_string() })?;
+        let instances = vox_scene::parse_instances(&bytes, vox_data.models.len())
+            .map_err(|message| PrefabLoadError::Parse { path : path.clone(), message : message.to_string() })?;
+
+        if vox_data.palette.len() != 256
+        {
+            return Err(PrefabLoadError::PaletteSizeMismatch { path, len : vox_data.palette.len() });
+        }
 
         // change orientation (switches y and z)
         for model in &mut vox_data.models
@@ -26,7 +46,44 @@ impl DotVoxWrapper
         }
         
 
-        DotVoxWrapper {vox_data}
+        Ok(DotVoxWrapper {path, vox_data, instances})
+    }
+
+    pub fn path(&self)
+        -> &str
+    {
+        &self.path
+    }
+
+    pub fn check_model_index(&self, model_index : usize)
+        -> Result<(), PrefabLoadError>
+    {
+        if model_index < self.model_count()
+        {
+            Ok(())
+        }
+        else
+        {
+            Err(PrefabLoadError::ModelIndexOutOfRange 
+            {
+                path : self.path.clone(), 
+                model_index, 
+                model_count : self.model_count()
+            })
+        }
+    }
+
+    pub fn model_count(&self)
+        -> usize
+    {
+        self.vox_data.models.len()
+    }
+
+    // placements of the models with their scene graph transforms resolved
+    pub fn instances(&self)
+        -> &[ModelInstance]
+    {
+        &self.instances
     }
     pub fn get_voxel(&self, coords : [usize ; 3], model_index : usize)
         -> Option<&dv::Voxel>
@@ -57,7 +114,25 @@ impl DotVoxWrapper
         return array;
     }
 
-    
+    // materials by palette index, entries without a MATL chunk are plain diffuse
+    pub fn materials(&self)
+        -> [VoxelMaterial ; 256]
+    {
+        let mut materials = [VoxelMaterial::default() ; 256];
+
+        // MATL ids count from 1 like the file's color indices, voxel indices are shifted down by one
+        for material in &self.vox_data.materials
+        {
+            let id = material.id as usize;
+            if id >= 1 && id <= 256
+            {
+                materials[id - 1] = VoxelMaterial::from_properties(&material.properties);
+            }
+        }
+
+        materials
+    }
+
     pub fn voxel_slice(&self, model_index : usize)
         -> &[dv::Voxel]
     {
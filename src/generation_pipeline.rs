@@ -0,0 +1,566 @@
+use std::fmt;
+use std::sync::Arc;
+
+use nalgebra as na;
+use serde::Deserialize;
+
+use crate::map_3D::Map3D;
+use crate::structure_placement::{StructurePlacementStage, StructureStageConfig};
+use crate::terrain_generator::{HeightmapTerrainGenerator, TerrainConfig};
+use crate::world_generator::{cell_hash, Fbm, GenerateContext, WorldGenerator, PARTITION_LENGTH};
+
+pub const GENERATION_CONFIG_PATH: &str = "resources/generation.toml";
+
+// One step of generation, reading and writing the cells the earlier stages left.
+// origin is the world cell of cells' [0, 0, 0], cells may be any size
+pub trait GenerationStage: Send + Sync {
+    fn apply(&self, origin: na::Vector3<i64>, context: &GenerateContext, cells: &mut Map3D<u16>);
+
+    // prefabs the stage refers to, so names missing from the manifest can be reported
+    fn prefab_names(&self) -> Vec<&str>;
+}
+
+// Stages run in order over a chunk that starts out empty.
+// The top layer of the partition below is generated along with it,
+// so stages looking at the cell under one see what is there
+pub struct GenerationPipeline {
+    stages: Vec<Box<dyn GenerationStage>>,
+}
+
+#[derive(Debug)]
+pub enum GenerationConfigError {
+    MissingFile { path: String, source: std::io::Error },
+    Parse { path: String, message: String },
+}
+
+impl fmt::Display for GenerationConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)
+    -> fmt::Result {
+        match self {
+            GenerationConfigError::MissingFile { path, source } =>
+                write!(f, "{}: could not read file ({})", path, source),
+            GenerationConfigError::Parse { path, message } =>
+                write!(f, "{}: could not parse generation config ({})", path, message),
+        }
+    }
+}
+
+impl std::error::Error for GenerationConfigError {}
+
+#[derive(Deserialize)]
+struct GenerationConfig {
+    #[serde(default)]
+    stage: Vec<StageConfig>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum StageConfig {
+    Terrain(TerrainConfig),
+    Caves(CaveConfig),
+    Ores(OreConfig),
+    Decoration(DecorationConfig),
+    Structures(StructureStageConfig),
+}
+
+impl GenerationPipeline {
+    pub fn new(stages: Vec<Box<dyn GenerationStage>>)
+    -> GenerationPipeline {
+        GenerationPipeline { stages }
+    }
+
+    // stages resolve their noise and prefabs from the context as they're built,
+    // the pipeline generates for that context alone
+    pub fn load(path: &str, context: &GenerateContext)
+    -> Result<GenerationPipeline, GenerationConfigError> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|source| GenerationConfigError::MissingFile { path: path.to_string(), source })?;
+        let config: GenerationConfig = toml::from_str(&text)
+            .map_err(|e| GenerationConfigError::Parse { path: path.to_string(), message: e.to_string() })?;
+
+        // structures stand on the surface of the last terrain stage before them
+        let mut terrain: Option<Arc<HeightmapTerrainGenerator>> = None;
+        let mut stages: Vec<Box<dyn GenerationStage>> = Vec::new();
+        for stage in config.stage {
+            match stage {
+                StageConfig::Terrain(config) => {
+                    let stage = Arc::new(HeightmapTerrainGenerator::new(config, context));
+                    terrain = Some(stage.clone());
+                    stages.push(Box::new(stage));
+                },
+                StageConfig::Caves(config) => stages.push(Box::new(CaveStage::new(config, context))),
+                StageConfig::Ores(config) => stages.push(Box::new(OreStage::new(config, context))),
+                StageConfig::Decoration(config) => stages.push(Box::new(DecorationStage::new(config, context))),
+                StageConfig::Structures(config) => {
+                    let stage = StructurePlacementStage::new(config, terrain.clone(), context)
+                        .map_err(|message| GenerationConfigError::Parse { path: path.to_string(), message })?;
+                    stages.push(Box::new(stage));
+                },
+            }
+        }
+
+        Ok(GenerationPipeline::new(stages))
+    }
+
+    // names used by a stage that the context can't resolve, each once
+    pub fn unknown_prefabs(&self, context: &GenerateContext)
+    -> Vec<String> {
+        let mut unknown: Vec<String> = Vec::new();
+        for name in self.stages.iter().flat_map(|stage| stage.prefab_names()) {
+            if context.prefab_id(name) == u16::MAX && !unknown.iter().any(|n| n == name) {
+                unknown.push(name.to_string());
+            }
+        }
+        unknown
+    }
+
+    // terrain alone, when there is no usable config
+    pub fn terrain_only(context: &GenerateContext)
+    -> GenerationPipeline {
+        GenerationPipeline::new(vec![Box::new(HeightmapTerrainGenerator::new(TerrainConfig::default(), context))])
+    }
+}
+
+impl WorldGenerator for GenerationPipeline {
+    fn generate(&self, partition_coords: na::Vector3<i32>, context: &GenerateContext, partition: &mut Map3D<u16>) {
+        let origin = partition_coords.map(|c| c as i64 * PARTITION_LENGTH as i64) - na::Vector3::y();
+        let mut cells = Map3D::new_with_default([PARTITION_LENGTH, PARTITION_LENGTH + 1, PARTITION_LENGTH], u16::MAX);
+        for stage in &self.stages {
+            stage.apply(origin, context, &mut cells);
+        }
+        partition.copy_region(&cells, [0, 1, 0], [PARTITION_LENGTH ; 3], [0 ; 3]);
+    }
+}
+
+impl GenerationStage for HeightmapTerrainGenerator {
+    fn apply(&self, origin: na::Vector3<i64>, _context: &GenerateContext, cells: &mut Map3D<u16>) {
+        self.fill(origin, cells);
+    }
+
+    fn prefab_names(&self)
+    -> Vec<&str> {
+        self.config.biomes.iter().flat_map(|b| vec![b.surface.as_str(), b.sub_surface.as_str(), b.deep.as_str()]).collect()
+    }
+}
+
+// a stage shared with stages that read from it
+impl<T: GenerationStage + ?Sized> GenerationStage for Arc<T> {
+    fn apply(&self, origin: na::Vector3<i64>, context: &GenerateContext, cells: &mut Map3D<u16>) {
+        (**self).apply(origin, context, cells);
+    }
+
+    fn prefab_names(&self)
+    -> Vec<&str> {
+        (**self).prefab_names()
+    }
+}
+
+// Cells of a stage's height band, min and max inclusive, unbounded when left out
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(default)]
+pub struct HeightRange {
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+}
+
+impl HeightRange {
+    pub fn contains(&self, y: i64)
+    -> bool {
+        !matches!(self.min, Some(min) if y < min) && !matches!(self.max, Some(max) if y > max)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CaveStyle {
+    // open caverns where one ridged noise crosses zero
+    Ridged,
+    // narrow tunnels where two ridged noises cross zero together
+    Worm,
+}
+
+// What a generation config gives a cave stage
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct CaveConfig {
+    // noise layer name, stages sharing a layer carve the same caves
+    pub layer: String,
+    pub style: CaveStyle,
+    pub noise: Fbm,
+    // how far from zero the noise may be and still carve, wider caves as it grows
+    pub threshold: f64,
+    pub heights: HeightRange,
+}
+
+impl Default for CaveConfig {
+    fn default()
+    -> CaveConfig {
+        CaveConfig {
+            layer: "caves".to_string(),
+            style: CaveStyle::Worm,
+            noise: Fbm { octaves: 2, frequency: 0.05, ..Fbm::default() },
+            threshold: 0.04,
+            heights: HeightRange::default(),
+        }
+    }
+}
+
+// Empties solid cells along the zero crossings of ridged noise
+pub struct CaveStage {
+    config: CaveConfig,
+    first: noise::OpenSimplex,
+    second: noise::OpenSimplex,
+}
+
+impl CaveStage {
+    pub fn new(config: CaveConfig, context: &GenerateContext)
+    -> CaveStage {
+        let first = context.noise(&config.layer);
+        let second = context.noise(&format!("{} second", config.layer));
+        CaveStage { config, first, second }
+    }
+}
+
+impl GenerationStage for CaveStage {
+    fn apply(&self, origin: na::Vector3<i64>, _context: &GenerateContext, cells: &mut Map3D<u16>) {
+        let config = &self.config;
+        for_each_cell(origin, cells, |world_coords, value| {
+            if *value == u16::MAX || !config.heights.contains(world_coords.y) {
+                return;
+            }
+            let coords_f64 = [world_coords.x as f64, world_coords.y as f64, world_coords.z as f64];
+            let carved = config.noise.sample_3d(&self.first, coords_f64).abs() < config.threshold
+                && (config.style == CaveStyle::Ridged || config.noise.sample_3d(&self.second, coords_f64).abs() < config.threshold);
+            if carved {
+                *value = u16::MAX;
+            }
+        });
+    }
+
+    fn prefab_names(&self)
+    -> Vec<&str> {
+        Vec::new()
+    }
+}
+
+// What a generation config gives an ore stage
+#[derive(Deserialize)]
+pub struct OreConfig {
+    #[serde(default = "default_ore_layer")]
+    pub layer: String,
+    pub prefab: String,
+    // the only prefabs the ore replaces
+    pub replaces: Vec<String>,
+    #[serde(default)]
+    pub noise: Fbm,
+    // noise above this becomes ore, rarer ore as it rises
+    pub threshold: f64,
+    #[serde(default)]
+    pub heights: HeightRange,
+}
+
+fn default_ore_layer()
+-> String {
+    "ores".to_string()
+}
+
+// Swaps host prefabs for an ore prefab where the layer's noise is high,
+// giving pockets and seams of it through the host rock
+pub struct OreStage {
+    config: OreConfig,
+    ore: u16,
+    hosts: Vec<u16>,
+    noise: noise::OpenSimplex,
+}
+
+impl OreStage {
+    pub fn new(config: OreConfig, context: &GenerateContext)
+    -> OreStage {
+        let ore = context.prefab_id(&config.prefab);
+        let hosts = config.replaces.iter().map(|name| context.prefab_id(name)).filter(|&id| id != u16::MAX).collect();
+        let noise = context.noise(&config.layer);
+        OreStage { config, ore, hosts, noise }
+    }
+}
+
+impl GenerationStage for OreStage {
+    fn apply(&self, origin: na::Vector3<i64>, _context: &GenerateContext, cells: &mut Map3D<u16>) {
+        // an ore missing from the manifest would carve holes in its hosts
+        if self.ore == u16::MAX {
+            return;
+        }
+        let config = &self.config;
+        for_each_cell(origin, cells, |world_coords, value| {
+            if !self.hosts.contains(value) || !config.heights.contains(world_coords.y) {
+                return;
+            }
+            let coords_f64 = [world_coords.x as f64, world_coords.y as f64, world_coords.z as f64];
+            if config.noise.sample_3d(&self.noise, coords_f64) > config.threshold {
+                *value = self.ore;
+            }
+        });
+    }
+
+    fn prefab_names(&self)
+    -> Vec<&str> {
+        std::iter::once(self.config.prefab.as_str()).chain(self.config.replaces.iter().map(|name| name.as_str())).collect()
+    }
+}
+
+// What a generation config gives a decoration stage
+#[derive(Deserialize)]
+pub struct DecorationConfig {
+    #[serde(default = "default_decoration_layer")]
+    pub layer: String,
+    pub prefab: String,
+    // prefabs it may rest on
+    pub on: Vec<String>,
+    // chance of each candidate cell being decorated, in [0, 1]
+    pub chance: f64,
+    #[serde(default)]
+    pub heights: HeightRange,
+}
+
+fn default_decoration_layer()
+-> String {
+    "decoration".to_string()
+}
+
+// Scatters a prefab on the empty cells resting on top of chosen prefabs.
+// The bottom layer of cells has nothing under it to look at, so is never decorated,
+// in a pipeline that layer belongs to the partition below
+pub struct DecorationStage {
+    config: DecorationConfig,
+    decoration: u16,
+    supports: Vec<u16>,
+    seed: u64,
+}
+
+impl DecorationStage {
+    pub fn new(config: DecorationConfig, context: &GenerateContext)
+    -> DecorationStage {
+        let decoration = context.prefab_id(&config.prefab);
+        let supports = config.on.iter().map(|name| context.prefab_id(name)).filter(|&id| id != u16::MAX).collect();
+        let seed = context.sub_seed(&config.layer);
+        DecorationStage { config, decoration, supports, seed }
+    }
+}
+
+impl GenerationStage for DecorationStage {
+    fn apply(&self, origin: na::Vector3<i64>, _context: &GenerateContext, cells: &mut Map3D<u16>) {
+        let dims = cells.dims();
+        for z in 0..dims[2] {
+        for y in 0..dims[1].saturating_sub(1) {
+        for x in 0..dims[0] {
+            let (below, cell) = ([x, y, z], [x, y + 1, z]);
+            if cells.get(cell) != Some(u16::MAX) || !self.supports.contains(&cells.get(below).unwrap()) {
+                continue;
+            }
+            let world_coords = origin + na::Vector3::new(x as i64, y as i64 + 1, z as i64);
+            if !self.config.heights.contains(world_coords.y) {
+                continue;
+            }
+            // top 53 bits as a fraction
+            let roll = (cell_hash(self.seed, world_coords) >> 11) as f64 / (1u64 << 53) as f64;
+            if roll < self.config.chance {
+                cells.set(cell, self.decoration).unwrap();
+            }
+        }}}
+    }
+
+    fn prefab_names(&self)
+    -> Vec<&str> {
+        std::iter::once(self.config.prefab.as_str()).chain(self.config.on.iter().map(|name| name.as_str())).collect()
+    }
+}
+
+// visits every cell with its world coordinates
+fn for_each_cell(origin: na::Vector3<i64>, cells: &mut Map3D<u16>, mut visit: impl FnMut(na::Vector3<i64>, &mut u16)) {
+    let dims = cells.dims();
+    for z in 0..dims[2] {
+    for y in 0..dims[1] {
+    for x in 0..dims[0] {
+        let world_coords = origin + na::Vector3::new(x as i64, y as i64, z as i64);
+        let mut value = cells.get([x, y, z]).unwrap();
+        visit(world_coords, &mut value);
+        cells.set([x, y, z], value).unwrap();
+    }}}
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra as na;
+
+    use super::{CaveConfig, CaveStage, CaveStyle, DecorationConfig, DecorationStage, GenerationPipeline, GenerationStage, HeightRange, OreConfig, OreStage, GENERATION_CONFIG_PATH};
+    use crate::world_generator::{chunk_hash, Fbm};
+    use crate::map_3D::Map3D;
+    use crate::prefab_registry::{PrefabRegistry, PREFAB_MANIFEST_PATH};
+    use crate::world_generator::{GenerateContext, WorldGenerator, PARTITION_LENGTH};
+
+    // solid cells at and below a world height
+    struct Floor {
+        top: i64,
+        id: u16,
+    }
+
+    impl GenerationStage for Floor {
+        fn apply(&self, origin: na::Vector3<i64>, _context: &GenerateContext, cells: &mut Map3D<u16>) {
+            let (top, id) = (self.top, self.id);
+            cells.set_all(&|coords| if origin.y + (coords[1] as i64) <= top { id } else { u16::MAX });
+        }
+
+        fn prefab_names(&self)
+        -> Vec<&str> {
+            Vec::new()
+        }
+    }
+
+    fn decoration(context: &GenerateContext)
+    -> DecorationStage {
+        DecorationStage::new(DecorationConfig {
+            layer: "decoration".to_string(),
+            prefab: "stool".to_string(),
+            on: vec!["bricks".to_string()],
+            chance: 1.,
+            heights: HeightRange::default(),
+        }, context)
+    }
+
+    #[test]
+    fn decorates_the_bottom_layer_on_the_partition_below() {
+        let registry = PrefabRegistry::load(PREFAB_MANIFEST_PATH).unwrap().0;
+        let context = GenerateContext::new(&registry, 3);
+        let (bricks, stool) = (context.prefab_id("bricks"), context.prefab_id("stool"));
+
+        // the floor's top is the top layer of partition y -1
+        let pipeline = GenerationPipeline::new(vec![Box::new(Floor { top: -1, id: bricks }), Box::new(decoration(&context))]);
+        let mut partition = Map3D::new([PARTITION_LENGTH ; 3]);
+        pipeline.generate(na::Vector3::new(2, 0, -1), &context, &mut partition);
+
+        for (coords, value) in partition.iter() {
+            let expected = if coords[1] == 0 { stool } else { u16::MAX };
+            assert_eq!(value, expected, "{:?}", coords);
+        }
+    }
+
+    #[test]
+    fn decoration_leaves_the_bottom_layer_of_its_cells() {
+        let registry = PrefabRegistry::load(PREFAB_MANIFEST_PATH).unwrap().0;
+        let context = GenerateContext::new(&registry, 3);
+        let (bricks, stool) = (context.prefab_id("bricks"), context.prefab_id("stool"));
+
+        // nothing is known under the bottom layer, so it stays empty over a floor below the cells
+        let mut cells = Map3D::new_with_default([4, 3, 4], u16::MAX);
+        decoration(&context).apply(na::Vector3::zeros(), &context, &mut cells);
+        assert!(cells.full_slice().iter().all(|&value| value == u16::MAX));
+
+        cells.fill_box([0 ; 3], [4, 1, 4], bricks);
+        decoration(&context).apply(na::Vector3::zeros(), &context, &mut cells);
+        for (coords, value) in cells.iter() {
+            let expected = [bricks, stool, u16::MAX][coords[1]];
+            assert_eq!(value, expected, "{:?}", coords);
+        }
+    }
+
+    #[test]
+    fn unknown_ore_leaves_its_hosts() {
+        let registry = PrefabRegistry::load(PREFAB_MANIFEST_PATH).unwrap().0;
+        let context = GenerateContext::new(&registry, 3);
+        let bricks = context.prefab_id("bricks");
+
+        let ores = OreStage::new(OreConfig {
+            layer: "ores".to_string(),
+            prefab: "missing".to_string(),
+            replaces: vec!["bricks".to_string()],
+            noise: Fbm::default(),
+            // every cell would be ore
+            threshold: -2.,
+            heights: HeightRange::default(),
+        }, &context);
+        let mut cells = Map3D::new_with_default([8 ; 3], bricks);
+        ores.apply(na::Vector3::zeros(), &context, &mut cells);
+        assert!(cells.full_slice().iter().all(|&value| value == bricks));
+    }
+
+    #[test]
+    fn generation_config_runs() {
+        let registry = PrefabRegistry::load(PREFAB_MANIFEST_PATH).unwrap().0;
+        let context = GenerateContext::new(&registry, 7);
+        let pipeline = GenerationPipeline::load(GENERATION_CONFIG_PATH, &context).unwrap();
+        assert!(pipeline.unknown_prefabs(&context).is_empty());
+
+        // the chunk the default terrain's surface runs through
+        let coords = na::Vector3::new(0, -1, 0);
+        let mut partition = Map3D::new([PARTITION_LENGTH ; 3]);
+        pipeline.generate(coords, &context, &mut partition);
+
+        let empty = partition.full_slice().iter().filter(|&&value| value == u16::MAX).count();
+        assert!(empty > 0 && empty < partition.volume(), "{} empty cells", empty);
+        let known = registry.ids_by_name().values().copied().collect::<Vec<_>>();
+        assert!(partition.full_slice().iter().all(|value| *value == u16::MAX || known.contains(value)));
+
+        let mut again = Map3D::new([PARTITION_LENGTH ; 3]);
+        pipeline.generate(coords, &context, &mut again);
+        assert_eq!(chunk_hash(&partition), chunk_hash(&again));
+    }
+
+    #[test]
+    fn height_ranges_are_inclusive() {
+        let range = |min, max| HeightRange { min, max };
+        let cases = [
+            (range(None, None), [true, true, true, true, true]),
+            (range(Some(0), None), [false, true, true, true, true]),
+            (range(None, Some(0)), [true, true, false, false, false]),
+            (range(Some(0), Some(1)), [false, true, true, false, false]),
+            (range(Some(1), Some(1)), [false, false, true, false, false]),
+            (range(Some(2), Some(0)), [false, false, false, false, false]),
+        ];
+        for (range, expected) in cases.iter() {
+            let contained: Vec<bool> = (-1..4).map(|y| range.contains(y)).collect();
+            assert_eq!(contained, expected.to_vec(), "{:?} {:?}", range.min, range.max);
+        }
+    }
+
+    fn caves(style: CaveStyle, threshold: f64, heights: HeightRange, context: &GenerateContext)
+    -> CaveStage {
+        CaveStage::new(CaveConfig { style, threshold, heights, ..CaveConfig::default() }, context)
+    }
+
+    #[test]
+    fn caves_carve_inside_their_heights_only() {
+        let registry = PrefabRegistry::placeholder_only();
+        let context = GenerateContext::new(&registry, 5);
+
+        // the noise is always within 10 of zero, so every cell in the band is carved
+        let stage = caves(CaveStyle::Ridged, 10., HeightRange { min: Some(2), max: Some(4) }, &context);
+        let origin = na::Vector3::new(-3, -1, 7);
+        let mut cells = Map3D::new_with_default([6, 8, 6], 0);
+        stage.apply(origin, &context, &mut cells);
+
+        for (coords, value) in cells.iter() {
+            let y = origin.y + coords[1] as i64;
+            let expected = if (2..=4).contains(&y) { u16::MAX } else { 0 };
+            assert_eq!(value, expected, "{:?}", coords);
+        }
+    }
+
+    #[test]
+    fn worms_carve_part_of_the_ridged_caves_of_their_layer() {
+        let registry = PrefabRegistry::placeholder_only();
+        let context = GenerateContext::new(&registry, 5);
+
+        let carve = |style| {
+            let mut cells = Map3D::new_with_default([24 ; 3], 0);
+            caves(style, 0.1, HeightRange::default(), &context).apply(na::Vector3::new(0, -12, 0), &context, &mut cells);
+            cells
+        };
+        let ridged = carve(CaveStyle::Ridged);
+        let worm = carve(CaveStyle::Worm);
+
+        let carved = |cells: &Map3D<u16>| cells.iter().filter(|&(_, value)| value == u16::MAX).map(|(coords, _)| coords).collect::<Vec<_>>();
+        let (ridged_carved, worm_carved) = (carved(&ridged), carved(&worm));
+        assert!(!worm_carved.is_empty());
+        assert!(worm_carved.len() < ridged_carved.len());
+        assert!(worm_carved.iter().all(|coords| ridged_carved.contains(coords)));
+    }
+}
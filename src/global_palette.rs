@@ -0,0 +1,432 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use crate::map_3D::Map3D;
+
+use super::standard_voxel_prefab::StandardVoxelPrefab;
+use super::voxel_material::VoxelMaterial;
+
+pub const PALETTE_ROW_LENGTH : usize = 256;
+
+// How prefab palettes are laid out in the palette array
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PaletteLayout
+{
+    // one deduplicated palette shared by every prefab,
+    // prefab volumes are remapped to global indices before upload
+    Global,
+    // one 256 entry row per prefab, volumes are uploaded as they are
+    PerPrefab,
+}
+
+impl PaletteLayout
+{
+    // value the shaders branch on
+    pub fn shader_value(&self)
+        -> i32
+    {
+        match self
+        {
+            PaletteLayout::Global => 0,
+            PaletteLayout::PerPrefab => 1,
+        }
+    }
+}
+
+// The colors and materials of every prefab merged into one palette.
+// Entries are the same when both color and material are.
+// Only palette entries a prefab actually uses are merged, the rest remap to u16::MAX.
+// Entries no prefab uses anymore are freed and handed out again to later entries
+pub struct GlobalPalette
+{
+    layout : PaletteLayout,
+    colors : Vec<u32>,
+    materials : Vec<VoxelMaterial>,
+    // per global entry, how many prefab palette indices remap to it
+    references : Vec<u32>,
+    // global entries without references, reused before the palette grows
+    free_entries : Vec<u16>,
+    // per prefab, local palette index to global palette index
+    remaps : Vec<[u16 ; PALETTE_ROW_LENGTH]>,
+    index_of_entry : HashMap<(u32, [u32 ; 7]), u16>
+}
+
+impl GlobalPalette
+{
+    pub fn build(prefabs : &[StandardVoxelPrefab], layout : PaletteLayout)
+        -> GlobalPalette
+    {
+        let mut palette = GlobalPalette 
+        {
+            layout,
+            colors : Vec::new(),
+            materials : Vec::new(),
+            references : Vec::new(),
+            free_entries : Vec::new(),
+            remaps : vec![[u16::MAX ; PALETTE_ROW_LENGTH] ; prefabs.len()],
+            index_of_entry : HashMap::new(),
+        };
+
+        for (prefab_id, prefab) in prefabs.iter().enumerate()
+        {
+            palette.update_prefab(prefab_id as u16, prefab);
+        }
+
+        palette
+    }
+
+    // Remaps a prefab after its content changed.
+    // Entries only the old content used are freed for later entries.
+    // Returns the rows of the palette array that need uploading
+    pub fn update_prefab(&mut self, prefab_id : u16, prefab : &StandardVoxelPrefab)
+        -> Range<usize>
+    {
+        // rows of the palette array entries were added to
+        let mut new_rows : Option<Range<usize>> = None;
+
+        let mut used = [false ; PALETTE_ROW_LENGTH];
+        for &local in prefab.palette_volume.full_slice()
+        {
+            if (local as usize) < PALETTE_ROW_LENGTH
+            {
+                used[local as usize] = true;
+            }
+        }
+
+        // the new remap takes its references before the old one lets go,
+        // so entries both use are never freed in between
+        let mut remap = [u16::MAX ; PALETTE_ROW_LENGTH];
+        for local in (0..PALETTE_ROW_LENGTH).filter(|&i| used[i])
+        {
+            let color = prefab.palette[local];
+            let material = prefab.materials[local];
+            let global = match self.index_of_entry.get(&(color, material.key()))
+            {
+                Some(&global) => global,
+                None => 
+                {
+                    let global = self.add_entry(color, material);
+                    let row = global as usize / PALETTE_ROW_LENGTH;
+                    new_rows = Some(match new_rows
+                    {
+                        Some(rows) => rows.start.min(row) .. rows.end.max(row + 1),
+                        None => row .. row + 1,
+                    });
+                    global
+                }
+            };
+            self.references[global as usize] += 1;
+            remap[local] = global;
+        }
+
+        let old_remap = std::mem::replace(&mut self.remaps[prefab_id as usize], remap);
+        for &global in old_remap.iter().filter(|&&global| global != u16::MAX)
+        {
+            self.release_entry(global);
+        }
+
+        match self.layout
+        {
+            PaletteLayout::Global => new_rows.unwrap_or(0..0),
+            PaletteLayout::PerPrefab => 
+                prefab_id as usize .. prefab_id as usize + 1,
+        }
+    }
+
+    // a free entry if there is one, the palette grows otherwise
+    fn add_entry(&mut self, color : u32, material : VoxelMaterial)
+        -> u16
+    {
+        let global = match self.free_entries.pop()
+        {
+            Some(global) => 
+            {
+                self.colors[global as usize] = color;
+                self.materials[global as usize] = material;
+                global
+            }
+            None => 
+            {
+                // u16::MAX stays free to mark empty voxels
+                assert!(self.colors.len() < u16::MAX as usize - 1, "too many distinct prefab colors for a global palette");
+                self.colors.push(color);
+                self.materials.push(material);
+                self.references.push(0);
+                (self.colors.len() - 1) as u16
+            }
+        };
+        self.index_of_entry.insert((color, material.key()), global);
+        global
+    }
+
+    fn release_entry(&mut self, global : u16)
+    {
+        let i = global as usize;
+        self.references[i] -= 1;
+        if self.references[i] > 0
+        {
+            return;
+        }
+
+        let key = (self.colors[i], self.materials[i].key());
+        if self.index_of_entry.get(&key) == Some(&global)
+        {
+            self.index_of_entry.remove(&key);
+        }
+        self.free_entries.push(global);
+    }
+
+    pub fn layout(&self)
+        -> PaletteLayout
+    {
+        self.layout
+    }
+
+    #[allow(dead_code)]
+    pub fn colors(&self)
+        -> &[u32]
+    {
+        &self.colors
+    }
+
+    // recolor tools look entries up through this and find_color
+    #[allow(dead_code)]
+    pub fn global_index(&self, prefab_id : u16, local_index : u16)
+        -> Option<u16>
+    {
+        self.remaps
+            .get(prefab_id as usize)
+            .and_then(|remap| remap.get(local_index as usize))
+            .copied()
+            .filter(|&global| global != u16::MAX)
+    }
+
+    // recolors every prefab voxel that uses this global entry
+    pub fn set_color(&mut self, global_index : u16, color : u32)
+    {
+        let material = self.materials[global_index as usize];
+        self.set_entry(global_index, color, material);
+    }
+
+    // later prefabs with the new color and material merge into the entry
+    fn set_entry(&mut self, global_index : u16, color : u32, material : VoxelMaterial)
+    {
+        let i = global_index as usize;
+        let old_key = (self.colors[i], self.materials[i].key());
+        if self.index_of_entry.get(&old_key) == Some(&global_index)
+        {
+            self.index_of_entry.remove(&old_key);
+        }
+        self.index_of_entry.entry((color, material.key())).or_insert(global_index);
+
+        self.colors[i] = color;
+        self.materials[i] = material;
+    }
+
+    // first global entry in use holding a color
+    #[allow(dead_code)]
+    pub fn find_color(&self, color : u32)
+        -> Option<u16>
+    {
+        (0..self.colors.len())
+            .find(|&i| self.colors[i] == color && self.references[i] > 0)
+            .map(|i| i as u16)
+    }
+
+    // the prefab's volume as the shaders expect it in this layout
+    pub fn remap_volume(&self, prefab_id : u16, palette_volume : &Map3D<u16>)
+        -> Map3D<u16>
+    {
+        match self.layout
+        {
+            PaletteLayout::PerPrefab => Map3D::from_vec(palette_volume.dims(), palette_volume.full_slice().to_vec()),
+            PaletteLayout::Global =>
+            {
+                let remap = &self.remaps[prefab_id as usize];
+                let mut remapped = Map3D::new(palette_volume.dims());
+                remapped.set_all(&|coords| match palette_volume.get(coords)
+                {
+                    Some(local) if (local as usize) < PALETTE_ROW_LENGTH => remap[local as usize],
+                    _ => u16::MAX,
+                });
+                remapped
+            }
+        }
+    }
+
+    // rows the palette array is created with, the global layout can grow up to u16::MAX entries
+    pub fn row_capacity(&self)
+        -> usize
+    {
+        match self.layout
+        {
+            PaletteLayout::Global => (u16::MAX as usize + PALETTE_ROW_LENGTH - 1) / PALETTE_ROW_LENGTH,
+            PaletteLayout::PerPrefab => self.row_count(),
+        }
+    }
+
+    // rows of the palette array in use
+    pub fn row_count(&self)
+        -> usize
+    {
+        match self.layout
+        {
+            PaletteLayout::Global => ((self.colors.len() + PALETTE_ROW_LENGTH - 1) / PALETTE_ROW_LENGTH).max(1),
+            PaletteLayout::PerPrefab => self.remaps.len().max(1),
+        }
+    }
+
+    // Texel data of the palette array, PALETTE_ROW_LENGTH wide.
+    // Global index i lives at (i % 256, i / 256), per prefab rows are filled through the remaps
+    // so recoloring a global entry shows up in both layouts. Unused entries are left black
+    pub fn texels(&self)
+        -> Vec<u32>
+    {
+        self.layout_entries(&self.colors, 0u32)
+    }
+
+    // Texel data of the material array, PALETTE_ROW_LENGTH * MATERIAL_TEXELS wide.
+    // Laid out like the palette array with each entry widened to MATERIAL_TEXELS texels
+    pub fn material_texels(&self)
+        -> Vec<[f32 ; 4]>
+    {
+        self.layout_entries(&self.materials, VoxelMaterial::default())
+            .iter()
+            .flat_map(|material| material.texels().to_vec())
+            .collect()
+    }
+
+    fn layout_entries<T : Copy>(&self, global_entries : &[T], unused : T)
+        -> Vec<T>
+    {
+        let mut entries = vec![unused ; self.row_count() * PALETTE_ROW_LENGTH];
+        match self.layout
+        {
+            PaletteLayout::Global => entries[..global_entries.len()].copy_from_slice(global_entries),
+            PaletteLayout::PerPrefab =>
+            {
+                for (row, remap) in entries.chunks_mut(PALETTE_ROW_LENGTH).zip(&self.remaps)
+                {
+                    for (entry, &global) in row.iter_mut().zip(remap.iter())
+                    {
+                        if global != u16::MAX
+                        {
+                            *entry = global_entries[global as usize];
+                        }
+                    }
+                }
+            }
+        }
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::voxel_material::MaterialKind;
+
+    fn recolored(prefab : &StandardVoxelPrefab, colors : [u32 ; 2])
+        -> StandardVoxelPrefab
+    {
+        let mut palette = prefab.palette;
+        palette[..2].copy_from_slice(&colors);
+        StandardVoxelPrefab::from_palette_volume(
+            Map3D::from_vec(prefab.palette_volume.dims(), prefab.palette_volume.full_slice().to_vec()), 
+            palette, prefab.materials)
+    }
+
+    #[test]
+    fn reloads_reuse_freed_entries()
+    {
+        let placeholder = StandardVoxelPrefab::placeholder();
+        let mut palette = GlobalPalette::build(&[StandardVoxelPrefab::placeholder(), StandardVoxelPrefab::placeholder()], PaletteLayout::Global);
+        assert_eq!(palette.colors().len(), 2);
+
+        for reload in 0..100u32
+        {
+            let colors = [reload * 2 + 2, reload * 2 + 3];
+            let rows = palette.update_prefab(1, &recolored(&placeholder, colors));
+
+            // the new colors are added before the old ones are let go of,
+            // so the reloaded prefab holds at most four entries next to the other prefab's two
+            assert!(palette.colors().len() <= 6);
+            assert!(rows.end <= palette.row_count());
+            for local in 0..2
+            {
+                let global = palette.global_index(1, local).unwrap();
+                assert_eq!(palette.colors()[global as usize], colors[local as usize]);
+                let global = palette.global_index(0, local).unwrap();
+                assert_eq!(palette.colors()[global as usize], placeholder.palette[local as usize]);
+            }
+        }
+    }
+
+    #[test]
+    fn entries_shared_with_the_old_content_are_kept()
+    {
+        let placeholder = StandardVoxelPrefab::placeholder();
+        let mut palette = GlobalPalette::build(&[StandardVoxelPrefab::placeholder()], PaletteLayout::Global);
+
+        let magenta = palette.global_index(0, 0).unwrap();
+        let rows = palette.update_prefab(0, &recolored(&placeholder, [placeholder.palette[0], 0xff00ff00]));
+
+        assert_eq!(palette.global_index(0, 0), Some(magenta));
+        assert_eq!(palette.colors()[palette.global_index(0, 1).unwrap() as usize], 0xff00ff00);
+        assert_eq!(palette.find_color(placeholder.palette[1]), None);
+        assert_eq!(rows, 0..1);
+    }
+
+    #[test]
+    fn build_merges_used_entries_and_remaps_volumes()
+    {
+        const GREEN : u32 = 0xff00ff00;
+        const RED : u32 = 0xff0000ff;
+
+        // magenta and black, with red listed but never used
+        let mut placeholder = StandardVoxelPrefab::placeholder();
+        placeholder.palette[5] = RED;
+        let (magenta, black) = (placeholder.palette[0], placeholder.palette[1]);
+
+        // black and green over a volume with holes
+        let mut volume = Map3D::new([32 ; 3]);
+        volume.set_all(&|c| [0, 1, u16::MAX][(c[0] + c[1] + c[2]) % 3]);
+        let mut palette = [0 ; 256];
+        palette[..2].copy_from_slice(&[black, GREEN]);
+        let holes = StandardVoxelPrefab::from_palette_volume(volume, palette, placeholder.materials);
+
+        // magenta again, but metal
+        let mut metal = recolored(&placeholder, [magenta, black]);
+        metal.materials[0] = VoxelMaterial { kind : MaterialKind::Metal, ..VoxelMaterial::default() };
+
+        let prefabs = [placeholder, holes, metal];
+        let global = GlobalPalette::build(&prefabs, PaletteLayout::Global);
+
+        assert_eq!(global.colors(), &[magenta, black, GREEN, magenta]);
+        assert_eq!(global.global_index(0, 5), None);
+        assert_eq!(global.global_index(1, 0), global.global_index(0, 1));
+        assert_eq!(global.global_index(2, 1), global.global_index(0, 1));
+        assert_ne!(global.global_index(2, 0), global.global_index(0, 0));
+        assert_eq!(global.find_color(RED), None);
+        assert_eq!(global.row_count(), 1);
+        assert_eq!(&global.texels()[..5], &[magenta, black, GREEN, magenta, 0]);
+
+        let remapped = global.remap_volume(1, &prefabs[1].palette_volume);
+        for (coords, local) in prefabs[1].palette_volume.iter()
+        {
+            let expected = if local == u16::MAX { u16::MAX } else { global.global_index(1, local).unwrap() };
+            assert_eq!(remapped.get(coords), Some(expected), "{:?}", coords);
+        }
+
+        // per prefab rows keep the volumes and fill each row from its own palette
+        let per_prefab = GlobalPalette::build(&prefabs, PaletteLayout::PerPrefab);
+        assert_eq!(per_prefab.remap_volume(1, &prefabs[1].palette_volume).full_slice(), prefabs[1].palette_volume.full_slice());
+        assert_eq!(per_prefab.row_count(), 3);
+        let texels = per_prefab.texels();
+        assert_eq!(&texels[..2], &[magenta, black]);
+        assert_eq!(texels[5], 0);
+        assert_eq!(&texels[256..258], &[black, GREEN]);
+        assert_eq!(per_prefab.material_texels()[2 * 512][0], MaterialKind::Metal as u32 as f32);
+    }
+}
@@ -1,23 +1,81 @@
 use displaced_chunks::DisplacedChunks;
-use map_3D::GenerateContext;
+use world_generator::GenerateContext;
 use render::render_context::RenderDescriptor;
 
 pub const WINDOW_X: u32 = 1920;
 pub const WINDOW_Y: u32 = 1080;
 pub const RENDER_RES_X: u32 = 480 * 4;
 pub const RENDER_RES_Y: u32 = 270 * 4;
+// field of view in degrees
+pub const FOV: f32 = 100.;
+// furthest pick distance in cubes
+pub const PICK_DISTANCE: f32 = 32. * 32. * 8.;
+// how often prefab sources are checked for changes, in seconds
+pub const PREFAB_POLL_INTERVAL: f32 = 1.;
+// edge length in cubes of the region written around a picked cube
+pub const VOX_EXPORT_LENGTH: usize = 128;
+// generated chunks taken from the workers and uploaded each frame, the rest wait a frame
+pub const MAX_CHUNKS_RECEIVED_PER_FRAME: usize = 16;
+// prefab cells --export-region writes at most, 512 cells are about 130 MB of colors
+pub const MAX_EXPORT_REGION_CELLS: i64 = 512;
 mod map_3D;
 mod render;
 mod displaced_chunks;
+mod chunk_workers;
+mod chunk_queue;
 mod dot_vox_wrapper;
+mod vox_scene;
+mod prefab_load_error;
 mod bit_voxels;
 mod standard_voxel_prefab;
+mod prefab_registry;
+mod prefab_orientation;
+mod prefab_watcher;
+mod prefab_analysis;
+mod world_generator;
+mod terrain_generator;
+mod generation_pipeline;
+mod structure_placement;
+mod global_palette;
+mod voxel_material;
+mod vox_writer;
+mod palette_chunk;
+mod bit_pyramid;
+mod cpu_march;
+mod picking;
+mod mesh_export;
 
 use nalgebra as na;
 
-use noise::NoiseFn;
-
 fn main() {
+    let (mut registry, mut load_errors) = 
+        match prefab_registry::PrefabRegistry::load(prefab_registry::PREFAB_MANIFEST_PATH) {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                eprintln!("prefab manifest failed to load, using placeholder: {}", e);
+                (prefab_registry::PrefabRegistry::placeholder_only(), vec![e])
+            }
+        };
+    for error in &load_errors {
+        eprintln!("prefab failed to load, using placeholder: {}", error);
+    }
+
+    let mut args: Vec<String> = std::env::args().collect();
+    let seed = match take_seed_arg(&mut args) {
+        Some(seed) => seed,
+        None => return,
+    };
+    println!("world seed: {}", seed);
+    if args.get(1).map(|a| a.as_str()) == Some("--export-region") {
+        export_region_cli(&args[2..], &registry, seed);
+        return;
+    }
+    // the per prefab palette rows the renderer used before the global palette
+    let palette_layout = if args.iter().any(|a| a == "--per-prefab-palettes") {
+        global_palette::PaletteLayout::PerPrefab
+    } else {
+        global_palette::PaletteLayout::Global
+    };
 
     // let open_simplex = noise::OpenSimplex::new();
 
@@ -36,9 +94,17 @@ fn main() {
     window.set_outer_position(winit::dpi::PhysicalPosition{x: 0, y: 0});
 
     let mut view_partition_coords = na::Vector3::new(-121, 0, 412);
-    let mut displaced_chunks = DisplacedChunks::<map_3D::Map3D<u16>>::new(view_partition_coords);
+    let generate_context = GenerateContext::new(&registry, seed);
+    let generator = load_generator(&generate_context);
+    let mut displaced_chunks = DisplacedChunks::<palette_chunk::PaletteChunk>::new(
+        view_partition_coords, Box::new(generator), generate_context, generation_worker_count());
+    let mut picker = picking::Picker::new();
+
+    let mut render_context = render::render_context::RenderContext::new(&window, displaced_chunks.len() as u32, registry.prefabs(), palette_layout);
+    render_context.report_errors(load_errors.iter().map(|e| e.to_string()).collect());
 
-    let mut render_context = render::render_context::RenderContext::new(&window, displaced_chunks.len() as u32);
+    let mut prefab_watcher = prefab_watcher::PrefabWatcher::new(
+        registry.source_paths(), std::time::Duration::from_secs_f32(PREFAB_POLL_INTERVAL));
 
     let mut input = winit_input_helper::WinitInputHelper::new();
 
@@ -48,6 +114,8 @@ fn main() {
 
     let mut orientation = na::UnitQuaternion::<f32>::identity();
     let mut pos = na::Vector3::repeat(15f32);
+    // where the camera was last frame, for the direction it's moving in
+    let mut previous_pos = pos;
 
     let mut window_focused = true;
 
@@ -85,11 +153,32 @@ fn main() {
             },
             winit::event::Event::RedrawRequested(_window_id) => {
 
-                displaced_chunks.try_initialize();
+                displaced_chunks.receive_generated(MAX_CHUNKS_RECEIVED_PER_FRAME);
+
+                let changed_paths = prefab_watcher.poll();
+                for path in &changed_paths {
+                    let (reloaded_ids, errors) = registry.reload_path(path);
+                    render_context.reload_prefabs(registry.prefabs(), &reloaded_ids);
+                    picker.reload_prefabs(&reloaded_ids);
+
+                    // errors of a file last until it loads again
+                    load_errors.retain(|e| e.path() != path);
+                    load_errors.extend(errors);
+                }
+                if !changed_paths.is_empty() {
+                    render_context.report_errors(load_errors.iter().map(|e| e.to_string()).collect());
+                }
 
                 delta_time = frame_time.elapsed().as_secs_f32();
                 frame_time = std::time::Instant::now();
 
+                // chunks in view and ahead of the camera are generated first
+                if delta_time > 0. {
+                    let forward = orientation.transform_vector(&na::Vector3::z());
+                    displaced_chunks.set_view_direction(forward, (pos - previous_pos) / delta_time);
+                }
+                previous_pos = pos;
+
                 let layer_index_data = displaced_chunks.get_index_map();
                 let map_data = displaced_chunks.clean_dirty_chunks();
 
@@ -122,6 +211,26 @@ fn main() {
             if input.key_held(winit::event::VirtualKeyCode::E) {
                 pos -= speed * orientation.transform_vector(&na::Vector3::z()) * delta_time;
             }
+            if input.key_pressed(winit::event::VirtualKeyCode::X) {
+                // writes the world around the crosshair back out for MagicaVoxel,
+                // the crosshair sits on the forward vector
+                let forward = orientation.transform_vector(&na::Vector3::z());
+                let status = match picker.pick(&displaced_chunks, registry.prefabs(), pos, forward, PICK_DISTANCE) {
+                    Some(hit) => {
+                        let min = hit.world_voxel - na::Vector3::repeat(VOX_EXPORT_LENGTH as i64 / 2);
+                        let mut writer = vox_writer::VoxWriter::new();
+                        let written = 
+                            writer.add_world_region(&displaced_chunks, registry.prefabs(), min, [VOX_EXPORT_LENGTH ; 3])
+                            .and_then(|_| writer.write("region.vox"));
+                        match written {
+                            Ok(()) => format!("wrote the region around {:?} to region.vox", hit.world_voxel),
+                            Err(e) => format!("failed to write region.vox: {}", e),
+                        }
+                    },
+                    None => "nothing under the crosshair to export".to_string(),
+                };
+                render_context.report_status(status);
+            }
             if input.key_pressed(winit::event::VirtualKeyCode::P) {
                 view_partition_coords += na::Vector3::new(1, 0, 0);
                 displaced_chunks.set_view_partition_coords(view_partition_coords);
@@ -130,61 +239,110 @@ fn main() {
 
     });
 }
-pub fn fill_voxel(coords: [usize ; 3], partition_coords: na::Vector3<i32>, generate_context: &GenerateContext) 
--> u16 
-{
-    // let world_coords = na::Vector3 + (partition_coords.map(|v| (v * 32) as f64));
-    let world_coords_f64 = [
-        ((coords[0] as i64) + (partition_coords[0] as i64) * 32) as f64,
-        ((coords[1] as i64) + (partition_coords[1] as i64) * 32) as f64,
-        ((coords[2] as i64) + (partition_coords[2] as i64) * 32) as f64,
-    ];
-    let squisher = 0.1;
-    let noise_value = generate_context.open_simplex.get([world_coords_f64[0] * squisher, world_coords_f64[1] * squisher, world_coords_f64[2] * squisher]);
-    if noise_value < -0.2 {
-        if world_coords_f64[1] > 20f64 {
-            return 0;
-        } 
-        else if world_coords_f64[1] > -20f64 {
-            return 2;
-        } else {
-            return 1;
+// all cores but the one rendering
+fn generation_worker_count() -> usize {
+    std::thread::available_parallelism()
+        .map(|n| n.get().saturating_sub(1))
+        .unwrap_or(1)
+        .max(1)
+}
+
+// the configured stages, or terrain alone when the config can't be used
+fn load_generator(generate_context: &GenerateContext) -> generation_pipeline::GenerationPipeline {
+    let pipeline = match generation_pipeline::GenerationPipeline::load(generation_pipeline::GENERATION_CONFIG_PATH, generate_context) {
+        Ok(pipeline) => pipeline,
+        Err(e) => {
+            eprintln!("generation config failed to load, using plain terrain: {}", e);
+            generation_pipeline::GenerationPipeline::terrain_only(generate_context)
         }
+    };
+    for name in pipeline.unknown_prefabs(generate_context) {
+        eprintln!("generation config names prefab \"{}\" which is not in the manifest, it generates as empty", name);
     }
-    u16::MAX
+    pipeline
+}
 
-    // let disp = [15 - coords[0] as i32, 15 - coords[1] as i32, 15 - coords[2] as i32];
-    // let mag_sqr = disp[0] * disp[0] + disp[1] * disp[1] + disp[2] * disp[2];
+// --seed <n> anywhere in the arguments picks the world, otherwise one is made from the clock.
+// Removes the pair so the rest parse as before, None when the seed isn't a number
+fn take_seed_arg(args: &mut Vec<String>) -> Option<u64> {
+    match args.iter().position(|a| a == "--seed") {
+        Some(i) => {
+            let seed = args.get(i + 1).and_then(|a| a.parse().ok());
+            if seed.is_none() {
+                eprintln!("usage: --seed <unsigned integer>");
+            }
+            args.drain(i..(i + 2).min(args.len()));
+            seed
+        }
+        None => Some(
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0)
+        ),
+    }
+}
 
-    // let is_in_sphere = mag_sqr < 20i32.pow(2);
+// --export-region <cell x> <cell y> <cell z> <size x> <size y> <size z> <output path without extension>
+// generates the world partitions covering a block of prefab cells and writes it as .obj/.mtl and .glb
+fn export_region_cli(args: &[String], registry: &prefab_registry::PrefabRegistry, seed: u64) {
+    let usage = "usage: --export-region <cell x> <cell y> <cell z> <size x> <size y> <size z> <output path>";
+    if args.len() != 7 {
+        eprintln!("{}", usage);
+        return;
+    }
+    let numbers: Vec<i64> = match args[..6].iter().map(|a| a.parse()).collect() {
+        Ok(numbers) => numbers,
+        Err(_) => {
+            eprintln!("{}", usage);
+            return;
+        }
+    };
+    if numbers[3..].iter().any(|&n| n <= 0) {
+        eprintln!("region size must be positive");
+        return;
+    }
+    // every cell expands to 32^3 colors before meshing
+    let cell_count = numbers[3..].iter().try_fold(1i64, |count, &n| count.checked_mul(n));
+    if cell_count.map_or(true, |count| count > MAX_EXPORT_REGION_CELLS) {
+        eprintln!("region is larger than {} prefab cells", MAX_EXPORT_REGION_CELLS);
+        return;
+    }
+    let min_cell = na::Vector3::new(numbers[0], numbers[1], numbers[2]);
+    let size_cells = [numbers[3] as usize, numbers[4] as usize, numbers[5] as usize];
+    let output = &args[6];
+
+    let partition_of = |cell: na::Vector3<i64>| cell.map(|c| c.div_euclid(32) as i32);
+    let max_cell = min_cell + na::Vector3::new(numbers[3], numbers[4], numbers[5]) - na::Vector3::repeat(1);
+    let (min_partition, max_partition) = (partition_of(min_cell), partition_of(max_cell));
+
+    let generate_context = GenerateContext::new(registry, seed);
+    let generator = load_generator(&generate_context);
+    let mut partitions = std::collections::HashMap::new();
+    for x in min_partition.x..=max_partition.x {
+    for y in min_partition.y..=max_partition.y {
+    for z in min_partition.z..=max_partition.z {
+        let partition_coords = na::Vector3::new(x, y, z);
+        let mut map = <map_3D::Map3D<u16> as displaced_chunks::ChunkData>::allocate();
+        displaced_chunks::ChunkData::initialize(&mut map, partition_coords, &generator, &generate_context);
+        partitions.insert(partition_coords, map);
+    }}}
+
+    let region = mesh_export::prefab_region_colors(size_cells, registry.prefabs(), |local| {
+        let cell = min_cell + na::Vector3::new(local[0] as i64, local[1] as i64, local[2] as i64);
+        let in_partition = cell.map(|c| c.rem_euclid(32) as usize);
+        partitions[&partition_of(cell)]
+            .get([in_partition.x, in_partition.y, in_partition.z])
+            .unwrap_or(u16::MAX)
+    });
 
-    // if partition_coords.y < 0 && coords == [15, 15, 15] {
-    //     return 0;
-    // }
-    // if partition_coords.y < 0 {
-    //     return u16::MAX;
-    // }
+    let mesh = mesh_export::greedy_mesh(&region, |color| color);
+    println!("exporting {} quads to {}", mesh.quads.len(), output);
 
-    // if partition_coords.x.rem_euclid(4) == 0 {
-    //     if is_in_sphere {
-    //         0
-    //     } else {
-    //         u16::MAX
-    //     }
-    // } else if partition_coords.x.rem_euclid(4) == 1 {
-    //     if is_in_sphere {
-    //         u16::MAX
-    //     } else {
-    //         0
-    //     }
-    // } else if partition_coords.x.rem_euclid(4) == 2 {
-    //     1
-    // } else {
-    //     if (coords[0] / 4 + coords[1] / 4  + coords[2] / 4) % 2 != 0 && !is_in_sphere {
-    //         2
-    //     } else {
-    //         u16::MAX
-    //     }
-    // }
+    if let Err(e) = mesh_export::write_obj(&mesh, output) {
+        eprintln!("failed to write obj: {}", e);
+    }
+    if let Err(e) = mesh_export::write_glb(&mesh, &format!("{}.glb", output)) {
+        eprintln!("failed to write glb: {}", e);
+    }
 }
-
@@ -1,89 +1,436 @@
+
 pub struct Map3D<T: Clone + Default + Copy> {
     data: Vec<T>,
-    length: usize,
+    dims: [usize ; 3],
+}
+
+// returned by set when the coordinates fall outside the dims of the map
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutOfBounds {
+    pub coords: [usize ; 3],
+    pub dims: [usize ; 3],
 }
 
 #[allow(dead_code)]
 impl<T: Clone + Default + Copy> Map3D<T> {
 
-    pub fn length(&self) 
+    pub fn dims(&self) 
+    -> [usize ; 3] {
+        self.dims
+    }
+
+    pub fn volume(&self) 
     -> usize {
-        self.length
+        self.data.len()
     }
-    pub fn new(length: usize) 
+
+    pub fn new(dims: [usize ; 3]) 
+    -> Self {
+        Self::new_with_default(dims, T::default())
+    }
+
+    pub fn new_with_default(dims: [usize ; 3], default_val: T) 
     -> Self {
         Self {
-            data: vec![T::default() ; length.pow(3)],
-            length,
+            data: vec![default_val ; dims[0] * dims[1] * dims[2]],
+            dims,
         }
     }
 
-    pub fn new_with_default(length: usize, default_val: T) 
+    // takes ownership of data laid out in index order
+    pub fn from_vec(dims: [usize ; 3], data: Vec<T>) 
     -> Self {
+        assert!(data.len() == dims[0] * dims[1] * dims[2], "data length does not match dims");
         Self {
-            data: vec![default_val ; length.pow(3)],
-            length
+            data,
+            dims,
         }
     }
 
+    pub fn in_bounds(&self, coords: [usize ; 3]) 
+    -> bool {
+        coords[0] < self.dims[0] &&
+        coords[1] < self.dims[1] &&
+        coords[2] < self.dims[2]
+    }
+
+    // x-major order: x, then y, then z
+    // does not check bounds, see try_index
     pub fn index(&self, coords: [usize ; 3]) 
     -> usize {
         coords[0] + 
-        coords[1] * self.length +
-        coords[2] * self.length.pow(2)
+        coords[1] * self.dims[0] +
+        coords[2] * self.dims[0] * self.dims[1]
     }
 
-    pub fn coords(index: usize, length: usize) 
+    pub fn try_index(&self, coords: [usize ; 3]) 
+    -> Option<usize> {
+        if self.in_bounds(coords) {
+            Some(self.index(coords))
+        } else {
+            None
+        }
+    }
+
+    pub fn coords(&self, index: usize) 
+    -> [usize ; 3] {
+        Self::coords_from_dims(index, self.dims)
+    }
+
+    pub fn coords_from_dims(index: usize, dims: [usize ; 3]) 
     -> [usize ; 3] {
         [
-            index % length,
-            (index / length) % length,
-            index / length.pow(2),
+            index % dims[0],
+            (index / dims[0]) % dims[1],
+            index / (dims[0] * dims[1]),
         ]
     }
 
     pub fn get(&self, coords: [usize ; 3]) 
-    -> T {
-        self.data[self.index(coords)]
+    -> Option<T> {
+        self.try_index(coords).map(|i| self.data[i])
     }
 
-    pub fn set(&mut self, coords: [usize ; 3], value: T) {
-        let i = self.index(coords);
-        self.data[i] = value
+    pub fn set(&mut self, coords: [usize ; 3], value: T) 
+    -> Result<(), OutOfBounds> {
+        match self.try_index(coords) {
+            Some(i) => {
+                self.data[i] = value;
+                Ok(())
+            },
+            None => Err(OutOfBounds { coords, dims: self.dims }),
+        }
     }
 
     pub fn set_all(&mut self, value_fn : &dyn Fn([usize ; 3]) -> T) {
-        let length = self.length;
+        let dims = self.dims;
         self.data
         .iter_mut()
         .enumerate()
-        .for_each(|(i, m)| *m = value_fn(Self::coords(i, length)));
+        .for_each(|(i, m)| *m = value_fn(Self::coords_from_dims(i, dims)));
+    }
+
+    // every coordinate of the map, in index order
+    pub fn coords_iter(&self) 
+    -> impl Iterator<Item = [usize ; 3]> {
+        let dims = self.dims;
+        (0..self.data.len()).map(move |i| Self::coords_from_dims(i, dims))
+    }
+
+    // every coordinate of the map with its value, in index order
+    pub fn iter(&self) 
+    -> impl Iterator<Item = ([usize ; 3], T)> + '_ {
+        let dims = self.dims;
+        self.data
+        .iter()
+        .enumerate()
+        .map(move |(i, v)| (Self::coords_from_dims(i, dims), *v))
     }
 
     pub fn full_slice(&self) 
     -> &[T] {
         &self.data
     }
-}
 
+    // clips the box starting at min with the given size to the map dims
+    // returns the inclusive min and exclusive max, or None when nothing overlaps
+    pub fn clip_box(&self, min: [usize ; 3], size: [usize ; 3]) 
+    -> Option<([usize ; 3], [usize ; 3])> {
+        let mut clipped_max = [0 ; 3];
+        for axis in 0..3 {
+            clipped_max[axis] = min[axis].saturating_add(size[axis]).min(self.dims[axis]);
+            if min[axis] >= clipped_max[axis] {
+                return None;
+            }
+        }
+        Some((min, clipped_max))
+    }
+
+    pub fn fill_box(&mut self, min: [usize ; 3], size: [usize ; 3], value: T) {
+        if let Some((min, max)) = self.clip_box(min, size) {
+            for z in min[2]..max[2] {
+            for y in min[1]..max[1] {
+                let row_start = self.index([min[0], y, z]);
+                let row_end = row_start + (max[0] - min[0]);
+                self.data[row_start..row_end]
+                .iter_mut()
+                .for_each(|v| *v = value);
+            }}
+        }
+    }
+
+    // swaps every occurrence of from with to inside the box
+    pub fn replace_in_box(&mut self, min: [usize ; 3], size: [usize ; 3], from: T, to: T) 
+    where T: PartialEq {
+        if let Some((min, max)) = self.clip_box(min, size) {
+            for z in min[2]..max[2] {
+            for y in min[1]..max[1] {
+                let row_start = self.index([min[0], y, z]);
+                let row_end = row_start + (max[0] - min[0]);
+                self.data[row_start..row_end]
+                .iter_mut()
+                .filter(|v| **v == from)
+                .for_each(|v| *v = to);
+            }}
+        }
+    }
 
-pub struct GenerateContext {
-    pub open_simplex: noise::OpenSimplex,
+    // copies the box of src starting at src_min so that src_min lands on dst_offset
+    // anything falling outside of either map is clipped
+    pub fn copy_region(&mut self, src: &Map3D<T>, src_min: [usize ; 3], size: [usize ; 3], dst_offset: [i32 ; 3]) {
+        self.for_each_region_row(src, src_min, size, dst_offset, 
+            |dst_row, src_row| dst_row.copy_from_slice(src_row)
+        );
+    }
+
+    // like copy_region, except voxels of src equal to empty leave the destination untouched
+    pub fn overlay_region(&mut self, src: &Map3D<T>, src_min: [usize ; 3], size: [usize ; 3], dst_offset: [i32 ; 3], empty: T) 
+    where T: PartialEq {
+        self.for_each_region_row(src, src_min, size, dst_offset, 
+            |dst_row, src_row| 
+                dst_row
+                .iter_mut()
+                .zip(src_row.iter())
+                .filter(|(_, s)| **s != empty)
+                .for_each(|(d, s)| *d = *s)
+        );
+    }
+
+    // copies the whole of src with its origin at dst_offset
+    pub fn blit(&mut self, src: &Map3D<T>, dst_offset: [i32 ; 3]) {
+        self.copy_region(src, [0 ; 3], src.dims, dst_offset);
+    }
+
+    // new map of the given size holding the box starting at min
+    // voxels outside of this map are left as the default value
+    pub fn extract(&self, min: [usize ; 3], size: [usize ; 3]) 
+    -> Map3D<T> {
+        let mut extracted = Map3D::new(size);
+        extracted.copy_region(self, min, size, [0 ; 3]);
+        extracted
+    }
+
+    // calls row_fn on every pair of overlapping x rows
+    fn for_each_region_row(&mut self, src: &Map3D<T>, src_min: [usize ; 3], size: [usize ; 3], dst_offset: [i32 ; 3], 
+        mut row_fn: impl FnMut(&mut [T], &[T])) 
+    {
+        // clip in source coordinates: a source voxel s lands on s - src_min + dst_offset
+        let mut start = [0usize ; 3];
+        let mut end = [0usize ; 3];
+        for axis in 0..3 {
+            let shift = dst_offset[axis] as i64 - src_min[axis] as i64;
+            let lower = (src_min[axis] as i64).max(-shift);
+            let upper = 
+                (src_min[axis] as i64 + size[axis] as i64)
+                .min(src.dims[axis] as i64)
+                .min(self.dims[axis] as i64 - shift);
+            if lower >= upper {
+                return;
+            }
+            start[axis] = lower as usize;
+            end[axis] = upper as usize;
+        }
+
+        let to_dst = |s: [usize ; 3]| {
+            let mut d = [0usize ; 3];
+            for axis in 0..3 {
+                d[axis] = (s[axis] as i64 - src_min[axis] as i64 + dst_offset[axis] as i64) as usize;
+            }
+            d
+        };
+
+        let row_len = end[0] - start[0];
+        for z in start[2]..end[2] {
+        for y in start[1]..end[1] {
+            let src_row_start = src.index([start[0], y, z]);
+            let dst_row_start = self.index(to_dst([start[0], y, z]));
+            row_fn(
+                &mut self.data[dst_row_start..dst_row_start + row_len],
+                &src.data[src_row_start..src_row_start + row_len],
+            );
+        }}
+    }
 }
+
+
 use nalgebra as na;
+use crate::world_generator::{GenerateContext, WorldGenerator, PARTITION_LENGTH};
 impl super::displaced_chunks::ChunkData for Map3D<u16> {
     fn allocate() -> Self {
-        Map3D::new(32)
+        Map3D::new([PARTITION_LENGTH ; 3])
     }
 
-    fn initialize(&mut self, world_chunk_coords: na::Vector3<i32>) {
-        let open_simplex = noise::OpenSimplex::new();
-        let generate_context = GenerateContext {
-            open_simplex
-        };
+    fn initialize(&mut self, world_chunk_coords: na::Vector3<i32>, generator: &dyn WorldGenerator, generate_context: &GenerateContext) {
         // println!("{}", min);
-        self.set_all(
-            &(|coords| super::fill_voxel(coords, world_chunk_coords, &generate_context))
-        );
+        generator.generate(world_chunk_coords, generate_context, self);
+    }
+
+    fn voxel_index(&self, coords: [usize ; 3]) -> u16 {
+        self.get(coords).unwrap_or(u16::MAX)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::{Map3D, OutOfBounds};
+
+    // src cells hold 1 + their index so every written cell says where it came from
+    fn numbered(dims: [usize ; 3]) 
+    -> Map3D<u16> {
+        let mut map = Map3D::new(dims);
+        map.set_all(&|c| 1 + (c[0] + c[1] * dims[0] + c[2] * dims[0] * dims[1]) as u16);
+        map
+    }
+
+    // the cells of dst that differ from 0, with their values
+    fn written(dst: &Map3D<u16>) 
+    -> Vec<([usize ; 3], u16)> {
+        dst.iter().filter(|(_, v)| *v != 0).collect()
+    }
+
+    #[test]
+    fn copy_region_negative_offset() {
+        let src = numbered([3 ; 3]);
+        let mut dst = Map3D::<u16>::new([4 ; 3]);
+        dst.copy_region(&src, [0 ; 3], [3 ; 3], [-1, -1, -1]);
+
+        let mut expected = Vec::new();
+        for z in 0..2 {
+        for y in 0..2 {
+        for x in 0..2 {
+            expected.push(([x, y, z], src.get([x + 1, y + 1, z + 1]).unwrap()));
+        }}}
+        expected.sort();
+        let mut actual = written(&dst);
+        actual.sort();
+        assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn copy_region_partial_overlap_each_axis() {
+        let src = numbered([3 ; 3]);
+        for axis in 0..3 {
+            let mut dst = Map3D::<u16>::new([4 ; 3]);
+            let mut offset = [0 ; 3];
+            offset[axis] = 3;
+            dst.copy_region(&src, [0 ; 3], [3 ; 3], offset);
+
+            // only the src layer at 0 along the axis lands, on the dst layer at 3
+            let mut expected: Vec<_> = 
+                src.iter()
+                .filter(|(c, _)| c[axis] == 0)
+                .map(|(c, v)| {
+                    let mut d = c;
+                    d[axis] = 3;
+                    (d, v)
+                })
+                .collect();
+            expected.sort();
+            let mut actual = written(&dst);
+            actual.sort();
+            assert_eq!(actual, expected, "axis {}", axis);
+            assert_eq!(actual.len(), 9);
+        }
+    }
+
+    #[test]
+    fn copy_region_fully_outside() {
+        let src = numbered([3 ; 3]);
+        for &offset in &[[4, 0, 0], [0, -3, 0], [0, 0, 100], [-100, -100, -100]] {
+            let mut dst = Map3D::<u16>::new([4 ; 3]);
+            dst.copy_region(&src, [0 ; 3], [3 ; 3], offset);
+            dst.overlay_region(&src, [0 ; 3], [3 ; 3], offset, 0);
+            assert!(written(&dst).is_empty(), "offset {:?}", offset);
+        }
+        let dst = Map3D::<u16>::new([4 ; 3]);
+        assert_eq!(dst.clip_box([4, 0, 0], [2 ; 3]), None);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn zero_size_box() {
+        let src = numbered([3 ; 3]);
+        for axis in 0..3 {
+            let mut size = [3 ; 3];
+            size[axis] = 0;
+            let mut dst = Map3D::<u16>::new([4 ; 3]);
+            dst.copy_region(&src, [0 ; 3], size, [0 ; 3]);
+            dst.overlay_region(&src, [0 ; 3], size, [0 ; 3], 0);
+            dst.fill_box([0 ; 3], size, 7);
+            assert!(written(&dst).is_empty(), "axis {}", axis);
+            assert_eq!(dst.clip_box([0 ; 3], size), None);
+        }
+    }
+
+    #[test]
+    fn clip_box_clips_to_dims() {
+        let map = Map3D::<u16>::new([4, 5, 6]);
+        assert_eq!(map.clip_box([1, 2, 3], [10, 1, 2]), Some(([1, 2, 3], [4, 3, 5])));
+        assert_eq!(map.clip_box([3, 4, 5], [1 ; 3]), Some(([3, 4, 5], [4, 5, 6])));
+    }
+
+    #[test]
+    fn overlay_region_skips_empty() {
+        let mut src = numbered([2 ; 3]);
+        src.set([1, 1, 1], 0).unwrap();
+        let mut dst = Map3D::new_with_default([3 ; 3], 9u16);
+        dst.overlay_region(&src, [0 ; 3], [2 ; 3], [1, 1, 1], 0);
+
+        for (c, v) in dst.iter() {
+            let expected = 
+                if c == [2, 2, 2] || c.contains(&0) { 9 }
+                else { src.get([c[0] - 1, c[1] - 1, c[2] - 1]).unwrap() };
+            assert_eq!(v, expected, "{:?}", c);
+        }
+    }
+
+    // no two axes the same length, so swapped axes show up
+    const DIMS: [usize ; 3] = [3, 5, 2];
+
+    #[test]
+    fn index_and_coords_are_x_major_on_a_non_cube() {
+        let map = Map3D::<u16>::new(DIMS);
+        assert_eq!(map.volume(), 30);
+        let cases = [([0, 0, 0], 0), ([1, 0, 0], 1), ([0, 1, 0], 3), ([0, 0, 1], 15), ([2, 4, 1], 29), ([1, 3, 1], 25)];
+        for &(coords, index) in cases.iter() {
+            assert_eq!(map.index(coords), index, "{:?}", coords);
+            assert_eq!(map.try_index(coords), Some(index), "{:?}", coords);
+            assert_eq!(map.coords(index), coords, "{}", index);
+        }
+        for index in 0..map.volume() {
+            assert_eq!(map.index(map.coords(index)), index);
+        }
+    }
+
+    #[test]
+    fn get_and_set_check_each_axis() {
+        let mut map = numbered(DIMS);
+        for &coords in [[3, 0, 0], [0, 5, 0], [0, 0, 2], [usize::MAX, 0, 0]].iter() {
+            assert!(!map.in_bounds(coords));
+            assert_eq!(map.try_index(coords), None, "{:?}", coords);
+            assert_eq!(map.get(coords), None, "{:?}", coords);
+            assert_eq!(map.set(coords, 0), Err(OutOfBounds { coords, dims: DIMS }));
+        }
+        // failed sets leave the map alone
+        assert_eq!(map.full_slice(), numbered(DIMS).full_slice());
+
+        assert_eq!(map.get([2, 4, 1]), Some(30));
+        assert_eq!(map.set([2, 4, 1], 100), Ok(()));
+        assert_eq!(map.get([2, 4, 1]), Some(100));
+        assert_eq!(map.full_slice()[29], 100);
+    }
+
+    #[test]
+    fn iterators_visit_every_cell_in_index_order() {
+        let map = numbered(DIMS);
+        let coords: Vec<[usize ; 3]> = map.coords_iter().collect();
+        assert_eq!(coords.len(), 30);
+        assert_eq!(&coords[..4], &[[0, 0, 0], [1, 0, 0], [2, 0, 0], [0, 1, 0]]);
+        assert_eq!(coords[29], [2, 4, 1]);
+        for (index, &c) in coords.iter().enumerate() {
+            assert_eq!(map.index(c), index);
+        }
+
+        let values: Vec<([usize ; 3], u16)> = map.iter().collect();
+        assert_eq!(values.iter().map(|&(c, _)| c).collect::<Vec<_>>(), coords);
+        assert!(values.iter().enumerate().all(|(index, &(_, value))| value as usize == index + 1));
+    }
+}
@@ -0,0 +1,418 @@
+// Greedy meshing of voxel volumes into colored quads,
+// with writers for OBJ (+ MTL) and binary glTF 2.0.
+// Colors are u32 in the dot_vox palette layout (r in the lowest byte, a in the highest)
+
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::map_3D::Map3D;
+use crate::standard_voxel_prefab::StandardVoxelPrefab;
+
+const PREFAB_LENGTH: usize = 32;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Quad {
+    // counter clockwise when viewed from the side the normal points to
+    pub corners: [[f32 ; 3] ; 4],
+    pub normal: [i32 ; 3],
+    pub color: u32,
+}
+
+#[derive(Default)]
+pub struct QuadMesh {
+    pub quads: Vec<Quad>,
+}
+
+// Merges visible faces of equal color into as few quads as possible.
+// color_fn returns None for empty space
+pub fn greedy_mesh<T: Clone + Default + Copy>(volume: &Map3D<T>, color_fn: impl Fn(T) -> Option<u32>) 
+-> QuadMesh {
+    let dims = volume.dims();
+    let color_at = |coords: [i64 ; 3]| -> Option<u32> {
+        if coords.iter().zip(dims.iter()).any(|(&c, &d)| c < 0 || c >= d as i64) {
+            return None;
+        }
+        volume.get([coords[0] as usize, coords[1] as usize, coords[2] as usize]).and_then(&color_fn)
+    };
+
+    let mut mesh = QuadMesh::default();
+
+    for d in 0..3 {
+        let u = (d + 1) % 3;
+        let v = (d + 2) % 3;
+
+        for &positive in [false, true].iter() {
+            let mut mask: Vec<Option<u32>> = vec![None ; dims[u] * dims[v]];
+
+            // a face sits on slice s between the cells s - 1 and s
+            for s in 0..=dims[d] {
+                for j in 0..dims[v] {
+                for i in 0..dims[u] {
+                    let mut front = [0i64 ; 3];
+                    front[d] = s as i64;
+                    front[u] = i as i64;
+                    front[v] = j as i64;
+                    let mut back = front;
+                    back[d] -= 1;
+
+                    let (solid, open) = if positive { (back, front) } else { (front, back) };
+                    mask[i + j * dims[u]] = match (color_at(solid), color_at(open)) {
+                        (Some(color), None) => Some(color),
+                        _ => None,
+                    };
+                }}
+
+                greedy_merge_slice(&mut mask, [dims[u], dims[v]], |i, j, w, h, color| {
+                    let corner = |a: usize, b: usize| {
+                        let mut c = [0f32 ; 3];
+                        c[d] = s as f32;
+                        c[u] = a as f32;
+                        c[v] = b as f32;
+                        c
+                    };
+                    let mut corners = [corner(i, j), corner(i + w, j), corner(i + w, j + h), corner(i, j + h)];
+                    if !positive {
+                        corners.reverse();
+                    }
+                    let mut normal = [0 ; 3];
+                    normal[d] = if positive { 1 } else { -1 };
+                    mesh.quads.push(Quad { corners, normal, color });
+                });
+            }
+        }
+    }
+
+    mesh
+}
+
+// grows rectangles of equal color along u, then along v, clearing the mask as it goes
+fn greedy_merge_slice(mask: &mut [Option<u32>], dims: [usize ; 2], mut emit: impl FnMut(usize, usize, usize, usize, u32)) {
+    for j in 0..dims[1] {
+        let mut i = 0;
+        while i < dims[0] {
+            let color = match mask[i + j * dims[0]] {
+                Some(color) => color,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+
+            let mut w = 1;
+            while i + w < dims[0] && mask[i + w + j * dims[0]] == Some(color) {
+                w += 1;
+            }
+
+            let mut h = 1;
+            'grow: while j + h < dims[1] {
+                for k in 0..w {
+                    if mask[i + k + (j + h) * dims[0]] != Some(color) {
+                        break 'grow;
+                    }
+                }
+                h += 1;
+            }
+
+            for b in 0..h {
+            for a in 0..w {
+                mask[i + a + (j + b) * dims[0]] = None;
+            }}
+
+            emit(i, j, w, h, color);
+            i += w;
+        }
+    }
+}
+
+// Colors of a block of prefab cells, each cell expanding into its 32^3 prefab.
+// cell_fn gives the prefab id of a cell, anything that is not a loaded prefab stays empty
+pub fn prefab_region_colors(size_cells: [usize ; 3], prefabs: &[StandardVoxelPrefab], cell_fn: impl Fn([usize ; 3]) -> u16) 
+-> Map3D<Option<u32>> {
+    let prefab_colors: Vec<Map3D<Option<u32>>> = 
+        prefabs
+        .iter()
+        .map(|prefab| {
+            let mut colors = Map3D::new(prefab.palette_volume.dims());
+            colors.set_all(&|coords| 
+                prefab.palette_volume.get(coords)
+                .filter(|&i| i != u16::MAX)
+                .and_then(|i| prefab.palette.get(i as usize).copied())
+            );
+            colors
+        })
+        .collect();
+
+    let mut region = Map3D::new([
+        size_cells[0] * PREFAB_LENGTH, 
+        size_cells[1] * PREFAB_LENGTH, 
+        size_cells[2] * PREFAB_LENGTH,
+    ]);
+
+    for z in 0..size_cells[2] {
+    for y in 0..size_cells[1] {
+    for x in 0..size_cells[0] {
+        if let Some(colors) = prefab_colors.get(cell_fn([x, y, z]) as usize) {
+            let offset = [(x * PREFAB_LENGTH) as i32, (y * PREFAB_LENGTH) as i32, (z * PREFAB_LENGTH) as i32];
+            region.blit(colors, offset);
+        }
+    }}}
+
+    region
+}
+
+
+fn color_channels(color: u32) 
+-> [u8 ; 4] {
+    color.to_le_bytes()
+}
+
+fn srgb_to_linear(channel: u8) 
+-> f32 {
+    let c = channel as f32 / 255.;
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn material_name(color: u32) 
+-> String {
+    let [r, g, b, _] = color_channels(color);
+    format!("color_{:02x}{:02x}{:02x}", r, g, b)
+}
+
+// writes <path_base>.obj and <path_base>.mtl, one material per color
+pub fn write_obj(mesh: &QuadMesh, path_base: &str) 
+-> std::io::Result<()> {
+    let mtl_path = format!("{}.mtl", path_base);
+    let mtl_file_name = 
+        std::path::Path::new(&mtl_path)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| mtl_path.clone());
+
+    // quads grouped by color so each material is used once
+    let mut by_color: BTreeMap<u32, Vec<&Quad>> = BTreeMap::new();
+    for quad in &mesh.quads {
+        by_color.entry(quad.color).or_insert_with(Vec::new).push(quad);
+    }
+
+    {
+        let mut mtl = std::io::BufWriter::new(std::fs::File::create(&mtl_path)?);
+        for &color in by_color.keys() {
+            let [r, g, b, _] = color_channels(color);
+            writeln!(mtl, "newmtl {}", material_name(color))?;
+            writeln!(mtl, "Kd {:.4} {:.4} {:.4}", r as f32 / 255., g as f32 / 255., b as f32 / 255.)?;
+            writeln!(mtl, "illum 1")?;
+            writeln!(mtl)?;
+        }
+    }
+
+    let mut obj = std::io::BufWriter::new(std::fs::File::create(format!("{}.obj", path_base))?);
+    writeln!(obj, "mtllib {}", mtl_file_name)?;
+
+    // the six axis normals, indexed by normal_index
+    for normal in AXIS_NORMALS.iter() {
+        writeln!(obj, "vn {} {} {}", normal[0], normal[1], normal[2])?;
+    }
+
+    let mut vertex_count = 0;
+    for (&color, quads) in &by_color {
+        writeln!(obj, "usemtl {}", material_name(color))?;
+        for quad in quads {
+            for corner in quad.corners.iter() {
+                writeln!(obj, "v {} {} {}", corner[0], corner[1], corner[2])?;
+            }
+            let n = normal_index(quad.normal) + 1;
+            let base = vertex_count + 1;
+            writeln!(obj, "f {}//{} {}//{} {}//{} {}//{}", 
+                base, n, base + 1, n, base + 2, n, base + 3, n)?;
+            vertex_count += 4;
+        }
+    }
+
+    Ok(())
+}
+
+const AXIS_NORMALS: [[i32 ; 3] ; 6] = [
+    [1, 0, 0], [-1, 0, 0],
+    [0, 1, 0], [0, -1, 0],
+    [0, 0, 1], [0, 0, -1],
+];
+
+fn normal_index(normal: [i32 ; 3]) 
+-> usize {
+    AXIS_NORMALS.iter().position(|&n| n == normal).expect("quad normals are axis aligned")
+}
+
+// writes a single mesh with vertex colors as binary glTF 2.0,
+// an empty mesh is an error since glTF has no valid empty buffers or accessors
+pub fn write_glb(mesh: &QuadMesh, path: &str) 
+-> std::io::Result<()> {
+    if mesh.quads.is_empty() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "the mesh has no quads"));
+    }
+
+    let vertex_count = mesh.quads.len() * 4;
+
+    let mut positions: Vec<u8> = Vec::with_capacity(vertex_count * 12);
+    let mut normals: Vec<u8> = Vec::with_capacity(vertex_count * 12);
+    let mut colors: Vec<u8> = Vec::with_capacity(vertex_count * 16);
+    let mut indices: Vec<u8> = Vec::with_capacity(mesh.quads.len() * 6 * 4);
+
+    let mut min = [f32::MAX ; 3];
+    let mut max = [f32::MIN ; 3];
+
+    for (q, quad) in mesh.quads.iter().enumerate() {
+        let [r, g, b, _] = color_channels(quad.color);
+        let linear = [srgb_to_linear(r), srgb_to_linear(g), srgb_to_linear(b), 1.];
+
+        for corner in quad.corners.iter() {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(corner[axis]);
+                max[axis] = max[axis].max(corner[axis]);
+                positions.extend_from_slice(&corner[axis].to_le_bytes());
+                normals.extend_from_slice(&(quad.normal[axis] as f32).to_le_bytes());
+            }
+            linear.iter().for_each(|c| colors.extend_from_slice(&c.to_le_bytes()));
+        }
+
+        let base = (q * 4) as u32;
+        [0, 1, 2, 0, 2, 3]
+        .iter()
+        .for_each(|i: &u32| indices.extend_from_slice(&(base + i).to_le_bytes()));
+    }
+
+    let views = [&positions, &normals, &colors, &indices];
+    let mut bin: Vec<u8> = Vec::new();
+    let mut view_offsets = Vec::new();
+    for view in views.iter() {
+        view_offsets.push(bin.len());
+        bin.extend_from_slice(view);
+    }
+
+    let json = format!(
+r#"{{"asset":{{"version":"2.0","generator":"gfx_testing mesh_export"}},"scene":0,"scenes":[{{"nodes":[0]}}],"nodes":[{{"mesh":0}}],"materials":[{{"pbrMetallicRoughness":{{"baseColorFactor":[1,1,1,1],"metallicFactor":0,"roughnessFactor":1}}}}],"meshes":[{{"primitives":[{{"attributes":{{"POSITION":0,"NORMAL":1,"COLOR_0":2}},"indices":3,"material":0}}]}}],"buffers":[{{"byteLength":{bin_len}}}],"bufferViews":[{{"buffer":0,"byteOffset":{o0},"byteLength":{l0},"target":34962}},{{"buffer":0,"byteOffset":{o1},"byteLength":{l1},"target":34962}},{{"buffer":0,"byteOffset":{o2},"byteLength":{l2},"target":34962}},{{"buffer":0,"byteOffset":{o3},"byteLength":{l3},"target":34963}}],"accessors":[{{"bufferView":0,"componentType":5126,"count":{vc},"type":"VEC3","min":[{min0},{min1},{min2}],"max":[{max0},{max1},{max2}]}},{{"bufferView":1,"componentType":5126,"count":{vc},"type":"VEC3"}},{{"bufferView":2,"componentType":5126,"count":{vc},"type":"VEC4"}},{{"bufferView":3,"componentType":5125,"count":{ic},"type":"SCALAR"}}]}}"#,
+        bin_len = bin.len(),
+        o0 = view_offsets[0], l0 = positions.len(),
+        o1 = view_offsets[1], l1 = normals.len(),
+        o2 = view_offsets[2], l2 = colors.len(),
+        o3 = view_offsets[3], l3 = indices.len(),
+        vc = vertex_count,
+        ic = mesh.quads.len() * 6,
+        min0 = min[0], min1 = min[1], min2 = min[2],
+        max0 = max[0], max1 = max[1], max2 = max[2],
+    );
+
+    let mut json_chunk = json.into_bytes();
+    while json_chunk.len() % 4 != 0 {
+        json_chunk.push(b' ');
+    }
+    while bin.len() % 4 != 0 {
+        bin.push(0);
+    }
+
+    let total_length = 12 + 8 + json_chunk.len() + 8 + bin.len();
+
+    let mut glb = std::io::BufWriter::new(std::fs::File::create(path)?);
+    glb.write_all(b"glTF")?;
+    glb.write_all(&2u32.to_le_bytes())?;
+    glb.write_all(&(total_length as u32).to_le_bytes())?;
+
+    glb.write_all(&(json_chunk.len() as u32).to_le_bytes())?;
+    glb.write_all(b"JSON")?;
+    glb.write_all(&json_chunk)?;
+
+    glb.write_all(&(bin.len() as u32).to_le_bytes())?;
+    glb.write_all(b"BIN\0")?;
+    glb.write_all(&bin)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RED: u32 = 0xff0000ff;
+
+    // two cubes along x
+    fn bar() 
+    -> QuadMesh {
+        let volume = Map3D::new_with_default([2, 1, 1], true);
+        greedy_mesh(&volume, |present| if present { Some(RED) } else { None })
+    }
+
+    fn sub(a: [f32 ; 3], b: [f32 ; 3]) 
+    -> [f32 ; 3] {
+        [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+    }
+
+    #[test]
+    fn bar_meshes_into_one_counter_clockwise_quad_per_side() {
+        let mesh = bar();
+        assert_eq!(mesh.quads.len(), 6);
+
+        for normal in AXIS_NORMALS.iter() {
+            let quads: Vec<_> = mesh.quads.iter().filter(|q| q.normal == *normal).collect();
+            assert_eq!(quads.len(), 1, "{:?}", normal);
+            let quad = quads[0];
+            assert_eq!(quad.color, RED);
+
+            // the cross product of the first two edges points along the normal, its length is the area
+            let a = sub(quad.corners[1], quad.corners[0]);
+            let b = sub(quad.corners[2], quad.corners[0]);
+            let cross = [a[1] * b[2] - a[2] * b[1], a[2] * b[0] - a[0] * b[2], a[0] * b[1] - a[1] * b[0]];
+            let area = if normal[0] == 0 { 2. } else { 1. };
+            assert_eq!(cross, [normal[0] as f32 * area, normal[1] as f32 * area, normal[2] as f32 * area]);
+
+            // the face lies on the side of the bar it faces
+            let axis = normal.iter().position(|&n| n != 0).unwrap();
+            let side = if normal[axis] > 0 { [2., 1., 1.][axis] } else { 0. };
+            assert!(quad.corners.iter().all(|c| c[axis] == side), "{:?}", quad.corners);
+        }
+    }
+
+    #[test]
+    fn bar_writes_obj_vertices_and_faces() {
+        let path_base = std::env::temp_dir().join("mesh_export_bar_test");
+        let path_base = path_base.to_str().unwrap();
+        let mesh = bar();
+        write_obj(&mesh, path_base).unwrap();
+
+        let obj = std::fs::read_to_string(format!("{}.obj", path_base)).unwrap();
+        let mtl = std::fs::read_to_string(format!("{}.mtl", path_base)).unwrap();
+        let lines: Vec<&str> = obj.lines().collect();
+
+        assert_eq!(lines[0], "mtllib mesh_export_bar_test.mtl");
+        assert_eq!(lines.iter().filter(|l| l.starts_with("vn ")).count(), 6);
+        assert!(lines.contains(&"usemtl color_ff0000"));
+        assert!(mtl.contains("newmtl color_ff0000\nKd 1.0000 0.0000 0.0000"));
+
+        let vertices: Vec<&str> = lines.iter().copied().filter(|l| l.starts_with("v ")).collect();
+        let faces: Vec<&str> = lines.iter().copied().filter(|l| l.starts_with("f ")).collect();
+        assert_eq!(vertices.len(), 24);
+        assert_eq!(faces.len(), 6);
+
+        // each face uses its quad's four vertices in order with the quad's normal
+        for (q, (quad, face)) in mesh.quads.iter().zip(&faces).enumerate() {
+            let n = normal_index(quad.normal) + 1;
+            let base = q * 4 + 1;
+            assert_eq!(*face, format!("f {}//{} {}//{} {}//{} {}//{}", base, n, base + 1, n, base + 2, n, base + 3, n));
+            for (corner, vertex) in quad.corners.iter().zip(&vertices[q * 4..q * 4 + 4]) {
+                assert_eq!(*vertex, format!("v {} {} {}", corner[0], corner[1], corner[2]));
+            }
+        }
+    }
+
+    #[test]
+    fn empty_mesh_is_not_written_as_glb() {
+        let path = std::env::temp_dir().join("mesh_export_empty_mesh_test.glb");
+        let path = path.to_str().unwrap();
+
+        let error = write_glb(&QuadMesh::default(), path).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(!std::path::Path::new(path).exists());
+    }
+}
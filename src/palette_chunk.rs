@@ -0,0 +1,210 @@
+use crate::map_3D::Map3D;
+use crate::world_generator::{GenerateContext, WorldGenerator};
+use nalgebra as na;
+
+const CHUNK_LENGTH: usize = 32;
+const CHUNK_VOLUME: usize = CHUNK_LENGTH * CHUNK_LENGTH * CHUNK_LENGTH;
+const WORD_BITS: usize = 64;
+
+// Compressed form of a 32^3 partition chunk of u16 indices.
+// Uniform chunks store only their value, everything else stores
+// a local palette and bit packed indices into that palette.
+// Indices never straddle two words.
+#[derive(Clone)]
+pub enum PaletteChunk {
+    Uniform(u16),
+    Packed {
+        palette: Vec<u16>,
+        bits_per_index: usize,
+        words: Vec<u64>,
+    },
+}
+
+#[allow(dead_code)]
+impl PaletteChunk {
+    pub fn from_map(map: &Map3D<u16>) 
+    -> Self {
+        assert!(map.dims() == [CHUNK_LENGTH ; 3], "palette chunks must be 32^3");
+
+        let values = map.full_slice();
+
+        let mut palette: Vec<u16> = Vec::new();
+        let mut local_indices: Vec<u16> = Vec::with_capacity(CHUNK_VOLUME);
+        // remembers the last lookup, neighbouring voxels are usually equal
+        let mut last = None;
+        for &value in values {
+            let local_index = match last {
+                Some((last_value, last_index)) if last_value == value => last_index,
+                _ => {
+                    let local_index = match palette.iter().position(|&p| p == value) {
+                        Some(i) => i as u16,
+                        None => {
+                            palette.push(value);
+                            (palette.len() - 1) as u16
+                        }
+                    };
+                    last = Some((value, local_index));
+                    local_index
+                }
+            };
+            local_indices.push(local_index);
+        }
+
+        if palette.len() == 1 {
+            return PaletteChunk::Uniform(palette[0]);
+        }
+
+        let bits_per_index = Self::bits_for_len(palette.len());
+        let per_word = WORD_BITS / bits_per_index;
+        let mut words = vec![0u64 ; (CHUNK_VOLUME + per_word - 1) / per_word];
+
+        for (i, &local_index) in local_indices.iter().enumerate() {
+            let shift = (i % per_word) * bits_per_index;
+            words[i / per_word] |= (local_index as u64) << shift;
+        }
+
+        PaletteChunk::Packed { palette, bits_per_index, words }
+    }
+
+    // expands into the dense layout that upload_index_map expects
+    pub fn to_map(&self) 
+    -> Map3D<u16> {
+        match self {
+            PaletteChunk::Uniform(value) => 
+                Map3D::new_with_default([CHUNK_LENGTH ; 3], *value),
+            PaletteChunk::Packed { palette, bits_per_index, words } => {
+                let per_word = WORD_BITS / bits_per_index;
+                let mask = (1u64 << bits_per_index) - 1;
+                let data = 
+                    (0..CHUNK_VOLUME)
+                    .map(|i| {
+                        let shift = (i % per_word) * bits_per_index;
+                        palette[((words[i / per_word] >> shift) & mask) as usize]
+                    })
+                    .collect();
+                Map3D::from_vec([CHUNK_LENGTH ; 3], data)
+            },
+        }
+    }
+
+    pub fn get(&self, coords: [usize ; 3]) 
+    -> Option<u16> {
+        if coords.iter().any(|&c| c >= CHUNK_LENGTH) {
+            return None;
+        }
+        match self {
+            PaletteChunk::Uniform(value) => Some(*value),
+            PaletteChunk::Packed { palette, bits_per_index, words } => {
+                let i = coords[0] + coords[1] * CHUNK_LENGTH + coords[2] * CHUNK_LENGTH * CHUNK_LENGTH;
+                let per_word = WORD_BITS / bits_per_index;
+                let mask = (1u64 << bits_per_index) - 1;
+                let shift = (i % per_word) * bits_per_index;
+                Some(palette[((words[i / per_word] >> shift) & mask) as usize])
+            },
+        }
+    }
+
+    pub fn is_uniform(&self) 
+    -> bool {
+        match self {
+            PaletteChunk::Uniform(_) => true,
+            _ => false,
+        }
+    }
+
+    // number of distinct values in the chunk
+    pub fn palette_len(&self) 
+    -> usize {
+        match self {
+            PaletteChunk::Uniform(_) => 1,
+            PaletteChunk::Packed { palette, .. } => palette.len(),
+        }
+    }
+
+    // approximate resident size in bytes, including the heap allocations
+    pub fn resident_size(&self) 
+    -> usize {
+        std::mem::size_of::<Self>() + 
+        match self {
+            PaletteChunk::Uniform(_) => 0,
+            PaletteChunk::Packed { palette, words, .. } => 
+                palette.capacity() * 2 + words.capacity() * 8,
+        }
+    }
+
+    fn bits_for_len(len: usize) 
+    -> usize {
+        let mut bits = 1;
+        while (1usize << bits) < len {
+            bits += 1;
+        }
+        bits
+    }
+}
+
+impl super::displaced_chunks::ChunkData for PaletteChunk {
+    fn allocate() -> Self {
+        PaletteChunk::Uniform(u16::MAX)
+    }
+
+    // generates through the dense map, then compresses it
+    fn initialize(&mut self, world_chunk_coords: na::Vector3<i32>, generator: &dyn WorldGenerator, generate_context: &GenerateContext) {
+        let mut map = <Map3D<u16> as super::displaced_chunks::ChunkData>::allocate();
+        map.initialize(world_chunk_coords, generator, generate_context);
+        *self = PaletteChunk::from_map(&map);
+    }
+
+    fn voxel_index(&self, coords: [usize ; 3]) -> u16 {
+        self.get(coords).unwrap_or(u16::MAX)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // chunk of `distinct` values in a scattered order, u16::MAX among them
+    fn scattered(distinct: u64) 
+    -> Map3D<u16> {
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut map = Map3D::new([CHUNK_LENGTH ; 3]);
+        for coords in map.coords_iter().collect::<Vec<_>>() {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            let value = state % distinct;
+            map.set(coords, if value == 0 { u16::MAX } else { value as u16 * 3 }).unwrap();
+        }
+        map
+    }
+
+    #[test]
+    fn packed_chunks_round_trip() {
+        // index widths that fill a word exactly and ones that leave bits over
+        for &distinct in &[2, 3, 5, 17, 100, 300] {
+            let map = scattered(distinct);
+            let chunk = PaletteChunk::from_map(&map);
+
+            assert!(!chunk.is_uniform());
+            assert_eq!(chunk.palette_len(), distinct as usize);
+            assert!(chunk.to_map().full_slice() == map.full_slice(), "{} distinct values", distinct);
+            for coords in map.coords_iter() {
+                assert_eq!(chunk.get(coords), map.get(coords));
+            }
+        }
+    }
+
+    #[test]
+    fn uniform_chunks_store_no_cells() {
+        for &value in &[0, 7, u16::MAX] {
+            let map = Map3D::new_with_default([CHUNK_LENGTH ; 3], value);
+            let chunk = PaletteChunk::from_map(&map);
+
+            assert!(chunk.is_uniform());
+            assert_eq!(chunk.resident_size(), std::mem::size_of::<PaletteChunk>());
+            assert!(chunk.to_map().full_slice() == map.full_slice());
+            assert_eq!(chunk.get([31, 0, 17]), Some(value));
+            assert_eq!(chunk.get([32, 0, 0]), None);
+        }
+    }
+}
@@ -0,0 +1,301 @@
+// Ray queries against the world on the CPU.
+// Runs the cpu_march traversal over the partition chunks and the loaded prefabs,
+// then resolves the hit cube back to partition, prefab and palette entry
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use nalgebra as na;
+
+use crate::bit_pyramid::BitPyramid;
+use crate::cpu_march::{self, MarchSource};
+use crate::displaced_chunks::{ChunkData, DisplacedChunks};
+use crate::map_3D::Map3D;
+use crate::render::resources::{chunk_id_to_variant, chunk_id_variant_to_id, ChunkIDVariant};
+use crate::standard_voxel_prefab::StandardVoxelPrefab;
+
+// cubes along one axis of a prefab, and of a partition
+const PREFAB_LENGTH: i64 = 32;
+const PARTITION_CUBE_LENGTH: i64 = 32 * 32;
+
+// everything editing needs about a hit, the export only reads world_voxel so far
+#[allow(dead_code)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PickHit {
+    // world coordinates of the hit cube
+    pub world_voxel: na::Vector3<i64>,
+    // outward normal of the face that was hit
+    pub normal: na::Vector3<i32>,
+    // world partition holding the cube
+    pub partition_coords: na::Vector3<i32>,
+    // prefab cell of the partition holding the cube
+    pub prefab_coords: na::Vector3<usize>,
+    pub prefab_id: u16,
+    pub palette_index: u16,
+    // distance from the origin in cubes
+    pub distance: f32,
+}
+
+// Keeps the bit pyramids picks are marched through between picks.
+// Partition pyramids are rebuilt once their chunk received new data,
+// prefab pyramids once the prefab is reloaded
+#[derive(Default)]
+pub struct Picker {
+    // by partition id, with the chunk revision they were built from
+    partition_pyramids: HashMap<usize, (u64, BitPyramid)>,
+    prefab_pyramids: HashMap<u16, BitPyramid>,
+}
+
+impl Picker {
+    pub fn new() 
+    -> Self {
+        Self::default()
+    }
+
+    // drops the pyramids of prefabs whose content changed
+    pub fn reload_prefabs(&mut self, prefab_ids: &[u16]) {
+        for prefab_id in prefab_ids {
+            self.prefab_pyramids.remove(prefab_id);
+        }
+    }
+
+    // Casts a ray from origin, given in prefab units relative to the view partition
+    // (the same space as the camera position handed to the renderer).
+    // max_distance is measured in cubes
+    pub fn pick<T: ChunkData>(&mut self, chunks: &DisplacedChunks<T>, prefabs: &[StandardVoxelPrefab], 
+        origin: na::Vector3<f32>, dir: na::Vector3<f32>, max_distance: f32) 
+    -> Option<PickHit> {
+        let dir = dir.normalize();
+        let source = WorldMarchSource { chunks, prefabs, picker: RefCell::new(self) };
+
+        let (pos_in_partition, displacement_coords) = cpu_march::split_position(origin);
+        let result = cpu_march::dda_march(&source, dir, pos_in_partition, displacement_coords);
+        if !result.hit {
+            return None;
+        }
+
+        let distance = cpu_march::cube_pos_intersect(pos_in_partition, displacement_coords, 
+            dir, result.normal, result.cube_coords);
+        if distance > max_distance {
+            return None;
+        }
+
+        let world_voxel = 
+            result.cube_coords.cast::<i64>() 
+            + chunks.view_partition_coords().cast::<i64>() * PARTITION_CUBE_LENGTH;
+
+        let (prefab_id, palette_index) = voxel_at(chunks, prefabs, world_voxel)?;
+
+        let partition_coords = world_voxel.map(|v| v.div_euclid(PARTITION_CUBE_LENGTH) as i32);
+        let prefab_coords = world_voxel.map(|v| (v.rem_euclid(PARTITION_CUBE_LENGTH) / PREFAB_LENGTH) as usize);
+
+        Some(PickHit {
+            world_voxel,
+            normal: result.normal.map(|n| n as i32),
+            partition_coords,
+            prefab_coords,
+            prefab_id,
+            palette_index,
+            distance,
+        })
+    }
+}
+
+// prefab id and palette index of a world cube, None when empty or not loaded
+pub fn voxel_at<T: ChunkData>(chunks: &DisplacedChunks<T>, prefabs: &[StandardVoxelPrefab], world_voxel: na::Vector3<i64>) 
+-> Option<(u16, u16)> {
+    let partition_coords = world_voxel.map(|v| v.div_euclid(PARTITION_CUBE_LENGTH) as i32);
+    let in_partition = world_voxel.map(|v| v.rem_euclid(PARTITION_CUBE_LENGTH));
+
+    let chunk = chunks.chunk_data(chunks.partition_id(partition_coords)?)?;
+
+    let prefab_id = chunk.voxel_index([
+        (in_partition.x / PREFAB_LENGTH) as usize,
+        (in_partition.y / PREFAB_LENGTH) as usize,
+        (in_partition.z / PREFAB_LENGTH) as usize,
+    ]);
+    let prefab = prefabs.get(prefab_id as usize)?;
+
+    let palette_index = prefab.palette_volume.get([
+        (in_partition.x % PREFAB_LENGTH) as usize,
+        (in_partition.y % PREFAB_LENGTH) as usize,
+        (in_partition.z % PREFAB_LENGTH) as usize,
+    ])?;
+    if palette_index == u16::MAX {
+        return None;
+    }
+
+    Some((prefab_id, palette_index))
+}
+
+
+// Reads chunks and prefabs in place of the GPU textures.
+// Bit pyramids are built the first time a query touches a chunk and kept in the picker
+struct WorldMarchSource<'a, 'p, T: ChunkData> {
+    chunks: &'a DisplacedChunks<T>,
+    prefabs: &'a [StandardVoxelPrefab],
+    picker: RefCell<&'p mut Picker>,
+}
+
+impl<'a, 'p, T: ChunkData> WorldMarchSource<'a, 'p, T> {
+    fn check_partition(&self, partition_id: usize, coords: [i32 ; 3], lod: usize) 
+    -> bool {
+        let chunk = match self.chunks.chunk_data(partition_id) {
+            Some(chunk) => chunk,
+            None => return false,
+        };
+        let revision = self.chunks.chunk_revision(partition_id);
+
+        let mut picker = self.picker.borrow_mut();
+        let (built_revision, bit_pyramid) = 
+            picker.partition_pyramids
+            .entry(partition_id)
+            .or_insert_with(|| (revision, partition_bit_pyramid(chunk)));
+        if *built_revision != revision {
+            *built_revision = revision;
+            *bit_pyramid = partition_bit_pyramid(chunk);
+        }
+        bit_pyramid.check_voxel(coords, lod)
+    }
+
+    fn check_prefab(&self, prefab_id: u16, coords: [i32 ; 3], lod: usize) 
+    -> bool {
+        let prefab = match self.prefabs.get(prefab_id as usize) {
+            Some(prefab) => prefab,
+            None => return false,
+        };
+
+        self.picker.borrow_mut().prefab_pyramids
+        .entry(prefab_id)
+        .or_insert_with(|| BitPyramid::from_bit_voxels(prefab.bit_voxels()))
+        .check_voxel(coords, lod)
+    }
+}
+
+fn partition_bit_pyramid<T: ChunkData>(chunk: &T) 
+-> BitPyramid {
+    let mut map = Map3D::new([32 ; 3]);
+    map.set_all(&|coords| chunk.voxel_index(coords));
+    BitPyramid::from_map(&map)
+}
+
+impl<'a, 'p, T: ChunkData> MarchSource for WorldMarchSource<'a, 'p, T> {
+    fn layer_chunk_id(&self, displacement: [i32 ; 3]) 
+    -> u16 {
+        // the same ids the layer index map handed to the renderer holds
+        let partition_coords = self.chunks.view_partition_coords() + na::Vector3::from(displacement);
+        self.chunks
+        .partition_id(partition_coords)
+        .filter(|&id| self.chunks.chunk_data(id).is_some())
+        .map_or(u16::MAX, |id| chunk_id_variant_to_id(ChunkIDVariant::PartitionID(id as u32)) as u16)
+    }
+
+    fn check_voxel(&self, chunk_id: u32, coords: [i32 ; 3], lod: usize) 
+    -> bool {
+        match chunk_id_to_variant(chunk_id) {
+            ChunkIDVariant::PrefabID(id) => self.check_prefab(id as u16, coords, lod),
+            ChunkIDVariant::PartitionID(id) => self.check_partition(id as usize, coords, lod),
+            ChunkIDVariant::LayerID(_) => false,
+        }
+    }
+
+    fn chunk_index(&self, chunk_id: u32, coords: [i32 ; 3]) 
+    -> u16 {
+        if coords.iter().any(|&c| !(0..32).contains(&c)) {
+            return u16::MAX;
+        }
+        let coords = [coords[0] as usize, coords[1] as usize, coords[2] as usize];
+        match chunk_id_to_variant(chunk_id) {
+            ChunkIDVariant::PrefabID(id) => 
+                self.prefabs
+                .get(id as usize)
+                .and_then(|prefab| prefab.palette_volume.get(coords))
+                .unwrap_or(u16::MAX),
+            ChunkIDVariant::PartitionID(id) => 
+                self.chunks
+                .chunk_data(id as usize)
+                .map_or(u16::MAX, |chunk| chunk.voxel_index(coords)),
+            ChunkIDVariant::LayerID(_) => u16::MAX,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+    use crate::prefab_registry::PrefabRegistry;
+    use crate::world_generator::{GenerateContext, WorldGenerator};
+
+    // A partition of one prefab id throughout, solid partitions are the placeholder prefab.
+    // Left of x = 20 only the partition below the origin is solid, right of it every partition is
+    struct Uniform(u16);
+
+    impl ChunkData for Uniform {
+        fn initialize(&mut self, coords: na::Vector3<i32>, _generator: &dyn WorldGenerator, _context: &GenerateContext) {
+            let solid = coords.x >= 20 || coords == na::Vector3::new(0, -1, 0);
+            self.0 = if solid { 0 } else { u16::MAX };
+        }
+
+        fn allocate() 
+        -> Self {
+            Uniform(u16::MAX)
+        }
+
+        fn voxel_index(&self, _coords: [usize ; 3]) 
+        -> u16 {
+            self.0
+        }
+    }
+
+    struct Unused;
+
+    impl WorldGenerator for Unused {
+        fn generate(&self, _partition_coords: na::Vector3<i32>, _context: &GenerateContext, _partition: &mut Map3D<u16>) {}
+    }
+
+    fn receive_all(chunks: &mut DisplacedChunks<Uniform>) {
+        let start = Instant::now();
+        let mut received = 0;
+        while received < chunks.len() {
+            received += chunks.receive_generated(usize::MAX);
+            assert!(start.elapsed() < Duration::from_secs(30), "chunks never finished generating");
+        }
+    }
+
+    // middle of a partition displaced from the view, in the prefab units picks start from
+    fn partition_middle(displacement: na::Vector3<i32>) 
+    -> na::Vector3<f32> {
+        displacement.map(|d| d as f32 * 32. + 16.5)
+    }
+
+    #[test]
+    fn picks_rebuild_pyramids_of_regenerated_chunks() {
+        let prefabs = [StandardVoxelPrefab::placeholder()];
+        let context = GenerateContext::new(&PrefabRegistry::placeholder_only(), 0);
+        let mut chunks = DisplacedChunks::<Uniform>::new(na::Vector3::zeros(), Box::new(Unused), context, 2);
+        receive_all(&mut chunks);
+
+        // the march divides by each direction component, so none is exactly zero
+        let down = na::Vector3::new(0.0001, -1., 0.0001);
+        let mut picker = Picker::new();
+
+        let hit = picker.pick(&chunks, &prefabs, partition_middle(na::Vector3::zeros()), down, f32::MAX).unwrap();
+        assert_eq!(hit.world_voxel, na::Vector3::new(528, -1, 528));
+        assert_eq!(hit.normal, na::Vector3::y());
+        assert_eq!(hit.partition_coords, na::Vector3::new(0, -1, 0));
+        assert_eq!(hit.prefab_id, 0);
+        assert_eq!(picker.pick(&chunks, &prefabs, partition_middle(na::Vector3::zeros()), down, f32::MAX), Some(hit));
+
+        // the view partition was marched through empty, once moved its id holds a solid partition
+        let reused_id = chunks.partition_id(na::Vector3::zeros()).unwrap();
+        chunks.set_view_partition_coords(na::Vector3::new(40, 0, 0));
+        receive_all(&mut chunks);
+
+        let reused_coords = chunks.partition_coords(reused_id).unwrap();
+        let origin = partition_middle(reused_coords - chunks.view_partition_coords());
+        let hit = picker.pick(&chunks, &prefabs, origin, down, f32::MAX).unwrap();
+        assert_eq!(hit.partition_coords, reused_coords);
+    }
+}
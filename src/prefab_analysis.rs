@@ -0,0 +1,330 @@
+use std::collections::VecDeque;
+
+use crate::map_3D::Map3D;
+
+use super::bit_voxels::BitVoxels;
+use super::prefab_orientation::Direction;
+
+pub const DIRECTIONS : [Direction ; 6] = [
+    Direction::PosX,
+    Direction::NegX,
+    Direction::PosY,
+    Direction::NegY,
+    Direction::PosZ,
+    Direction::NegZ,
+];
+
+// The outermost layer of voxels on one side.
+// Cells are laid out by the two remaining axes in x, y, z order,
+// so (y, z) for the x sides, (x, z) for the y sides and (x, y) for the z sides
+pub struct FaceMask
+{
+    dims : [usize ; 2],
+    cells : Vec<bool>
+}
+
+impl FaceMask
+{
+    #[allow(dead_code)]
+    pub fn dims(&self)
+        -> [usize ; 2]
+    {
+        self.dims
+    }
+
+    #[allow(dead_code)]
+    pub fn get(&self, coords : [usize ; 2])
+        -> bool
+    {
+        self.cells[coords[0] + coords[1] * self.dims[0]]
+    }
+
+    #[allow(dead_code)]
+    pub fn count(&self)
+        -> usize
+    {
+        self.cells.iter().filter(|&&solid| solid).count()
+    }
+
+    // the side is closed, neighbours behind it can be culled
+    pub fn is_full(&self)
+        -> bool
+    {
+        self.cells.iter().all(|&solid| solid)
+    }
+
+    #[allow(dead_code)]
+    pub fn is_empty(&self)
+        -> bool
+    {
+        self.cells.iter().all(|&solid| !solid)
+    }
+}
+
+// Shape facts about a voxel volume that tools check before placing it.
+// Built with every prefab, nothing in the viewer reads them yet
+pub struct PrefabAnalysis
+{
+    // number of voxels present
+    #[allow(dead_code)]
+    pub occupancy : usize,
+    // tight bounds as (inclusive min, exclusive max), None when empty
+    #[allow(dead_code)]
+    pub bounds : Option<([usize ; 3], [usize ; 3])>,
+    // indexed like DIRECTIONS
+    face_masks : Vec<FaceMask>,
+    // 6-connected pieces, 0 is empty and pieces count up from 1
+    #[allow(dead_code)]
+    pub component_labels : Map3D<u32>,
+    // voxels in each piece, piece n at n - 1
+    pub component_sizes : Vec<usize>,
+    // present voxels with at least one empty or out of bounds neighbour
+    #[allow(dead_code)]
+    pub surface_voxels : Vec<[usize ; 3]>
+}
+
+impl PrefabAnalysis
+{
+    pub fn new(bit_voxels : &BitVoxels)
+        -> PrefabAnalysis
+    {
+        let dims = bit_voxels.dims();
+
+        let face_masks = DIRECTIONS.iter().map(|&direction| face_mask(bit_voxels, direction)).collect();
+        let (component_labels, component_sizes) = label_components(bit_voxels);
+
+        let surface_voxels =
+            component_labels.coords_iter()
+            .filter(|&coords| bit_voxels.get_voxel(coords))
+            .filter(|&coords| {
+                let neighbours = neighbours(coords, dims);
+                neighbours.len() < 6 || neighbours.iter().any(|&n| !bit_voxels.get_voxel(n))
+            })
+            .collect();
+
+        PrefabAnalysis
+        {
+            occupancy : bit_voxels.count(),
+            bounds : bit_voxels.bounds(),
+            face_masks,
+            component_labels,
+            component_sizes,
+            surface_voxels,
+        }
+    }
+
+    #[allow(dead_code)]
+    pub fn face_mask(&self, direction : Direction)
+        -> &FaceMask
+    {
+        let side = DIRECTIONS.iter().position(|&d| d == direction).unwrap();
+        &self.face_masks[side]
+    }
+
+    // full sides in DIRECTIONS order
+    #[allow(dead_code)]
+    pub fn solid_faces(&self)
+        -> [bool ; 6]
+    {
+        let mut solid = [false ; 6];
+        for (side, mask) in self.face_masks.iter().enumerate()
+        {
+            solid[side] = mask.is_full();
+        }
+        solid
+    }
+
+    #[allow(dead_code)]
+    pub fn component_count(&self)
+        -> usize
+    {
+        self.component_sizes.len()
+    }
+
+    #[allow(dead_code)]
+    pub fn is_connected(&self)
+        -> bool
+    {
+        self.component_count() <= 1
+    }
+}
+
+fn face_mask(bit_voxels : &BitVoxels, direction : Direction)
+    -> FaceMask
+{
+    let dims = bit_voxels.dims();
+
+    // axis the side faces along, the layer on it and the two axes across it
+    let (axis, layer) = match direction
+    {
+        Direction::PosX => (0, dims[0].saturating_sub(1)),
+        Direction::NegX => (0, 0),
+        Direction::PosY => (1, dims[1].saturating_sub(1)),
+        Direction::NegY => (1, 0),
+        Direction::PosZ => (2, dims[2].saturating_sub(1)),
+        Direction::NegZ => (2, 0),
+    };
+    let (u, v) = match axis
+    {
+        0 => (1, 2),
+        1 => (0, 2),
+        _ => (0, 1),
+    };
+
+    let mask_dims = [dims[u], dims[v]];
+    let mut cells = vec![false ; mask_dims[0] * mask_dims[1]];
+    if dims[axis] > 0
+    {
+        for j in 0..mask_dims[1] {
+        for i in 0..mask_dims[0] {
+            let mut coords = [0 ; 3];
+            coords[axis] = layer;
+            coords[u] = i;
+            coords[v] = j;
+            cells[i + j * mask_dims[0]] = bit_voxels.get_voxel(coords);
+        }}
+    }
+
+    FaceMask { dims : mask_dims, cells }
+}
+
+// flood fills every piece breadth first
+fn label_components(bit_voxels : &BitVoxels)
+    -> (Map3D<u32>, Vec<usize>)
+{
+    let dims = bit_voxels.dims();
+    let mut labels = Map3D::new(dims);
+    let mut sizes = Vec::new();
+    let mut queue = VecDeque::new();
+
+    for start in labels.coords_iter().collect::<Vec<_>>()
+    {
+        if !bit_voxels.get_voxel(start) || labels.get(start) != Some(0)
+        {
+            continue;
+        }
+
+        let label = sizes.len() as u32 + 1;
+        let mut size = 0;
+        labels.set(start, label).unwrap();
+        queue.push_back(start);
+
+        while let Some(coords) = queue.pop_front()
+        {
+            size += 1;
+            for neighbour in neighbours(coords, dims)
+            {
+                if bit_voxels.get_voxel(neighbour) && labels.get(neighbour) == Some(0)
+                {
+                    labels.set(neighbour, label).unwrap();
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+
+        sizes.push(size);
+    }
+
+    (labels, sizes)
+}
+
+// the in bounds 6-neighbours of a voxel
+fn neighbours(coords : [usize ; 3], dims : [usize ; 3])
+    -> Vec<[usize ; 3]>
+{
+    let mut result = Vec::with_capacity(6);
+    for axis in 0..3
+    {
+        if coords[axis] > 0
+        {
+            let mut n = coords;
+            n[axis] -= 1;
+            result.push(n);
+        }
+        if coords[axis] + 1 < dims[axis]
+        {
+            let mut n = coords;
+            n[axis] += 1;
+            result.push(n);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::{PrefabAnalysis, DIRECTIONS};
+    use crate::bit_voxels::BitVoxels;
+    use crate::prefab_orientation::Direction;
+
+    fn filled(dims : [usize ; 3], solid : impl Fn([usize ; 3]) -> bool)
+        -> BitVoxels
+    {
+        let mut bit_voxels = BitVoxels::empty(dims);
+        for x in 0..dims[0] {
+        for y in 0..dims[1] {
+        for z in 0..dims[2] {
+            bit_voxels.set_voxel([x, y, z], solid([x, y, z]));
+        }}}
+        bit_voxels
+    }
+
+    #[test]
+    fn solid_block_is_one_closed_piece()
+    {
+        let analysis = PrefabAnalysis::new(&filled([4, 3, 5], |_| true));
+
+        assert_eq!(analysis.occupancy, 60);
+        assert_eq!(analysis.bounds, Some(([0, 0, 0], [4, 3, 5])));
+        assert_eq!(analysis.solid_faces(), [true ; 6]);
+        for &direction in DIRECTIONS.iter()
+        {
+            assert!(analysis.face_mask(direction).is_full(), "{:?}", direction);
+        }
+        // masks span the two other axes in x, y, z order
+        assert_eq!(analysis.face_mask(Direction::PosX).dims(), [3, 5]);
+        assert_eq!(analysis.face_mask(Direction::NegY).dims(), [4, 5]);
+        assert_eq!(analysis.face_mask(Direction::PosZ).count(), 12);
+        assert_eq!(analysis.component_sizes, vec![60]);
+        assert!(analysis.is_connected());
+        // all but the 2x1x3 core
+        assert_eq!(analysis.surface_voxels.len(), 54);
+    }
+
+    #[test]
+    fn disjoint_blobs_are_separate_pieces()
+    {
+        let first = |c : [usize ; 3]| c.iter().all(|&v| v < 2);
+        let second = |c : [usize ; 3]| c[0] >= 4 && c[1] == 4 && c[2] == 4;
+        let analysis = PrefabAnalysis::new(&filled([6, 6, 6], |c| first(c) || second(c)));
+
+        assert_eq!(analysis.component_count(), 2);
+        assert_eq!(analysis.component_sizes, vec![8, 2]);
+        assert_eq!(analysis.component_labels.get([1, 1, 1]), Some(1));
+        assert_eq!(analysis.component_labels.get([5, 4, 4]), Some(2));
+        assert_eq!(analysis.component_labels.get([3, 3, 3]), Some(0));
+        assert!(!analysis.is_connected());
+        assert!(!analysis.face_mask(Direction::PosX).is_empty());
+        assert!(analysis.face_mask(Direction::PosY).is_empty());
+
+        // touching on a corner only is still apart
+        let analysis = PrefabAnalysis::new(&filled([6, 6, 6], |c| first(c) || c == [2, 2, 2]));
+        assert_eq!(analysis.component_sizes, vec![8, 1]);
+    }
+
+    #[test]
+    fn hollow_shell_has_an_inner_surface()
+    {
+        // walls two voxels thick around a 3^3 cavity
+        let analysis = PrefabAnalysis::new(&filled([7, 7, 7], |c| !c.iter().all(|&v| (2..5).contains(&v))));
+
+        assert_eq!(analysis.occupancy, 343 - 27);
+        assert_eq!(analysis.component_count(), 1);
+        assert_eq!(analysis.solid_faces(), [true ; 6]);
+        // the outer layer and the 9 voxels facing each side of the cavity
+        assert_eq!(analysis.surface_voxels.len(), (343 - 125) + 6 * 9);
+        assert!(analysis.surface_voxels.contains(&[1, 3, 3]));
+        assert!(!analysis.surface_voxels.contains(&[1, 1, 3]));
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+
+// Everything that can go wrong turning a .vox file into prefabs.
+// Each variant carries the path of the file that failed
+#[derive(Debug)]
+pub enum PrefabLoadError
+{
+    MissingFile { path : String, source : std::io::Error },
+    Parse { path : String, message : String },
+    PaletteSizeMismatch { path : String, len : usize },
+    ModelIndexOutOfRange { path : String, model_index : usize, model_count : usize },
+}
+
+impl PrefabLoadError
+{
+    pub fn path(&self)
+        -> &str
+    {
+        match self
+        {
+            PrefabLoadError::MissingFile { path, .. } => path,
+            PrefabLoadError::Parse { path, .. } => path,
+            PrefabLoadError::PaletteSizeMismatch { path, .. } => path,
+            PrefabLoadError::ModelIndexOutOfRange { path, .. } => path,
+        }
+    }
+}
+
+impl fmt::Display for PrefabLoadError
+{
+    fn fmt(&self, f : &mut fmt::Formatter<'_>)
+        -> fmt::Result
+    {
+        match self
+        {
+            PrefabLoadError::MissingFile { path, source } => 
+                write!(f, "{}: could not read file ({})", path, source),
+            PrefabLoadError::Parse { path, message } => 
+                write!(f, "{}: could not parse vox data ({})", path, message),
+            PrefabLoadError::PaletteSizeMismatch { path, len } => 
+                write!(f, "{}: palette has {} entries instead of 256", path, len),
+            PrefabLoadError::ModelIndexOutOfRange { path, model_index, model_count } => 
+                write!(f, "{}: model {} requested but the file has {} models", path, model_index, model_count),
+        }
+    }
+}
+
+impl std::error::Error for PrefabLoadError
+{
+    fn source(&self)
+        -> Option<&(dyn std::error::Error + 'static)>
+    {
+        match self
+        {
+            PrefabLoadError::MissingFile { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::error::Error;
+
+    use super::PrefabLoadError;
+    use crate::dot_vox_wrapper::DotVoxWrapper;
+    use crate::map_3D::Map3D;
+    use crate::prefab_registry::PrefabRegistry;
+    use crate::standard_voxel_prefab::{ModelAnchor, PrefabTiles, VoxStructure};
+    use crate::voxel_material::VoxelMaterial;
+    use crate::vox_writer::VoxWriter;
+
+    fn temp_path(name : &str)
+        -> String
+    {
+        std::env::temp_dir().join(name).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn missing_files_name_the_file()
+    {
+        let path = temp_path("prefab_load_error_missing_test.vox");
+        let _ = std::fs::remove_file(&path);
+
+        let errors = vec![
+            DotVoxWrapper::new(&path).err().unwrap(),
+            PrefabTiles::new(&path, 0, ModelAnchor::default()).err().unwrap(),
+            VoxStructure::new(&path).err().unwrap(),
+            PrefabRegistry::load(&path).err().unwrap(),
+        ];
+        for error in &errors
+        {
+            assert!(matches!(error, PrefabLoadError::MissingFile { .. }), "{:?}", error);
+            assert_eq!(error.path(), path);
+            assert!(error.source().is_some());
+            assert!(error.to_string().starts_with(&path), "{}", error);
+        }
+    }
+
+    #[test]
+    fn unreadable_files_are_parse_errors()
+    {
+        let path = temp_path("prefab_load_error_garbage_test.vox");
+        std::fs::write(&path, b"not a vox file").unwrap();
+
+        let error = DotVoxWrapper::new(&path).err().unwrap();
+        assert!(matches!(error, PrefabLoadError::Parse { .. }), "{:?}", error);
+        assert_eq!(error.path(), path);
+    }
+
+    #[test]
+    fn model_index_out_of_range_names_the_file()
+    {
+        let path = temp_path("prefab_load_error_model_test.vox");
+        let mut writer = VoxWriter::new();
+        writer.add_volume(&Map3D::new_with_default([2, 2, 2], 0xff808080u32), [0 ; 3], |color| Some((color, VoxelMaterial::default()))).unwrap();
+        writer.write(&path).unwrap();
+
+        match PrefabTiles::new(&path, 3, ModelAnchor::default())
+        {
+            Err(PrefabLoadError::ModelIndexOutOfRange { path : error_path, model_index, model_count }) =>
+            {
+                assert_eq!(error_path, path);
+                assert_eq!((model_index, model_count), (3, 1));
+            },
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("model 3 of a single model file loaded"),
+        }
+
+        // the registry keeps going with the placeholder and hands the error back
+        let manifest_path = temp_path("prefab_load_error_model_test.toml");
+        std::fs::write(&manifest_path, format!("[[prefab]]\nname = \"fourth\"\npath = {:?}\nmodel = 3\n", path)).unwrap();
+        let (registry, errors) = PrefabRegistry::load(&manifest_path).unwrap();
+        assert_eq!(registry.id("fourth"), Some(0));
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], PrefabLoadError::ModelIndexOutOfRange { model_index : 3, .. }), "{:?}", errors[0]);
+        assert_eq!(errors[0].path(), path);
+    }
+}
@@ -0,0 +1,276 @@
+use std::convert::TryFrom;
+
+use crate::map_3D::Map3D;
+
+// Axis aligned directions, in the wrapper's orientation (y up)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction
+{
+    PosX,
+    NegX,
+    PosY,
+    NegY,
+    PosZ,
+    NegZ,
+}
+
+impl TryFrom<&str> for Direction
+{
+    type Error = String;
+
+    // "+x", "-y", ...
+    fn try_from(name : &str)
+        -> Result<Direction, String>
+    {
+        match name
+        {
+            "+x" => Ok(Direction::PosX),
+            "-x" => Ok(Direction::NegX),
+            "+y" => Ok(Direction::PosY),
+            "-y" => Ok(Direction::NegY),
+            "+z" => Ok(Direction::PosZ),
+            "-z" => Ok(Direction::NegZ),
+            _ => Err(format!("\"{}\" is not a direction, expected one of +x -x +y -y +z -z", name)),
+        }
+    }
+}
+
+// A rotation, possibly mirrored, of a prefab about its center.
+// The matrix is a signed permutation, rows applied to column vectors like VoxTransform
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Orientation
+{
+    matrix : [[i8 ; 3] ; 3]
+}
+
+impl Orientation
+{
+    pub const IDENTITY : Orientation = Orientation { matrix : [[1, 0, 0], [0, 1, 0], [0, 0, 1]] };
+
+    // mirrors the x axis
+    pub const MIRROR_X : Orientation = Orientation { matrix : [[-1, 0, 0], [0, 1, 0], [0, 0, 1]] };
+
+    // a quarter turn about +y, +z to +x
+    pub const QUARTER_TURN_Y : Orientation = Orientation { matrix : [[0, 0, 1], [0, 1, 0], [-1, 0, 0]] };
+
+    // a quarter turn about +x, +y to +z
+    pub const QUARTER_TURN_X : Orientation = Orientation { matrix : [[1, 0, 0], [0, 0, -1], [0, 1, 0]] };
+
+    // the 4 turns about y, identity first
+    pub fn horizontal_rotations()
+        -> Vec<Orientation>
+    {
+        (0..4).map(|turns| Orientation::QUARTER_TURN_Y.power(turns)).collect()
+    }
+
+    // all 24 rotations of a cube, identity first
+    pub fn rotations()
+        -> Vec<Orientation>
+    {
+        // each of the 6 directions +y can be turned to, then the 4 turns about that direction
+        let up_turns = [
+            Orientation::IDENTITY,
+            Orientation::QUARTER_TURN_X,
+            Orientation::QUARTER_TURN_X.power(2),
+            Orientation::QUARTER_TURN_X.power(3),
+            Orientation::QUARTER_TURN_Y.then(&Orientation::QUARTER_TURN_X),
+            Orientation::QUARTER_TURN_Y.power(3).then(&Orientation::QUARTER_TURN_X),
+        ];
+
+        up_turns
+            .iter()
+            .flat_map(|up_turn|
+                (0..4).map(move |turns| up_turn.then(&Orientation::QUARTER_TURN_Y.power(turns))))
+            .collect()
+    }
+
+    // Prefabs are authored facing +z, this turns +z to a direction
+    // keeping +y up where possible
+    pub fn facing(direction : Direction)
+        -> Orientation
+    {
+        match direction
+        {
+            Direction::PosZ => Orientation::IDENTITY,
+            Direction::PosX => Orientation::QUARTER_TURN_Y,
+            Direction::NegZ => Orientation::QUARTER_TURN_Y.power(2),
+            Direction::NegX => Orientation::QUARTER_TURN_Y.power(3),
+            Direction::NegY => Orientation::QUARTER_TURN_X,
+            Direction::PosY => Orientation::QUARTER_TURN_X.power(3),
+        }
+    }
+
+    // self applied after other
+    pub fn then(&self, other : &Orientation)
+        -> Orientation
+    {
+        let mut matrix = [[0i8 ; 3] ; 3];
+        for row in 0..3 {
+        for col in 0..3 {
+            matrix[row][col] = (0..3).map(|k| self.matrix[row][k] * other.matrix[k][col]).sum();
+        }}
+        Orientation {matrix}
+    }
+
+    pub fn power(&self, times : usize)
+        -> Orientation
+    {
+        (0..times).fold(Orientation::IDENTITY, |acc, _| self.then(&acc))
+    }
+
+    pub fn mirrored(&self)
+        -> Orientation
+    {
+        self.then(&Orientation::MIRROR_X)
+    }
+
+    pub fn apply(&self, v : [i32 ; 3])
+        -> [i32 ; 3]
+    {
+        let m = &self.matrix;
+        let row = |r : usize| m[r][0] as i32 * v[0] + m[r][1] as i32 * v[1] + m[r][2] as i32 * v[2];
+        [row(0), row(1), row(2)]
+    }
+
+    pub fn apply_to_dims(&self, dims : [usize ; 3])
+        -> [usize ; 3]
+    {
+        let turned = self.apply([dims[0] as i32, dims[1] as i32, dims[2] as i32]);
+        [turned[0].abs() as usize, turned[1].abs() as usize, turned[2].abs() as usize]
+    }
+
+    // the volume turned about its center
+    pub fn apply_to_volume<T : Clone + Default + Copy>(&self, volume : &Map3D<T>)
+        -> Map3D<T>
+    {
+        let dims = volume.dims();
+        let turned_dims = self.apply_to_dims(dims);
+        let mut turned = Map3D::new(turned_dims);
+
+        // doubled coordinates relative to the center stay integral
+        for (coords, value) in volume.iter()
+        {
+            let centered = [
+                2 * coords[0] as i32 - (dims[0] as i32 - 1),
+                2 * coords[1] as i32 - (dims[1] as i32 - 1),
+                2 * coords[2] as i32 - (dims[2] as i32 - 1),
+            ];
+            let rotated = self.apply(centered);
+            let turned_coords = [
+                ((rotated[0] + turned_dims[0] as i32 - 1) / 2) as usize,
+                ((rotated[1] + turned_dims[1] as i32 - 1) / 2) as usize,
+                ((rotated[2] + turned_dims[2] as i32 - 1) / 2) as usize,
+            ];
+            turned.set(turned_coords, value).unwrap();
+        }
+
+        turned
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::collections::HashSet;
+
+    use super::{Direction, Orientation};
+    use crate::map_3D::Map3D;
+
+    #[test]
+    fn rotations_are_24_distinct_and_unmirrored()
+    {
+        let rotations = Orientation::rotations();
+        assert_eq!(rotations.len(), 24);
+        assert_eq!(rotations[0], Orientation::IDENTITY);
+
+        let distinct : HashSet<_> = rotations.iter().copied().collect();
+        assert_eq!(distinct.len(), 24);
+        assert!(rotations.iter().all(|r| !distinct.contains(&r.mirrored())));
+        assert!(Orientation::horizontal_rotations().iter().all(|r| distinct.contains(r)));
+    }
+
+    #[test]
+    fn every_orientation_has_an_inverse_among_them()
+    {
+        let rotations = Orientation::rotations();
+        let all : Vec<_> = rotations.iter().copied().chain(rotations.iter().map(|r| r.mirrored())).collect();
+
+        for orientation in &all
+        {
+            let inverses : Vec<_> = all.iter().filter(|other| orientation.then(other) == Orientation::IDENTITY).collect();
+            assert_eq!(inverses.len(), 1, "{:?}", orientation);
+            assert_eq!(inverses[0].then(orientation), Orientation::IDENTITY);
+        }
+        assert_eq!(Orientation::QUARTER_TURN_Y.power(4), Orientation::IDENTITY);
+        assert_eq!(Orientation::MIRROR_X.power(2), Orientation::IDENTITY);
+    }
+
+    #[test]
+    fn facing_turns_plus_z_to_the_direction()
+    {
+        let cases = [
+            (Direction::PosX, [1, 0, 0]),
+            (Direction::NegX, [-1, 0, 0]),
+            (Direction::PosY, [0, 1, 0]),
+            (Direction::NegY, [0, -1, 0]),
+            (Direction::PosZ, [0, 0, 1]),
+            (Direction::NegZ, [0, 0, -1]),
+        ];
+        for &(direction, vector) in cases.iter()
+        {
+            assert_eq!(Orientation::facing(direction).apply([0, 0, 1]), vector, "{:?}", direction);
+        }
+    }
+
+    // one voxel of a non cubic volume, so a swapped axis or a missed flip moves it elsewhere
+    fn one_voxel()
+        -> Map3D<u8>
+    {
+        let mut volume = Map3D::new([2, 3, 4]);
+        volume.set([1, 0, 3], 7).unwrap();
+        volume
+    }
+
+    fn voxel_coords(volume : &Map3D<u8>)
+        -> Vec<[usize ; 3]>
+    {
+        volume.iter().filter(|&(_, value)| value == 7).map(|(coords, _)| coords).collect()
+    }
+
+    #[test]
+    fn volumes_turn_about_their_center()
+    {
+        let cases = [
+            // +z to +x, the far z end goes to the far x end
+            (Orientation::QUARTER_TURN_Y, [4, 3, 2], [3, 0, 0]),
+            // +y to +z, the bottom goes to the back
+            (Orientation::QUARTER_TURN_X, [2, 4, 3], [1, 0, 0]),
+            (Orientation::MIRROR_X, [2, 3, 4], [0, 0, 3]),
+            (Orientation::QUARTER_TURN_Y.power(2), [2, 3, 4], [0, 0, 0]),
+        ];
+        for (orientation, dims, coords) in cases.iter()
+        {
+            let turned = orientation.apply_to_volume(&one_voxel());
+            assert_eq!(turned.dims(), *dims, "{:?}", orientation);
+            assert_eq!(voxel_coords(&turned), vec![*coords], "{:?}", orientation);
+        }
+    }
+
+    #[test]
+    fn turning_back_restores_the_volume()
+    {
+        let mut volume = Map3D::new([2, 3, 4]);
+        volume.set_all(&|coords| (coords[0] + 2 * coords[1] + 6 * coords[2]) as u8);
+
+        let rotations = Orientation::rotations();
+        for orientation in rotations.iter().copied().chain(rotations.iter().map(|r| r.mirrored()))
+        {
+            let inverse = rotations.iter().copied().chain(rotations.iter().map(|r| r.mirrored()))
+                .find(|other| other.then(&orientation) == Orientation::IDENTITY)
+                .unwrap();
+            let restored = inverse.apply_to_volume(&orientation.apply_to_volume(&volume));
+            assert_eq!(restored.dims(), volume.dims());
+            assert_eq!(restored.full_slice(), volume.full_slice(), "{:?}", orientation);
+        }
+    }
+}
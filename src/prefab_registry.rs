@@ -0,0 +1,690 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use super::dot_vox_wrapper::DotVoxWrapper;
+use super::map_3D::Map3D;
+use super::prefab_load_error::PrefabLoadError;
+use super::prefab_orientation::{Direction, Orientation};
+use super::render::resources::MAX_PREFAB_IDS;
+use super::standard_voxel_prefab::{ModelAnchor, PrefabTiles, StandardVoxelPrefab};
+
+pub const PREFAB_MANIFEST_PATH : &str = "resources/prefabs.toml";
+
+#[derive(Deserialize)]
+struct Manifest
+{
+    #[serde(default)]
+    prefab : Vec<ManifestEntry>
+}
+
+#[derive(Deserialize, Clone)]
+struct ManifestEntry
+{
+    name : String,
+    path : String,
+    #[serde(default)]
+    model : usize,
+    #[serde(default)]
+    anchor : ManifestAnchor,
+    #[serde(default)]
+    orientations : ManifestOrientations,
+    #[serde(default)]
+    mirrored : bool,
+    #[serde(default)]
+    properties : HashMap<String, toml::Value>
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum ManifestAnchor
+{
+    BottomCentered,
+    Centered,
+    Corner,
+}
+
+impl Default for ManifestAnchor
+{
+    fn default()
+        -> ManifestAnchor
+    {
+        ManifestAnchor::BottomCentered
+    }
+}
+
+impl From<ManifestAnchor> for ModelAnchor
+{
+    fn from(anchor : ManifestAnchor)
+        -> ModelAnchor
+    {
+        match anchor
+        {
+            ManifestAnchor::BottomCentered => ModelAnchor::BottomCentered,
+            ManifestAnchor::Centered => ModelAnchor::Centered,
+            ManifestAnchor::Corner => ModelAnchor::Corner,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum ManifestOrientations
+{
+    None,
+    Horizontal,
+    All,
+}
+
+impl Default for ManifestOrientations
+{
+    fn default()
+        -> ManifestOrientations
+    {
+        ManifestOrientations::None
+    }
+}
+
+impl ManifestOrientations
+{
+    fn orientations(&self, mirrored : bool)
+        -> Vec<Orientation>
+    {
+        let rotations = match self
+        {
+            ManifestOrientations::None => vec![Orientation::IDENTITY],
+            ManifestOrientations::Horizontal => Orientation::horizontal_rotations(),
+            ManifestOrientations::All => Orientation::rotations(),
+        };
+
+        if mirrored
+        {
+            let mirrors : Vec<_> = rotations.iter().map(|r| r.mirrored()).collect();
+            rotations.into_iter().chain(mirrors).collect()
+        }
+        else
+        {
+            rotations
+        }
+    }
+}
+
+// what the manifest says about a prefab
+pub struct PrefabEntry
+{
+    pub name : String,
+    pub path : String,
+    pub model : usize,
+    pub anchor : ModelAnchor,
+    // identity first, each registered as a variant
+    pub orientations : Vec<Orientation>,
+    pub properties : HashMap<String, toml::Value>
+}
+
+// Named prefabs with stable ids, loaded from a manifest.
+// Listed prefabs take ids in manifest order, an id is also the prefab's row in the palette array
+// and its PrefabID chunk in the index map texture.
+// Models larger than 32^3 take one base id per non empty tile, the name refers to the first.
+// Rotated and mirrored variants take the ids right after the prefab they come from,
+// so listing a new prefab at the end of the manifest leaves every earlier id as it was,
+// while changing the orientations of a prefab renumbers every prefab listed after it.
+// Variants that come out identical to an earlier one share its id
+pub struct PrefabRegistry
+{
+    // in manifest order
+    entries : Vec<PrefabEntry>,
+    // base ids of each entry's tiles, laid out as the tiles are, 1x1x1 for prefabs of a single tile
+    entry_tiles : Vec<Map3D<u16>>,
+    // indexed by id
+    prefabs : Vec<StandardVoxelPrefab>,
+    // index into entries of every id
+    entry_indices : Vec<usize>,
+    // base id and orientation of every id
+    variants : Vec<(u16, Orientation)>,
+    variant_ids : HashMap<(u16, Orientation), u16>,
+    ids_by_name : HashMap<String, u16>
+}
+
+#[allow(dead_code)]
+impl PrefabRegistry
+{
+    // Fails only when the manifest itself cannot be used.
+    // Prefabs that fail to load are swapped for the placeholder and returned as errors
+    pub fn load(manifest_path : &str)
+        -> Result<(PrefabRegistry, Vec<PrefabLoadError>), PrefabLoadError>
+    {
+        PrefabRegistry::load_with_max_ids(manifest_path, MAX_PREFAB_IDS as usize)
+    }
+
+    // max_ids is the id texture's size outside of tests
+    fn load_with_max_ids(manifest_path : &str, max_ids : usize)
+        -> Result<(PrefabRegistry, Vec<PrefabLoadError>), PrefabLoadError>
+    {
+        let text = std::fs::read_to_string(manifest_path)
+            .map_err(|source| PrefabLoadError::MissingFile { path : manifest_path.to_string(), source })?;
+        let manifest : Manifest = toml::from_str(&text)
+            .map_err(|e| PrefabLoadError::Parse { path : manifest_path.to_string(), message : e.to_string() })?;
+
+        let mut registry = PrefabRegistry::empty();
+        let mut errors = Vec::new();
+
+        for entry in manifest.prefab
+        {
+            if registry.ids_by_name.contains_key(&entry.name)
+            {
+                return Err(PrefabLoadError::Parse 
+                {
+                    path : manifest_path.to_string(), 
+                    message : format!("prefab name \"{}\" is listed twice", entry.name)
+                });
+            }
+
+            let entry = PrefabEntry 
+            {
+                orientations : entry.orientations.orientations(entry.mirrored),
+                name : entry.name,
+                path : entry.path,
+                model : entry.model,
+                anchor : entry.anchor.into(),
+                properties : entry.properties,
+            };
+
+            let tiles = match PrefabRegistry::load_entry(&entry)
+            {
+                Ok(tiles) => tiles,
+                Err(e) =>
+                {
+                    errors.push(e);
+                    PrefabTiles::single(StandardVoxelPrefab::placeholder())
+                }
+            };
+
+            let entry_index = registry.push(entry, tiles);
+            registry.push_variants(entry_index);
+        }
+
+        if registry.len() > max_ids
+        {
+            return Err(PrefabLoadError::Parse 
+            {
+                path : manifest_path.to_string(), 
+                message : format!("{} prefabs with their variants, at most {} fit", registry.len(), max_ids)
+            });
+        }
+
+        Ok((registry, errors))
+    }
+
+    // stands in when the manifest is unusable so the renderer still has a prefab row
+    pub fn placeholder_only()
+        -> PrefabRegistry
+    {
+        let mut registry = PrefabRegistry::empty();
+        registry.push(
+            PrefabEntry 
+            {
+                name : "placeholder".to_string(),
+                path : String::new(),
+                model : 0,
+                anchor : ModelAnchor::default(),
+                orientations : vec![Orientation::IDENTITY],
+                properties : HashMap::new(),
+            },
+            PrefabTiles::single(StandardVoxelPrefab::placeholder())
+        );
+        registry
+    }
+
+    fn empty()
+        -> PrefabRegistry
+    {
+        PrefabRegistry 
+        {
+            entries : Vec::new(),
+            entry_tiles : Vec::new(),
+            prefabs : Vec::new(),
+            entry_indices : Vec::new(),
+            variants : Vec::new(),
+            variant_ids : HashMap::new(),
+            ids_by_name : HashMap::new(),
+        }
+    }
+
+    // models larger than 32^3 come back as several tiles, those can't be oriented
+    fn load_entry(entry : &PrefabEntry)
+        -> Result<PrefabTiles, PrefabLoadError>
+    {
+        let vox_data_wrap = DotVoxWrapper::new(&entry.path)?;
+        let tiles = PrefabTiles::from_model(&vox_data_wrap, entry.model, entry.anchor)?;
+
+        if tiles.prefabs.len() > 1 && entry.orientations.len() > 1
+        {
+            return Err(PrefabLoadError::Parse 
+            {
+                path : entry.path.clone(), 
+                message : format!("model {} of \"{}\" is split into {} tiles, which can't be oriented", entry.model, entry.name, tiles.prefabs.len())
+            });
+        }
+        Ok(tiles)
+    }
+
+    // returns the entry's index
+    fn push(&mut self, entry : PrefabEntry, tiles : PrefabTiles)
+        -> usize
+    {
+        let entry_index = self.entries.len();
+        let first_id = self.prefabs.len() as u16;
+        self.entry_tiles.push(tiles.layout_with_ids(first_id));
+        for (tile, prefab) in tiles.prefabs.into_iter().enumerate()
+        {
+            let id = first_id + tile as u16;
+            self.prefabs.push(prefab);
+            self.entry_indices.push(entry_index);
+            self.variants.push((id, Orientation::IDENTITY));
+            self.variant_ids.insert((id, Orientation::IDENTITY), id);
+        }
+
+        self.ids_by_name.insert(entry.name.clone(), first_id);
+        self.entries.push(entry);
+        entry_index
+    }
+
+    // must follow the entry's push, before any other entry is pushed
+    fn push_variants(&mut self, entry_index : usize)
+    {
+        for base_id in self.base_ids(entry_index)
+        {
+            for orientation in self.entries[entry_index].orientations.clone()
+            {
+                self.push_variant(base_id, orientation);
+            }
+        }
+    }
+
+    // ids of an entry's tiles in layout order
+    fn base_ids(&self, entry_index : usize)
+        -> Vec<u16>
+    {
+        self.entry_tiles[entry_index].full_slice().iter().copied().filter(|&id| id != u16::MAX).collect()
+    }
+
+    fn push_variant(&mut self, base_id : u16, orientation : Orientation)
+        -> u16
+    {
+        if let Some(&id) = self.variant_ids.get(&(base_id, orientation))
+        {
+            return id;
+        }
+
+        let prefab = self.prefabs[base_id as usize].oriented(orientation);
+
+        // symmetric prefabs turn into themselves
+        let same_as = 
+            self.variant_ids
+            .iter()
+            .filter(|((base, _), _)| *base == base_id)
+            .map(|(_, &id)| id)
+            .find(|&id| self.prefabs[id as usize].palette_volume.full_slice() == prefab.palette_volume.full_slice());
+
+        let id = match same_as
+        {
+            Some(id) => id,
+            None =>
+            {
+                self.prefabs.push(prefab);
+                self.entry_indices.push(self.entry_indices[base_id as usize]);
+                self.variants.push((base_id, orientation));
+                (self.prefabs.len() - 1) as u16
+            }
+        };
+        self.variant_ids.insert((base_id, orientation), id);
+        id
+    }
+
+    // the .vox files the listed prefabs come from, each once
+    pub fn source_paths(&self)
+        -> Vec<String>
+    {
+        let mut paths : Vec<String> = 
+            self.entries
+            .iter()
+            .map(|e| e.path.clone())
+            .filter(|path| !path.is_empty())
+            .collect();
+        paths.sort();
+        paths.dedup();
+        paths
+    }
+
+    // Reloads every listed prefab made from a file, along with its variants.
+    // Ids do not change, and variants found identical at load keep sharing an id.
+    // Returns the ids whose prefabs were replaced. A prefab that fails, or whose tiles
+    // no longer match the ones it was given ids for, keeps its previous content
+    pub fn reload_path(&mut self, path : &str)
+        -> (Vec<u16>, Vec<PrefabLoadError>)
+    {
+        let mut reloaded = Vec::new();
+        let mut errors = Vec::new();
+
+        for entry_index in 0..self.entries.len()
+        {
+            if self.entries[entry_index].path != path
+            {
+                continue;
+            }
+
+            let base_ids = self.base_ids(entry_index);
+            let tiles = match PrefabRegistry::load_entry(&self.entries[entry_index])
+            {
+                Ok(tiles) => tiles,
+                Err(e) =>
+                {
+                    errors.push(e);
+                    continue;
+                }
+            };
+            let layout = tiles.layout_with_ids(base_ids[0]);
+            let tiles_unchanged = 
+                layout.dims() == self.entry_tiles[entry_index].dims() 
+                && layout.full_slice() == self.entry_tiles[entry_index].full_slice();
+            if !tiles_unchanged
+            {
+                errors.push(PrefabLoadError::Parse 
+                {
+                    path : path.to_string(), 
+                    message : format!("the tiles of \"{}\" changed, restart to load it", self.entries[entry_index].name)
+                });
+                continue;
+            }
+
+            for (base_id, prefab) in base_ids.into_iter().zip(tiles.prefabs)
+            {
+                for id in self.variant_ids_of(base_id).into_iter().filter(|&id| id != base_id)
+                {
+                    let (_, orientation) = self.variants[id as usize];
+                    self.prefabs[id as usize] = prefab.oriented(orientation);
+                }
+                self.prefabs[base_id as usize] = prefab;
+                reloaded.extend(self.variant_ids_of(base_id));
+            }
+        }
+
+        (reloaded, errors)
+    }
+
+    // id of a listed prefab as authored, the first tile of one split into tiles
+    pub fn id(&self, name : &str)
+        -> Option<u16>
+    {
+        self.ids_by_name.get(name).copied()
+    }
+
+    // the ids of a listed prefab's tiles laid out to be placed as a group, u16::MAX for empty tiles
+    pub fn tiles(&self, name : &str)
+        -> Option<&Map3D<u16>>
+    {
+        let id = self.id(name)?;
+        self.entry_tiles.get(self.entry_indices[id as usize])
+    }
+
+    // id of a prefab variant, None when the manifest does not ask for the orientation
+    pub fn oriented_id(&self, name : &str, orientation : Orientation)
+        -> Option<u16>
+    {
+        self.variant_ids.get(&(self.id(name)?, orientation)).copied()
+    }
+
+    // e.g. stairs facing +x, prefabs are authored facing +z
+    pub fn facing_id(&self, name : &str, direction : Direction)
+        -> Option<u16>
+    {
+        self.oriented_id(name, Orientation::facing(direction))
+    }
+
+    // base id and orientation of any id
+    pub fn variant(&self, id : u16)
+        -> Option<(u16, Orientation)>
+    {
+        self.variants.get(id as usize).copied()
+    }
+
+    // ids that share a listed prefab, the base id included
+    pub fn variant_ids_of(&self, base_id : u16)
+        -> Vec<u16>
+    {
+        let mut ids : Vec<u16> = 
+            self.variant_ids
+            .iter()
+            .filter(|((base, _), _)| *base == base_id)
+            .map(|(_, &id)| id)
+            .collect();
+        ids.sort();
+        ids.dedup();
+        ids
+    }
+
+    // name of the listed prefab an id comes from
+    pub fn name(&self, id : u16)
+        -> Option<&str>
+    {
+        self.entry(id).map(|e| e.name.as_str())
+    }
+
+    // the manifest entry an id comes from, variants share their base's
+    pub fn entry(&self, id : u16)
+        -> Option<&PrefabEntry>
+    {
+        let entry_index = *self.entry_indices.get(id as usize)?;
+        self.entries.get(entry_index)
+    }
+
+    pub fn property(&self, id : u16, key : &str)
+        -> Option<&toml::Value>
+    {
+        self.entry(id).and_then(|e| e.properties.get(key))
+    }
+
+    // prefabs indexed by id
+    pub fn prefabs(&self)
+        -> &[StandardVoxelPrefab]
+    {
+        &self.prefabs
+    }
+
+    pub fn ids_by_name(&self)
+        -> &HashMap<String, u16>
+    {
+        &self.ids_by_name
+    }
+
+    pub fn len(&self)
+        -> usize
+    {
+        self.prefabs.len()
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::collections::HashSet;
+
+    use super::PrefabRegistry;
+    use crate::map_3D::Map3D;
+    use crate::prefab_load_error::PrefabLoadError;
+    use crate::prefab_orientation::Orientation;
+    use crate::voxel_material::VoxelMaterial;
+    use crate::vox_writer::VoxWriter;
+
+    // a 32^3 model holding the given voxels, written to the temp dir
+    fn write_model(name : &str, voxels : &[[usize ; 3]])
+        -> String
+    {
+        let mut volume = Map3D::new([32 ; 3]);
+        for &coords in voxels
+        {
+            volume.set(coords, true).unwrap();
+        }
+        let mut writer = VoxWriter::new();
+        writer.add_volume(&volume, [0 ; 3], |solid| if solid { Some((0xff808080, VoxelMaterial::default())) } else { None }).unwrap();
+
+        let path = std::env::temp_dir().join(format!("{}.vox", name));
+        let path = path.to_str().unwrap().to_string();
+        writer.write(&path).unwrap();
+        path
+    }
+
+    fn write_manifest(name : &str, manifest : &str)
+        -> String
+    {
+        let path = std::env::temp_dir().join(format!("{}.toml", name));
+        let path = path.to_str().unwrap().to_string();
+        std::fs::write(&path, manifest).unwrap();
+        path
+    }
+
+    fn load(name : &str, manifest : &str)
+        -> PrefabRegistry
+    {
+        let (registry, errors) = PrefabRegistry::load(&write_manifest(name, manifest)).unwrap();
+        assert!(errors.is_empty());
+        registry
+    }
+
+    fn entry(name : &str, path : &str, extra : &str)
+        -> String
+    {
+        format!("[[prefab]]\nname = \"{}\"\npath = {:?}\n{}\n", name, path, extra)
+    }
+
+    #[test]
+    fn identical_variants_share_an_id()
+    {
+        let mut all = Vec::new();
+        for x in 0..32 {
+        for y in 0..32 {
+        for z in 0..32 {
+            all.push([x, y, z]);
+        }}}
+        let cube = write_model("prefab_registry_cube_test", &all);
+        let corner = write_model("prefab_registry_corner_test", &[[0, 0, 0]]);
+        let registry = load("prefab_registry_dedup_test", &format!(
+            "[[prefab]]\nname = \"cube\"\npath = {:?}\norientations = \"all\"\nmirrored = true\n\n\
+            [[prefab]]\nname = \"corner\"\npath = {:?}\norientations = \"all\"\nmirrored = true\n",
+            cube, corner));
+
+        let orientations : Vec<_> = 
+            Orientation::rotations()
+            .into_iter()
+            .flat_map(|r| vec![r, r.mirrored()])
+            .collect();
+
+        // every turn of a full cube is the cube itself
+        assert_eq!(registry.id("cube"), Some(0));
+        assert!(orientations.iter().all(|&o| registry.oriented_id("cube", o) == Some(0)));
+        assert_eq!(registry.variant_ids_of(0), vec![0]);
+
+        // a corner voxel can only end up in one of the 8 corners
+        let corner_id = registry.id("corner").unwrap();
+        assert_eq!(corner_id, 1);
+        let corner_ids : HashSet<_> = orientations.iter().map(|&o| registry.oriented_id("corner", o).unwrap()).collect();
+        assert_eq!(corner_ids.len(), 8);
+        assert_eq!(registry.variant_ids_of(corner_id), (1..9).collect::<Vec<u16>>());
+        assert_eq!(registry.len(), 9);
+    }
+
+    #[test]
+    fn ids_follow_the_manifest()
+    {
+        let corner = write_model("prefab_registry_ids_test", &[[0, 0, 0]]);
+        let missing = std::env::temp_dir().join("prefab_registry_missing_test.vox");
+        let missing = missing.to_str().unwrap();
+        let manifest = [
+            entry("first", &corner, ""),
+            entry("missing", missing, ""),
+            entry("third", &corner, "[prefab.properties]\nsolid = true"),
+        ].concat();
+        let (registry, errors) = PrefabRegistry::load(&write_manifest("prefab_registry_ids_test", &manifest)).unwrap();
+
+        // files that fail to load still take their id, as the placeholder
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].path(), missing);
+        assert_eq!(registry.len(), 3);
+        assert_eq!([registry.id("first"), registry.id("missing"), registry.id("third")], [Some(0), Some(1), Some(2)]);
+        assert_eq!(registry.id("fourth"), None);
+        assert_eq!(registry.name(2), Some("third"));
+        assert_eq!(registry.name(3), None);
+        assert_eq!(registry.property(2, "solid"), Some(&toml::Value::Boolean(true)));
+        assert_eq!(registry.variant(1), Some((1, Orientation::IDENTITY)));
+    }
+
+    #[test]
+    fn duplicate_names_are_rejected()
+    {
+        let corner = write_model("prefab_registry_duplicate_test", &[[0, 0, 0]]);
+        let manifest = [entry("twice", &corner, ""), entry("twice", &corner, "")].concat();
+        let path = write_manifest("prefab_registry_duplicate_test", &manifest);
+
+        match PrefabRegistry::load(&path)
+        {
+            Err(PrefabLoadError::Parse { path : error_path, message }) =>
+            {
+                assert_eq!(error_path, path);
+                assert!(message.contains("\"twice\""), "{}", message);
+            },
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("duplicate names loaded"),
+        }
+    }
+
+    #[test]
+    fn too_many_ids_are_rejected()
+    {
+        let corner = write_model("prefab_registry_overflow_test", &[[0, 0, 0]]);
+        // 1 + 4 turns of the corner + 1
+        let manifest = [
+            entry("first", &corner, ""),
+            entry("turned", &corner, "orientations = \"horizontal\""),
+            entry("last", &corner, ""),
+        ].concat();
+        let path = write_manifest("prefab_registry_overflow_test", &manifest);
+
+        assert_eq!(PrefabRegistry::load_with_max_ids(&path, 6).unwrap().0.len(), 6);
+        match PrefabRegistry::load_with_max_ids(&path, 5)
+        {
+            Err(PrefabLoadError::Parse { path : error_path, message }) =>
+            {
+                assert_eq!(error_path, path);
+                assert!(message.contains("6 prefabs"), "{}", message);
+            },
+            Err(e) => panic!("unexpected error {}", e),
+            Ok(_) => panic!("6 ids loaded with room for 5"),
+        }
+    }
+
+    // pins the numbering the manifest header describes
+    #[test]
+    fn variants_follow_their_prefab()
+    {
+        let corner = write_model("prefab_registry_numbering_test", &[[0, 0, 0]]);
+        let turned = entry("turned", &corner, "orientations = \"horizontal\"");
+        let plain = entry("turned", &corner, "");
+        let after = entry("after", &corner, "");
+
+        let registry = load("prefab_registry_numbering_test", &[turned.clone(), after.clone()].concat());
+        assert_eq!(registry.variant_ids_of(0), vec![0, 1, 2, 3]);
+        assert_eq!(registry.oriented_id("turned", Orientation::QUARTER_TURN_Y), Some(1));
+        assert_eq!(registry.variant(2), Some((0, Orientation::QUARTER_TURN_Y.power(2))));
+        assert_eq!(registry.id("after"), Some(4));
+
+        // appending keeps every earlier id
+        let appended = load("prefab_registry_appended_test", &[turned, after.clone(), entry("appended", &corner, "")].concat());
+        assert_eq!(appended.id("after"), Some(4));
+        assert_eq!(appended.id("appended"), Some(5));
+
+        // dropping the turns of an earlier prefab moves the later ones down
+        let unturned = load("prefab_registry_unturned_test", &[plain, after].concat());
+        assert_eq!(unturned.oriented_id("turned", Orientation::QUARTER_TURN_Y), None);
+        assert_eq!(unturned.id("after"), Some(1));
+    }
+}
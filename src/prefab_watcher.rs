@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant, SystemTime};
+
+// Polls the modification times of prefab source files
+pub struct PrefabWatcher
+{
+    files : Vec<WatchedFile>,
+    interval : Duration,
+    last_poll : Instant
+}
+
+struct WatchedFile
+{
+    path : String,
+    // None while the file is missing
+    modified : Option<SystemTime>
+}
+
+impl PrefabWatcher
+{
+    pub fn new(paths : Vec<String>, interval : Duration)
+        -> PrefabWatcher
+    {
+        let files = 
+            paths
+            .into_iter()
+            .map(|path| WatchedFile { modified : PrefabWatcher::modified(&path), path })
+            .collect();
+
+        PrefabWatcher {files, interval, last_poll : Instant::now()}
+    }
+
+    fn modified(path : &str)
+        -> Option<SystemTime>
+    {
+        std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+    }
+
+    // Paths whose modification time changed since the last poll, including files that disappeared.
+    // Does nothing until the interval has passed
+    pub fn poll(&mut self)
+        -> Vec<String>
+    {
+        if self.last_poll.elapsed() < self.interval
+        {
+            return Vec::new();
+        }
+        self.last_poll = Instant::now();
+
+        let mut changed = Vec::new();
+        for file in &mut self.files
+        {
+            let modified = PrefabWatcher::modified(&file.path);
+            if modified != file.modified
+            {
+                file.modified = modified;
+                changed.push(file.path.clone());
+            }
+        }
+        changed
+    }
+}
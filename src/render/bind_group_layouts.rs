@@ -60,6 +60,12 @@ fn create_primary_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
                 min_binding_size: None,
             },
             // palette array
+            wgpu::BindingType::SampledTexture {
+                dimension: wgpu::TextureViewDimension::D2,
+                component_type: wgpu::TextureComponentType::Float,
+                multisampled: false,
+            },
+            // material array
             wgpu::BindingType::SampledTexture {
                 dimension: wgpu::TextureViewDimension::D2,
                 component_type: wgpu::TextureComponentType::Float,
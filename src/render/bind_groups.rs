@@ -69,6 +69,9 @@ fn create_primary_binds<'a>(device: &wgpu::Device, views: &'a super::resource_vi
                     wgpu::BindingResource::TextureView(
                         &views.palette_array
                     ),
+                    wgpu::BindingResource::TextureView(
+                        &views.material_array
+                    ),
                 ]
             )
         }
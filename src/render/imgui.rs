@@ -8,6 +8,9 @@ pub struct ImguiRenderer {
     wgpu_renderer: imgui_wgpu::Renderer,
     platform: imgui_winit_support::WinitPlatform,
     accumulated_deltas: std::collections::VecDeque<f32>,
+    errors: Vec<String>,
+    // outcome of the last user action, shown until replaced
+    status: Option<String>,
 }
 
 impl ImguiRenderer {
@@ -55,9 +58,19 @@ impl ImguiRenderer {
             wgpu_renderer,
             platform,
             accumulated_deltas: std::collections::VecDeque::new(),
+            errors: Vec::new(),
+            status: None,
         }
     }
 
+    pub fn set_errors(&mut self, errors: Vec<String>) {
+        self.errors = errors;
+    }
+
+    pub fn set_status(&mut self, status: String) {
+        self.status = Some(status);
+    }
+
     pub fn render<'a>(
         &'a mut self, 
         device: &wgpu::Device, 
@@ -145,6 +158,37 @@ impl ImguiRenderer {
             )
         }
 
+        if !self.errors.is_empty() {
+            let imgui_window = imgui::Window::new(im_str!("Asset Errors"));
+            let errors = &self.errors;
+
+            imgui_window
+            .size([500., 160.], imgui::Condition::FirstUseEver)
+            .position([20., 160.], imgui::Condition::FirstUseEver)
+            .build(
+                &ui,
+                || {
+                    for error in errors {
+                        ui.text_wrapped(&im_str!("{}", error));
+                    }
+                }
+            )
+        }
+
+        if let Some(status) = &self.status {
+            let imgui_window = imgui::Window::new(im_str!("Status"));
+
+            imgui_window
+            .size([500., 60.], imgui::Condition::FirstUseEver)
+            .position([20., 540.], imgui::Condition::FirstUseEver)
+            .build(
+                &ui,
+                || {
+                    ui.text_wrapped(&im_str!("{}", status));
+                }
+            )
+        }
+
         self.platform.prepare_render(&ui, &window);
 
         self.wgpu_renderer
@@ -1,4 +1,4 @@
-use crate::{map_3D::Map3D, standard_voxel_prefab::StandardVoxelPrefab};
+use crate::{global_palette::{GlobalPalette, PaletteLayout, PALETTE_ROW_LENGTH}, map_3D::Map3D, palette_chunk::PaletteChunk, standard_voxel_prefab::StandardVoxelPrefab, voxel_material::MATERIAL_TEXELS};
 use nalgebra as na;
 use wgpu::Extent3d;
 
@@ -19,21 +19,17 @@ pub struct RenderContext {
     pipelines: super::pipelines::Pipelines,
     resources: super::resources::Resources,
 
+    palette: GlobalPalette,
+
     imgui_renderer: super::imgui::ImguiRenderer,
 }
 
 impl RenderContext {
-    pub fn new(window: &winit::window::Window, partition_count: u32) 
+    pub fn new(window: &winit::window::Window, partition_count: u32, prefabs: &[StandardVoxelPrefab], palette_layout: PaletteLayout) 
     -> Self {
         let instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
 
         let surface = unsafe { instance.create_surface(window) };
-        let prefabs = vec![
-            StandardVoxelPrefab::new("resources/bricks.vox"),
-            StandardVoxelPrefab::new("resources/inscribed stone.vox"),
-            StandardVoxelPrefab::new("resources/ridged_stone.vox")
-        ];
-        // let voxel_prefab_brick = ;
 
     let adapter = 
         futures::executor::block_on(
@@ -70,8 +66,9 @@ impl RenderContext {
 
     let bind_group_layouts = super::bind_group_layouts::BindGroupLayouts::new(&device);
 
+    let palette = GlobalPalette::build(prefabs, palette_layout);
         
-    let resources = super::resources::Resources::new(&device, partition_count, prefabs.len() as u32);
+    let resources = super::resources::Resources::new(&device, partition_count, palette.row_capacity() as u32);
        
     
     let pipelines = 
@@ -99,6 +96,7 @@ impl RenderContext {
             bind_groups,
             pipelines,
             resources,
+            palette,
             imgui_renderer,
         };
 
@@ -108,45 +106,113 @@ impl RenderContext {
     }
 
 
-    pub fn init_prefabs(&mut self, prefabs: Vec<StandardVoxelPrefab>) {
+    pub fn init_prefabs(&mut self, prefabs: &[StandardVoxelPrefab]) {
+
+        self.upload_palette();
 
-        let mega_palette: Vec<u32> = prefabs.iter().flat_map(|p| p.palette.iter()).map(|v| *v).collect();
+
+        for (i, prefab) in prefabs.iter().enumerate() {
+            let mut encoder = 
+                self.device.create_command_encoder(
+                    &wgpu::CommandEncoderDescriptor {
+                        label: None,
+                    }
+            );
+            // if i > 0 {
+                // continue;
+            // }
+            self.upload_prefab(&mut encoder, prefab, i as u32);
+            self.queue.submit(Some(encoder.finish()));
+        }
+    }
+
+    // uploads the palette and material arrays
+    fn upload_palette(&self) {
+        self.upload_palette_rows(0..self.palette.row_count());
+    }
+
+    fn upload_palette_rows(&self, rows: std::ops::Range<usize>) {
+        if rows.start >= rows.end {
+            return;
+        }
+        let row_count = (rows.end - rows.start) as u32;
+
+        let mega_palette = self.palette.texels();
+        let palette_rows = &mega_palette[rows.start * PALETTE_ROW_LENGTH..rows.end * PALETTE_ROW_LENGTH];
         // for (i, c) in mega_palette.iter().enumerate() {
             // println!("{}: {:b}", i, c);
         // }
 
         self.queue.write_texture(
-            self.resources.palette_texture_copy_view(),
-            unsafe {mega_palette[..].align_to().1},
+            self.resources.palette_texture_copy_view(rows.start as u32),
+            unsafe {palette_rows.align_to().1},
             wgpu::TextureDataLayout {
                 offset: 0,
-                bytes_per_row: 256 * 4,
-                rows_per_image: prefabs.len() as u32
+                bytes_per_row: PALETTE_ROW_LENGTH as u32 * 4,
+                rows_per_image: row_count
             },
             wgpu::Extent3d {
-                width: 256,
-                height: prefabs.len() as u32,
+                width: PALETTE_ROW_LENGTH as u32,
+                height: row_count,
                 depth: 1
             }
         );
 
+        let materials = self.palette.material_texels();
+        let material_row_length = PALETTE_ROW_LENGTH * MATERIAL_TEXELS;
+        let material_rows = &materials[rows.start * material_row_length..rows.end * material_row_length];
+        self.queue.write_texture(
+            self.resources.material_texture_copy_view(rows.start as u32),
+            unsafe {material_rows.align_to().1},
+            wgpu::TextureDataLayout {
+                offset: 0,
+                bytes_per_row: material_row_length as u32 * 16,
+                rows_per_image: row_count
+            },
+            wgpu::Extent3d {
+                width: material_row_length as u32,
+                height: row_count,
+                depth: 1
+            }
+        );
+    }
 
+    // Re-uploads prefabs whose content changed, ids keep their slots.
+    // Only the palette rows the changed prefabs touch are written again
+    pub fn reload_prefabs(&mut self, prefabs: &[StandardVoxelPrefab], prefab_ids: &[u16]) {
+        for &prefab_id in prefab_ids {
+            let prefab = &prefabs[prefab_id as usize];
+            let rows = self.palette.update_prefab(prefab_id, prefab);
+            self.upload_palette_rows(rows);
 
-        for (i, prefab) in prefabs.into_iter().enumerate() {
             let mut encoder = 
                 self.device.create_command_encoder(
                     &wgpu::CommandEncoderDescriptor {
                         label: None,
                     }
             );
-            // if i > 0 {
-                // continue;
-            // }
-            self.upload_prefab(&mut encoder, prefab, i as u32);
+            self.upload_prefab(&mut encoder, prefab, prefab_id as u32);
             self.queue.submit(Some(encoder.finish()));
         }
     }
 
+    // recolors a global palette entry across every prefab using it
+    #[allow(dead_code)]
+    pub fn set_palette_color(&mut self, global_index: u16, color: u32) {
+        self.palette.set_color(global_index, color);
+        self.upload_palette();
+    }
+
+    // messages shown in the overlay until replaced
+    pub fn report_errors(&mut self, errors: Vec<String>) {
+        self.imgui_renderer.set_errors(errors);
+    }
+
+    // a line in the overlay saying how the last user action went, shown until replaced
+    pub fn report_status(&mut self, status: String) {
+        self.imgui_renderer.set_status(status);
+    }
+
     pub fn render(&mut self, render_desc: RenderDescriptor) {
         let frame = 
             self.swapchain.get_current_frame()
@@ -161,13 +227,22 @@ impl RenderContext {
             );
 
         // write each map texture chunk that needs to be uploaded
-        render_desc.map_data
-        .iter()
-        .for_each(|m|
-            self.upload_index_map(ChunkIDVariant::PartitionID(m.0 as u32), m.1)
-        );
-
-        assert!(render_desc.map_data.len() < 2);
+        // chunks are only expanded to the dense layout here.
+        // construct_bit_volume writes the chunk it works on into a shared buffer,
+        // so each chunk is submitted on its own before the next overwrites it
+        for (partition_id, chunk) in &render_desc.map_data {
+            let variant = ChunkIDVariant::PartitionID(*partition_id as u32);
+            self.upload_index_map(variant.clone(), &chunk.to_map());
+
+            let mut chunk_encoder = 
+                self.device.create_command_encoder(
+                    &wgpu::CommandEncoderDescriptor {
+                        label: None,
+                    }
+                );
+            self.construct_bit_volume(&mut chunk_encoder, variant);
+            self.queue.submit(Some(chunk_encoder.finish()));
+        }
 
 
         // write layer index map
@@ -190,15 +265,10 @@ impl RenderContext {
         {
             let data = super::shader_data::trace_frame::make_bytes(
                 render_desc.pos, [crate::RENDER_RES_X, crate::RENDER_RES_Y],
-                render_desc.cam_orientation, 100f32, );
+                render_desc.cam_orientation, crate::FOV, self.palette.layout());
             self.queue.write_buffer(&self.resources.buffers.trace_frame, 0, &data);
         }
 
-        if render_desc.map_data.len() > 0 {
-            self.construct_bit_volume(&mut encoder,
-                super::resources::ChunkIDVariant::PartitionID(render_desc.map_data[0].0 as u32));
-        }
-
         use super::resources::div_ceil;
 
         let res_dispatch = [div_ceil(crate::RENDER_RES_X, 8), div_ceil(crate::RENDER_RES_Y, 8)];
@@ -256,10 +326,11 @@ impl RenderContext {
         )
     }
 
-    pub fn upload_prefab(&self, encoder: &mut wgpu::CommandEncoder, prefab: StandardVoxelPrefab, prefab_id: u32) {
+    pub fn upload_prefab(&self, encoder: &mut wgpu::CommandEncoder, prefab: &StandardVoxelPrefab, prefab_id: u32) {
         let variant = ChunkIDVariant::PrefabID(prefab_id);
 
-        self.upload_index_map(variant.clone(), &prefab.palette_volume);
+        let palette_volume = self.palette.remap_volume(prefab_id as u16, &prefab.palette_volume);
+        self.upload_index_map(variant.clone(), &palette_volume);
         self.construct_bit_volume(encoder, variant);
     }
 
@@ -346,7 +417,7 @@ fn swapchain_only_color_attachments(
 
 pub struct RenderDescriptor<'a> {
     pub window: &'a winit::window::Window,
-    pub map_data: Vec<(usize, &'a Map3D<u16>)>,
+    pub map_data: Vec<(usize, &'a PaletteChunk)>,
     pub layer_index_data: Vec<u16>,
     pub cam_orientation: na::UnitQuaternion<f32>,
     pub pos: na::Vector3<f32>,
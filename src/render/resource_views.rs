@@ -9,6 +9,7 @@ pub struct ResourceViews<'a> {
     pub default_sampler: &'a wgpu::Sampler,
     pub mono_bit_map_mipmaps: Vec<wgpu::TextureView>,
     pub palette_array: wgpu::TextureView,
+    pub material_array: wgpu::TextureView,
 }
 
 impl<'a> ResourceViews<'a> {
@@ -49,6 +50,9 @@ impl<'a> ResourceViews<'a> {
             palette_array:
                 resources.palette_array
                 .create_view(&wgpu::TextureViewDescriptor::default()),
+            material_array:
+                resources.material_array
+                .create_view(&wgpu::TextureViewDescriptor::default()),
         }
     }
 }
\ No newline at end of file
@@ -9,6 +9,7 @@ pub struct Resources {
     pub buffers: Buffers,
     pub default_sampler: wgpu::Sampler,
     pub palette_array: wgpu::Texture,
+    pub material_array: wgpu::Texture,
 }
 
 // a unique id for a chunk in texture memory
@@ -25,10 +26,10 @@ pub fn chunk_id_to_chunk_coords(chunk_id: u32) -> [u32 ; 3] {
     [chunk_id % 32, chunk_id / (32 * 32), (chunk_id / 32) % 32]
 }
 
-pub fn chunk_id_variant_to_id(chunk_id_variant: ChunkIDVariant) -> u32 {
-    const MAX_PREFAB_IDS: u32 = 32 * 32 * 2;
-    const MAX_LAYER_IDS: u32 = 32 * 2;
+pub const MAX_PREFAB_IDS: u32 = 32 * 32 * 2;
+const MAX_LAYER_IDS: u32 = 32 * 2;
 
+pub fn chunk_id_variant_to_id(chunk_id_variant: ChunkIDVariant) -> u32 {
     match chunk_id_variant {
         ChunkIDVariant::PrefabID(id) => id,
         ChunkIDVariant::PartitionID(id) => id + MAX_PREFAB_IDS + MAX_LAYER_IDS,
@@ -36,6 +37,16 @@ pub fn chunk_id_variant_to_id(chunk_id_variant: ChunkIDVariant) -> u32 {
     }
 }
 
+pub fn chunk_id_to_variant(chunk_id: u32) -> ChunkIDVariant {
+    if chunk_id < MAX_PREFAB_IDS {
+        ChunkIDVariant::PrefabID(chunk_id)
+    } else if chunk_id < MAX_PREFAB_IDS + MAX_LAYER_IDS {
+        ChunkIDVariant::LayerID(chunk_id - MAX_PREFAB_IDS)
+    } else {
+        ChunkIDVariant::PartitionID(chunk_id - MAX_PREFAB_IDS - MAX_LAYER_IDS)
+    }
+}
+
 
 impl Resources {
     pub fn map_texture_copy_view_chunk_id(&self, chunk_id: ChunkIDVariant)
@@ -58,12 +69,31 @@ impl Resources {
             }
     }
 
-    pub fn palette_texture_copy_view(&self) 
+    pub fn palette_texture_copy_view(&self, first_row: u32) 
     -> wgpu::TextureCopyView {
         wgpu::TextureCopyView {
             texture: &self.palette_array,
             mip_level: 0,
-            origin: wgpu::Origin3d::ZERO,
+            origin: 
+                wgpu::Origin3d {
+                    x: 0,
+                    y: first_row,
+                    z: 0,
+                },
+        }
+    }
+
+    pub fn material_texture_copy_view(&self, first_row: u32) 
+    -> wgpu::TextureCopyView {
+        wgpu::TextureCopyView {
+            texture: &self.material_array,
+            mip_level: 0,
+            origin: 
+                wgpu::Origin3d {
+                    x: 0,
+                    y: first_row,
+                    z: 0,
+                },
         }
     }
 
@@ -113,6 +143,7 @@ impl Resources {
             mono_bit_map_texture: create_mono_bit_map(&device, chunk_height),
             default_sampler,
             palette_array: create_palette_array(device, palette_count),
+            material_array: create_material_array(device, palette_count),
         }
 
     }
@@ -136,6 +167,25 @@ pub fn create_palette_array(device: &wgpu::Device, palette_count: u32) -> wgpu::
     )
 }
 
+// laid out like the palette array, each entry is MATERIAL_TEXELS texels wide
+pub fn create_material_array(device: &wgpu::Device, palette_count: u32) -> wgpu::Texture {
+    device.create_texture(
+        &wgpu::TextureDescriptor {
+            label: None,
+            size: wgpu::Extent3d {
+                width: 256 * crate::voxel_material::MATERIAL_TEXELS as u32,
+                height: palette_count,
+                depth: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsage::COPY_DST | wgpu::TextureUsage::SAMPLED,
+        }
+    )
+}
+
 pub fn create_index_map(device: &wgpu::Device, extent: wgpu::Extent3d) -> wgpu::Texture {
     device.create_texture(
         &wgpu::TextureDescriptor {
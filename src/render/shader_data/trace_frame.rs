@@ -1,6 +1,8 @@
 
 use nalgebra as na;
 
+use crate::global_palette::PaletteLayout;
+
 #[derive(Default, Copy, Clone)]
 #[allow(dead_code)]
 struct TraceFrameData {
@@ -13,14 +15,15 @@ struct TraceFrameData {
     forward: [f32 ; 3],
         p3: i32,
     cotan_half_fov: f32,
-        p4: [i32 ; 3],
+    palette_layout: i32,
+        p4: [i32 ; 2],
     resolution: [i32 ; 2],
         p5: [i32 ; 2]
 }
 unsafe impl bytemuck::Zeroable for TraceFrameData {}
 unsafe impl bytemuck::Pod for TraceFrameData {}
 
-pub fn make_bytes(pos: na::Vector3<f32>, render_resolution : [u32 ; 2], orientation: na::UnitQuaternion<f32>, fov: f32) 
+pub fn make_bytes(pos: na::Vector3<f32>, render_resolution : [u32 ; 2], orientation: na::UnitQuaternion<f32>, fov: f32, palette_layout: PaletteLayout) 
 -> Vec<u8> {
 
     let trace_frame_data = TraceFrameData {
@@ -33,6 +36,8 @@ pub fn make_bytes(pos: na::Vector3<f32>, render_resolution : [u32 ; 2], orientat
             orientation.transform_vector(&na::Vector3::z_axis()).into(),
         cotan_half_fov:
             1. / (fov.to_radians() * 0.5).tan(),
+        palette_layout:
+            palette_layout.shader_value(),
         resolution:
             [render_resolution[0] as i32, render_resolution[1] as i32],
         ..Default::default()
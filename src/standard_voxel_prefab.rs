@@ -4,45 +4,346 @@ use crate::map_3D::Map3D;
 
 use super::bit_voxels::BitVoxels;
 use super::dot_vox_wrapper::DotVoxWrapper;
+use super::prefab_analysis::PrefabAnalysis;
+use super::prefab_load_error::PrefabLoadError;
+use super::prefab_orientation::Orientation;
+use super::vox_scene::ModelInstance;
+use super::voxel_material::VoxelMaterial;
+
+const PREFAB_LENGTH : usize = 32;
 
 pub struct StandardVoxelPrefab
 {
     dims : [usize ; 3],
     bit_voxels : BitVoxels,
+    // computed once with the prefab, prefabs are not edited in place
+    analysis : PrefabAnalysis,
     pub palette_volume : Map3D<u16>,
-    pub palette : [u32 ; 256]
+    pub palette : [u32 ; 256],
+    // material of each palette entry
+    pub materials : [VoxelMaterial ; 256]
 }
 
 
 impl StandardVoxelPrefab
 {
-    pub fn new(vox_file_path : &str)
+    // magenta and black checkers, stands in for prefabs that failed to load
+    pub fn placeholder()
+        -> StandardVoxelPrefab
+    {
+        const CHECKER_LENGTH : usize = 8;
+
+        let mut palette = [0u32 ; 256];
+        palette[0] = 0xffff00ff;
+        palette[1] = 0xff000000;
+
+        let mut palette_volume = Map3D::new([PREFAB_LENGTH ; 3]);
+        palette_volume.set_all(&|coords| 
+            ((coords[0] / CHECKER_LENGTH + coords[1] / CHECKER_LENGTH + coords[2] / CHECKER_LENGTH) % 2) as u16
+        );
+
+        StandardVoxelPrefab::from_palette_volume(palette_volume, palette, [VoxelMaterial::default() ; 256])
+    }
+
+    // palette_volume must be 32^3, u16::MAX marks empty voxels
+    pub fn from_palette_volume(palette_volume : Map3D<u16>, palette : [u32 ; 256], materials : [VoxelMaterial ; 256])
+        -> StandardVoxelPrefab
+    {
+        assert!(palette_volume.dims() == [PREFAB_LENGTH ; 3], "prefabs are 32^3");
+
+        let dims = palette_volume.dims();
+        let bit_voxels = BitVoxels::from_palette_volume(&palette_volume);
+        let analysis = PrefabAnalysis::new(&bit_voxels);
+
+        StandardVoxelPrefab {dims, bit_voxels, analysis, palette_volume, palette, materials}
+    }
+
+    // a copy turned about its center, palette and materials are shared
+    pub fn oriented(&self, orientation : Orientation)
         -> StandardVoxelPrefab
     {
-        let vox_data_wrap = DotVoxWrapper::new(vox_file_path);
+        StandardVoxelPrefab::from_palette_volume(
+            orientation.apply_to_volume(&self.palette_volume), self.palette, self.materials)
+    }
+
+    pub fn dims(&self)
+        -> [usize ; 3]
+    {
+        self.dims
+    }
+
+    pub fn bit_voxels(&self)
+        -> &BitVoxels
+    {
+        &self.bit_voxels
+    }
+
+    #[allow(dead_code)]
+    pub fn analysis(&self)
+        -> &PrefabAnalysis
+    {
+        &self.analysis
+    }
+}
+
+
+// Where a model that does not fill its tiles sits inside them.
+// y is up, as in the wrapper's orientation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModelAnchor
+{
+    // centered on every axis
+    Centered,
+    // centered on x and z, resting on the bottom of the tiles
+    BottomCentered,
+    // model origin on the tile origin
+    Corner,
+}
+
+impl Default for ModelAnchor
+{
+    fn default()
+        -> ModelAnchor
+    {
+        ModelAnchor::BottomCentered
+    }
+}
+
+impl ModelAnchor
+{
+    // offset of the model inside padded extents
+    fn offset(&self, dims : [usize ; 3], padded_dims : [usize ; 3])
+        -> [usize ; 3]
+    {
+        let centered = |axis : usize| (padded_dims[axis] - dims[axis]) / 2;
+        match self
+        {
+            ModelAnchor::Centered => [centered(0), centered(1), centered(2)],
+            ModelAnchor::BottomCentered => [centered(0), 0, centered(2)],
+            ModelAnchor::Corner => [0, 0, 0],
+        }
+    }
+}
+
+
+// A model of any size cut into a grid of 32^3 prefabs,
+// a model no larger than 32^3 is a single tile placed by its anchor
+pub struct PrefabTiles
+{
+    // the non empty tiles
+    pub prefabs : Vec<StandardVoxelPrefab>,
+    // one cell per tile holding its index into prefabs, u16::MAX for empty tiles
+    // laid out like a partition map so it can be placed as a group
+    pub layout : Map3D<u16>,
+    // where the model's voxel origin lies in the tiles
+    pub model_offset : [usize ; 3]
+}
+
+impl PrefabTiles
+{
+    pub fn new(vox_file_path : &str, model_index : usize, anchor : ModelAnchor)
+        -> Result<PrefabTiles, PrefabLoadError>
+    {
+        PrefabTiles::from_model(&DotVoxWrapper::new(vox_file_path)?, model_index, anchor)
+    }
 
-        let bit_voxels = BitVoxels::new(&vox_data_wrap, 0);
+    pub fn from_model(vox_data_wrap : &DotVoxWrapper, model_index : usize, anchor : ModelAnchor)
+        -> Result<PrefabTiles, PrefabLoadError>
+    {
+        vox_data_wrap.check_model_index(model_index)?;
 
-        let dims = vox_data_wrap.dims(0);
+        let dims = vox_data_wrap.dims(model_index);
+        let grid_dims = [
+            ((dims[0] + PREFAB_LENGTH - 1) / PREFAB_LENGTH).max(1),
+            ((dims[1] + PREFAB_LENGTH - 1) / PREFAB_LENGTH).max(1),
+            ((dims[2] + PREFAB_LENGTH - 1) / PREFAB_LENGTH).max(1),
+        ];
+        let padded_dims = [grid_dims[0] * PREFAB_LENGTH, grid_dims[1] * PREFAB_LENGTH, grid_dims[2] * PREFAB_LENGTH];
+        let model_offset = anchor.offset(dims, padded_dims);
 
-        if dims[0] != 32 || dims[1] != 32 || dims[2] != 32
+        let mut full_volume = Map3D::new_with_default(padded_dims, u16::MAX);
+        for voxel in vox_data_wrap.voxel_slice(model_index)
         {
-            panic!("Must have appropriately dimensioned model!");
+            full_volume.set([
+                    voxel.x as usize + model_offset[0], 
+                    voxel.y as usize + model_offset[1], 
+                    voxel.z as usize + model_offset[2]
+                ], voxel.i as u16)
+                .map_err(|_| PrefabLoadError::Parse 
+                {
+                    path : vox_data_wrap.path().to_string(), 
+                    message : format!("voxel of model {} lies outside of the model dims", model_index)
+                })?;
         }
 
-        let palette_volume = 
+        let palette = vox_data_wrap.palette();
+        let materials = vox_data_wrap.materials();
+
+        let mut prefabs = Vec::new();
+        let mut layout = Map3D::new_with_default(grid_dims, u16::MAX);
+        for tile_coords in layout.coords_iter().collect::<Vec<_>>()
         {
-            let mut pal_vol = Map3D::new_with_default(32, u16::MAX);
-            for voxel in vox_data_wrap.voxel_slice(0)
+            let tile_min = [
+                tile_coords[0] * PREFAB_LENGTH, 
+                tile_coords[1] * PREFAB_LENGTH, 
+                tile_coords[2] * PREFAB_LENGTH
+            ];
+            let palette_volume = full_volume.extract(tile_min, [PREFAB_LENGTH ; 3]);
+
+            if palette_volume.full_slice().iter().all(|&i| i == u16::MAX)
             {
-                pal_vol.set([voxel.x as usize, voxel.y as usize, voxel.z as usize], (voxel.i) as u16);
-                // pal_vol[index] = voxel.i as u16;
+                continue;
             }
-            pal_vol
-        };
 
-        let palette = vox_data_wrap.palette();
+            layout.set(tile_coords, prefabs.len() as u16).unwrap();
+            prefabs.push(StandardVoxelPrefab::from_palette_volume(palette_volume, palette, materials));
+        }
+
+        // an empty model still makes an (empty) prefab
+        if prefabs.is_empty()
+        {
+            return Ok(PrefabTiles::single(StandardVoxelPrefab::from_palette_volume(
+                Map3D::new_with_default([PREFAB_LENGTH ; 3], u16::MAX), palette, materials)));
+        }
+
+        Ok(PrefabTiles {prefabs, layout, model_offset})
+    }
+
+    // a lone prefab as a 1x1x1 grid
+    pub fn single(prefab : StandardVoxelPrefab)
+        -> PrefabTiles
+    {
+        PrefabTiles {prefabs : vec![prefab], layout : Map3D::new_with_default([1 ; 3], 0), model_offset : [0 ; 3]}
+    }
+
+    pub fn grid_dims(&self)
+        -> [usize ; 3]
+    {
+        self.layout.dims()
+    }
+
+    // the layout with tile indices swapped for the prefab ids the tiles were registered under
+    // tile i is expected at first_prefab_id + i
+    pub fn layout_with_ids(&self, first_prefab_id : u16)
+        -> Map3D<u16>
+    {
+        let mut ids = Map3D::new(self.layout.dims());
+        ids.set_all(&|coords| match self.layout.get(coords)
+        {
+            Some(tile) if tile != u16::MAX => first_prefab_id + tile,
+            _ => u16::MAX,
+        });
+        ids
+    }
+}
+
+
+// Every model of a .vox file as prefab tiles, placed the way the scene graph places them.
+// instances index into models through model_index,
+// tiles are anchored at the model's corner so voxel_to_world of the origin is the first tile's origin
+pub struct VoxStructure
+{
+    pub models : Vec<PrefabTiles>,
+    pub instances : Vec<ModelInstance>
+}
+
+impl VoxStructure
+{
+    pub fn new(vox_file_path : &str)
+        -> Result<VoxStructure, PrefabLoadError>
+    {
+        let vox_data_wrap = DotVoxWrapper::new(vox_file_path)?;
+
+        let models = 
+            (0..vox_data_wrap.model_count())
+            .map(|model_index| PrefabTiles::from_model(&vox_data_wrap, model_index, ModelAnchor::Corner))
+            .collect::<Result<_, _>>()?;
+
+        let instances = 
+            vox_data_wrap.instances()
+            .iter()
+            .filter(|instance| !instance.hidden)
+            .cloned()
+            .collect();
+
+        Ok(VoxStructure {models, instances})
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::{ModelAnchor, PrefabTiles};
+    use crate::map_3D::Map3D;
+    use crate::voxel_material::VoxelMaterial;
+    use crate::vox_writer::VoxWriter;
+
+    const RED : u32 = 0xff0000ff;
+    const GREEN : u32 = 0xff00ff00;
+    const BLUE : u32 = 0xffff0000;
+
+    // a 40x10x70 model, 2x1x3 tiles, with a voxel on each corner of its diagonal and one inside
+    fn write_model(name : &str)
+        -> String
+    {
+        let mut volume = Map3D::new([40, 10, 70]);
+        volume.set([0, 0, 0], RED).unwrap();
+        volume.set([39, 9, 69], BLUE).unwrap();
+        volume.set([25, 5, 30], GREEN).unwrap();
+
+        let mut writer = VoxWriter::new();
+        writer.add_volume(&volume, [0 ; 3], |color| if color != 0 { Some((color, VoxelMaterial::default())) } else { None }).unwrap();
+        let path = std::env::temp_dir().join(format!("{}.vox", name));
+        let path = path.to_str().unwrap().to_string();
+        writer.write(&path).unwrap();
+        path
+    }
+
+    // color of the voxel at a position in the padded tiles
+    fn color_at(tiles : &PrefabTiles, coords : [usize ; 3])
+        -> Option<u32>
+    {
+        let tile = tiles.layout.get([coords[0] / 32, coords[1] / 32, coords[2] / 32])?;
+        let prefab = tiles.prefabs.get(tile as usize)?;
+        let index = prefab.palette_volume.get([coords[0] % 32, coords[1] % 32, coords[2] % 32])?;
+        if index == u16::MAX { None } else { Some(prefab.palette[index as usize]) }
+    }
+
+    #[test]
+    fn models_larger_than_a_prefab_are_cut_into_anchored_tiles()
+    {
+        let path = write_model("standard_voxel_prefab_tiles_test");
+
+        // offset in the 64x32x96 tiles, then the non empty tiles
+        let cases = [
+            (ModelAnchor::Corner, [0, 0, 0], 2),
+            (ModelAnchor::Centered, [12, 11, 13], 3),
+            (ModelAnchor::BottomCentered, [12, 0, 13], 3),
+        ];
+        for &(anchor, offset, tile_count) in cases.iter()
+        {
+            let tiles = PrefabTiles::new(&path, 0, anchor).unwrap();
+            assert_eq!(tiles.grid_dims(), [2, 1, 3], "{:?}", anchor);
+            assert_eq!(tiles.model_offset, offset, "{:?}", anchor);
+            assert_eq!(tiles.prefabs.len(), tile_count, "{:?}", anchor);
+            assert_eq!(tiles.layout.full_slice().iter().filter(|&&tile| tile != u16::MAX).count(), tile_count);
+
+            for &(voxel, color) in [([0, 0, 0], RED), ([39, 9, 69], BLUE), ([25, 5, 30], GREEN)].iter()
+            {
+                let placed = [voxel[0] + offset[0], voxel[1] + offset[1], voxel[2] + offset[2]];
+                assert_eq!(color_at(&tiles, placed), Some(color), "{:?} {:?}", anchor, voxel);
+            }
+            let voxel_count : usize = tiles.prefabs.iter().map(|prefab| prefab.bit_voxels().count()).sum();
+            assert_eq!(voxel_count, 3);
+        }
 
-        StandardVoxelPrefab {dims, bit_voxels, palette_volume, palette}
+        // the inner voxel moves from the first tile to the middle one when centered
+        let corner = PrefabTiles::new(&path, 0, ModelAnchor::Corner).unwrap();
+        assert_eq!(corner.layout.get([0, 0, 0]), Some(0));
+        assert_eq!(corner.layout.get([1, 0, 1]), Some(u16::MAX));
+        let centered = PrefabTiles::new(&path, 0, ModelAnchor::Centered).unwrap();
+        assert_ne!(centered.layout.get([1, 0, 1]), Some(u16::MAX));
+        assert_eq!(centered.layout_with_ids(10).get([1, 0, 1]), centered.layout.get([1, 0, 1]).map(|tile| tile + 10));
     }
 }
@@ -0,0 +1,479 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use nalgebra as na;
+use serde::Deserialize;
+
+use crate::dot_vox_wrapper::DotVoxWrapper;
+use crate::generation_pipeline::GenerationStage;
+use crate::map_3D::Map3D;
+use crate::terrain_generator::HeightmapTerrainGenerator;
+use crate::vox_scene::VoxTransform;
+use crate::world_generator::{cell_hash, GenerateContext, PARTITION_LENGTH};
+
+// A block of prefab cells placed as one piece, u16::MAX cells leave the world as it is
+pub struct Structure {
+    pub name: String,
+    // relative odds of being picked for a region
+    pub weight: u32,
+    // cells buried below the surface it stands on
+    pub sink: i64,
+    pub cells: Map3D<u16>,
+}
+
+// How a generation config describes a structure, either a grid of characters or a .vox scene
+#[derive(Deserialize)]
+pub struct StructureConfig {
+    pub name: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    #[serde(default)]
+    pub sink: i64,
+    // one character to a prefab name, cells of characters left out keep what the world has there
+    #[serde(default)]
+    pub legend: HashMap<String, String>,
+    // bottom layer first, each a list of rows along z with one character per cell along x
+    #[serde(default)]
+    pub layers: Vec<Vec<String>>,
+    // a .vox scene of 32^3 models, each listed in the prefab manifest with its path and model
+    pub vox: Option<String>,
+}
+
+fn default_weight()
+-> u32 {
+    1
+}
+
+impl StructureConfig {
+    pub fn build(&self, context: &GenerateContext)
+    -> Result<Structure, String> {
+        let cells = match &self.vox {
+            Some(path) if !self.layers.is_empty() =>
+                return Err(format!("structure \"{}\" has both layers and vox \"{}\", give one", self.name, path)),
+            Some(path) => self.vox_cells(path, context)?,
+            None => self.grid_cells(context)?,
+        };
+
+        Ok(Structure {
+            name: self.name.clone(),
+            weight: self.weight,
+            sink: self.sink,
+            cells,
+        })
+    }
+
+    fn grid_cells(&self, context: &GenerateContext)
+    -> Result<Map3D<u16>, String> {
+        let mut legend = HashMap::new();
+        for (key, name) in &self.legend {
+            let mut chars = key.chars();
+            let c = match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => return Err(format!("structure \"{}\" legend key \"{}\" is not a single character", self.name, key)),
+            };
+            // u16::MAX would leave the world as it is rather than place anything
+            let id = context.prefab_id(name);
+            if id == u16::MAX {
+                return Err(format!("structure \"{}\" legend \"{}\" names prefab \"{}\" which is not in the manifest", self.name, key, name));
+            }
+            legend.insert(c, id);
+        }
+
+        let dims = [
+            self.layers.iter().flatten().map(|row| row.chars().count()).max().unwrap_or(0),
+            self.layers.len(),
+            self.layers.iter().map(|layer| layer.len()).max().unwrap_or(0),
+        ];
+        if dims.contains(&0) {
+            return Err(format!("structure \"{}\" has no cells", self.name));
+        }
+
+        let mut cells = Map3D::new_with_default(dims, u16::MAX);
+        for (y, layer) in self.layers.iter().enumerate() {
+        for (z, row) in layer.iter().enumerate() {
+        for (x, c) in row.chars().enumerate() {
+            if let Some(&id) = legend.get(&c) {
+                cells.set([x, y, z], id).unwrap();
+            }
+        }}}
+        Ok(cells)
+    }
+
+    // every shown instance of the scene becomes the prefab listed for its model,
+    // at the cell its 32^3 block sits in
+    fn vox_cells(&self, path: &str, context: &GenerateContext)
+    -> Result<Map3D<u16>, String> {
+        let wrapper = DotVoxWrapper::new(path).map_err(|e| e.to_string())?;
+
+        let mut placed = Vec::new();
+        for instance in wrapper.instances().iter().filter(|instance| !instance.hidden) {
+            if instance.transform.rotation != VoxTransform::IDENTITY.rotation {
+                return Err(format!("{}: model {} is rotated, structures place models as authored", path, instance.model_index));
+            }
+            let id = context.model_prefab_id(path, instance.model_index);
+            if id == u16::MAX {
+                return Err(format!("{}: model {} is not listed in the prefab manifest", path, instance.model_index));
+            }
+            let (min, _) = instance.world_bounds(wrapper.dims(instance.model_index));
+            let cell = [
+                (min[0] + PARTITION_LENGTH as i32 / 2).div_euclid(PARTITION_LENGTH as i32),
+                (min[1] + PARTITION_LENGTH as i32 / 2).div_euclid(PARTITION_LENGTH as i32),
+                (min[2] + PARTITION_LENGTH as i32 / 2).div_euclid(PARTITION_LENGTH as i32),
+            ];
+            placed.push((cell, id));
+        }
+        if placed.is_empty() {
+            return Err(format!("{}: no models are shown", path));
+        }
+
+        let mut min = [i32::MAX ; 3];
+        let mut max = [i32::MIN ; 3];
+        for (cell, _) in &placed {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(cell[axis]);
+                max[axis] = max[axis].max(cell[axis]);
+            }
+        }
+
+        let dims = [(max[0] - min[0] + 1) as usize, (max[1] - min[1] + 1) as usize, (max[2] - min[2] + 1) as usize];
+        let mut cells = Map3D::new_with_default(dims, u16::MAX);
+        for (cell, id) in placed {
+            cells.set([(cell[0] - min[0]) as usize, (cell[1] - min[1]) as usize, (cell[2] - min[2]) as usize], id).unwrap();
+        }
+        Ok(cells)
+    }
+
+    pub fn prefab_names(&self)
+    -> Vec<&str> {
+        self.legend.values().map(|name| name.as_str()).collect()
+    }
+}
+
+// What a generation config gives a structure stage
+#[derive(Deserialize)]
+pub struct StructureStageConfig {
+    #[serde(default = "default_structure_layer")]
+    pub layer: String,
+    // cells along x and z of the square regions that each hold at most one structure
+    #[serde(default = "default_region_size")]
+    pub region_size: i64,
+    // chance of a region holding a structure, in [0, 1]
+    #[serde(default = "default_chance")]
+    pub chance: f64,
+    // height of the structures' bottom cells, on the terrain surface when left out
+    pub y: Option<i64>,
+    pub structures: Vec<StructureConfig>,
+}
+
+fn default_structure_layer()
+-> String {
+    "structures".to_string()
+}
+
+fn default_region_size()
+-> i64 {
+    24
+}
+
+fn default_chance()
+-> f64 {
+    0.5
+}
+
+// Scatters structures over the world, one per region at most.
+// Where a region's structure goes is worked out from the seed and the region alone,
+// so every chunk it overlaps stamps its own part and the pieces line up in any order
+pub struct StructurePlacementStage {
+    seed: u64,
+    region_size: i64,
+    chance: f64,
+    y: Option<i64>,
+    structures: Vec<Structure>,
+    // what the surface heights come from when y is left out
+    terrain: Option<Arc<HeightmapTerrainGenerator>>,
+    prefab_names: Vec<String>,
+}
+
+// a structure index and the world cell of its min corner
+type Placement = (usize, na::Vector3<i64>);
+
+impl StructurePlacementStage {
+    pub fn new(config: StructureStageConfig, terrain: Option<Arc<HeightmapTerrainGenerator>>, context: &GenerateContext)
+    -> Result<StructurePlacementStage, String> {
+        if config.region_size <= 0 {
+            return Err(format!("structure region_size must be positive, not {}", config.region_size));
+        }
+        if config.y.is_none() && terrain.is_none() {
+            return Err("structures without a y need a terrain stage before them".to_string());
+        }
+
+        let structures = config.structures.iter().map(|structure| structure.build(context)).collect::<Result<Vec<_>, _>>()?;
+        let prefab_names = config.structures.iter().flat_map(|s| s.prefab_names()).map(|name| name.to_string()).collect();
+
+        Ok(StructurePlacementStage {
+            seed: context.sub_seed(&config.layer),
+            region_size: config.region_size,
+            chance: config.chance,
+            y: config.y,
+            structures,
+            terrain,
+            prefab_names,
+        })
+    }
+
+    // the structure a region holds, if any
+    fn placement(&self, region: [i64 ; 2])
+    -> Option<Placement> {
+        // separate draws for each choice, told apart by the middle coordinate
+        let draw = |n: i64| cell_hash(self.seed, na::Vector3::new(region[0], n, region[1]));
+        let fraction = |hash: u64| (hash >> 11) as f64 / (1u64 << 53) as f64;
+
+        let total_weight: u64 = self.structures.iter().map(|s| s.weight as u64).sum();
+        if total_weight == 0 || fraction(draw(0)) >= self.chance {
+            return None;
+        }
+
+        let mut pick = draw(1) % total_weight;
+        let index = self.structures.iter().position(|s| {
+            if pick < s.weight as u64 { true } else { pick -= s.weight as u64; false }
+        })?;
+        let structure = &self.structures[index];
+        let dims = structure.cells.dims();
+
+        let x = region[0] * self.region_size + (draw(2) % self.region_size as u64) as i64;
+        let z = region[1] * self.region_size + (draw(3) % self.region_size as u64) as i64;
+        let y = match (self.y, &self.terrain) {
+            (Some(y), _) => y,
+            (None, Some(terrain)) => {
+                let (height, _) = terrain.column(x + dims[0] as i64 / 2, z + dims[2] as i64 / 2);
+                height + 1 - structure.sink
+            },
+            (None, None) => return None,
+        };
+
+        Some((index, na::Vector3::new(x, y, z)))
+    }
+}
+
+impl GenerationStage for StructurePlacementStage {
+    fn apply(&self, origin: na::Vector3<i64>, _context: &GenerateContext, cells: &mut Map3D<u16>) {
+        if self.structures.is_empty() {
+            return;
+        }
+        let dims = cells.dims();
+
+        // regions whose structure could reach into the cells, any structure's min corner
+        // can be up to its size before them
+        let reach_x = self.structures.iter().map(|s| s.cells.dims()[0]).max().unwrap() as i64;
+        let reach_z = self.structures.iter().map(|s| s.cells.dims()[2]).max().unwrap() as i64;
+        let region_of = |cell: i64| cell.div_euclid(self.region_size);
+
+        for region_z in region_of(origin.z - reach_z + 1)..=region_of(origin.z + dims[2] as i64 - 1) {
+        for region_x in region_of(origin.x - reach_x + 1)..=region_of(origin.x + dims[0] as i64 - 1) {
+            let (index, min) = match self.placement([region_x, region_z]) {
+                Some(placement) => placement,
+                None => continue,
+            };
+            let structure_cells = &self.structures[index].cells;
+            let structure_dims = structure_cells.dims();
+
+            let offset = min - origin;
+            let overlaps = (0..3).all(|axis| offset[axis] < dims[axis] as i64 && offset[axis] + structure_dims[axis] as i64 > 0);
+            if overlaps {
+                cells.overlay_region(structure_cells, [0 ; 3], structure_dims, [offset.x as i32, offset.y as i32, offset.z as i32], u16::MAX);
+            }
+        }}
+    }
+
+    fn prefab_names(&self)
+    -> Vec<&str> {
+        self.prefab_names.iter().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use nalgebra as na;
+
+    use super::{StructureConfig, StructurePlacementStage, StructureStageConfig};
+    use crate::generation_pipeline::GenerationStage;
+    use crate::map_3D::Map3D;
+    use crate::prefab_registry::PrefabRegistry;
+    use crate::voxel_material::VoxelMaterial;
+    use crate::vox_writer::VoxWriter;
+    use crate::world_generator::GenerateContext;
+
+    fn grid(legend_name: &str)
+    -> StructureConfig {
+        let mut legend = HashMap::new();
+        legend.insert("#".to_string(), legend_name.to_string());
+        StructureConfig {
+            name: "pillar".to_string(),
+            weight: 1,
+            sink: 0,
+            legend,
+            layers: vec![vec!["#.".to_string()], vec!["#".to_string()]],
+            vox: None,
+        }
+    }
+
+    #[test]
+    fn grid_places_legend_prefabs_and_leaves_the_rest() {
+        let registry = PrefabRegistry::placeholder_only();
+        let context = GenerateContext::new(&registry, 0);
+        let structure = grid("placeholder").build(&context).unwrap();
+
+        assert_eq!(structure.cells.dims(), [2, 2, 1]);
+        let id = context.prefab_id("placeholder");
+        assert_eq!(structure.cells.full_slice(), &[id, u16::MAX, id, u16::MAX]);
+    }
+
+    #[test]
+    fn grid_rejects_unknown_prefabs() {
+        let registry = PrefabRegistry::placeholder_only();
+        let context = GenerateContext::new(&registry, 0);
+        assert!(grid("missing").build(&context).is_err());
+    }
+
+    #[test]
+    fn structures_are_seamless_across_chunks_in_any_order() {
+        let registry = PrefabRegistry::placeholder_only();
+        let context = GenerateContext::new(&registry, 7);
+        let mut legend = HashMap::new();
+        legend.insert("#".to_string(), "placeholder".to_string());
+        let block = StructureConfig {
+            name: "block".to_string(),
+            weight: 1,
+            sink: 0,
+            legend,
+            layers: vec![vec!["###".to_string(), "###".to_string()] ; 2],
+            vox: None,
+        };
+        let config = StructureStageConfig {
+            layer: "structures".to_string(),
+            region_size: 4,
+            chance: 1.0,
+            y: Some(-1),
+            structures: vec![block],
+        };
+        let stage = StructurePlacementStage::new(config, None, &context).unwrap();
+
+        let origin = na::Vector3::new(-12, -2, -12);
+        let mut whole = Map3D::new_with_default([24, 4, 24], u16::MAX);
+        stage.apply(origin, &context, &mut whole);
+
+        // structures 3 cells wide in 4 cell regions, some must cross the chunk borders at 8 and 16
+        let id = context.prefab_id("placeholder");
+        let crosses = (0..24).any(|a| (0..4).any(|y| [7, 15].iter().any(|&b| {
+            whole.get([b, y, a]) == Some(id) && whole.get([b + 1, y, a]) == Some(id) ||
+            whole.get([a, y, b]) == Some(id) && whole.get([a, y, b + 1]) == Some(id)
+        })));
+        assert!(crosses);
+
+        let mut chunk_offsets = Vec::new();
+        for z in 0..3 {
+        for x in 0..3 {
+            chunk_offsets.push([x * 8, 0, z * 8]);
+        }}
+        let forward = chunk_offsets.clone();
+        chunk_offsets.reverse();
+        for order in [forward, chunk_offsets].iter() {
+            let mut world = Map3D::new_with_default([24, 4, 24], 0);
+            for &offset in order {
+                let mut chunk = Map3D::new_with_default([8, 4, 8], u16::MAX);
+                stage.apply(origin + na::Vector3::new(offset[0] as i64, offset[1] as i64, offset[2] as i64), &context, &mut chunk);
+                world.blit(&chunk, offset);
+            }
+            assert_eq!(world.full_slice(), whole.full_slice());
+        }
+    }
+
+    // writes 32^3 models at the given wrapper mins and a manifest listing each as its own prefab
+    fn write_scene(name: &str, mins: &[[i32 ; 3]])
+    -> (String, String) {
+        let mut writer = VoxWriter::new();
+        let model = Map3D::new_with_default([32, 32, 32], true);
+        for &min in mins {
+            writer.add_volume(&model, min, |solid| if solid { Some((0xff808080, VoxelMaterial::default())) } else { None }).unwrap();
+        }
+        let vox_path = std::env::temp_dir().join(format!("{}.vox", name));
+        let vox_path = vox_path.to_str().unwrap().to_string();
+        writer.write(&vox_path).unwrap();
+
+        let manifest: String = (0..mins.len())
+            .map(|model| format!("[[prefab]]\nname = \"part_{}\"\npath = {:?}\nmodel = {}\n\n", model, vox_path, model))
+            .collect();
+        let manifest_path = std::env::temp_dir().join(format!("{}.toml", name));
+        let manifest_path = manifest_path.to_str().unwrap().to_string();
+        std::fs::write(&manifest_path, manifest).unwrap();
+        (vox_path, manifest_path)
+    }
+
+    fn vox_structure(vox_path: &str)
+    -> StructureConfig {
+        StructureConfig {
+            name: "scene".to_string(),
+            weight: 1,
+            sink: 0,
+            legend: HashMap::new(),
+            layers: Vec::new(),
+            vox: Some(vox_path.to_string()),
+        }
+    }
+
+    #[test]
+    fn vox_models_become_cells_rounded_to_32() {
+        // off the cell grid by less than half a cell, and below and behind the origin
+        let (vox_path, manifest_path) = write_scene("structure_placement_vox_cells_test", &[[3, -2, 0], [32, 0, -30], [-33, 64, 1]]);
+        let (registry, errors) = PrefabRegistry::load(&manifest_path).unwrap();
+        assert!(errors.is_empty());
+        let context = GenerateContext::new(&registry, 0);
+
+        let structure = vox_structure(&vox_path).build(&context).unwrap();
+        // cells [0, 0, 0], [1, 0, -1] and [-1, 2, 0] moved up by the min [-1, 0, -1]
+        assert_eq!(structure.cells.dims(), [3, 3, 2]);
+        let part = |model: usize| context.prefab_id(&format!("part_{}", model));
+        let mut expected = Map3D::new_with_default([3, 3, 2], u16::MAX);
+        expected.set([1, 0, 1], part(0)).unwrap();
+        expected.set([2, 0, 0], part(1)).unwrap();
+        expected.set([0, 2, 1], part(2)).unwrap();
+        assert_eq!(structure.cells.full_slice(), expected.full_slice());
+    }
+
+    #[test]
+    fn rotated_vox_models_are_rejected() {
+        let (vox_path, manifest_path) = write_scene("structure_placement_rotated_test", &[[0, 0, 0], [32, 0, 0]]);
+        let (registry, _) = PrefabRegistry::load(&manifest_path).unwrap();
+        let context = GenerateContext::new(&registry, 0);
+        assert!(vox_structure(&vox_path).build(&context).is_ok());
+
+        // a later node chunk with the same id replaces the writer's transform of model 1
+        let mut bytes = std::fs::read(&vox_path).unwrap();
+        fn push_string(content: &mut Vec<u8>, s: &str) {
+            content.extend_from_slice(&(s.len() as i32).to_le_bytes());
+            content.extend_from_slice(s.as_bytes());
+        }
+        let mut content = Vec::new();
+        // node id, no attributes, child, reserved, layer, one frame of two entries
+        for &value in [4i32, 0, 5, -1, 0, 1, 2].iter() {
+            content.extend_from_slice(&value.to_le_bytes());
+        }
+        push_string(&mut content, "_r");
+        // x and z swapped
+        push_string(&mut content, &(2 | 1 << 2).to_string());
+        push_string(&mut content, "_t");
+        push_string(&mut content, "48 16 16");
+        bytes.extend_from_slice(b"nTRN");
+        bytes.extend_from_slice(&(content.len() as i32).to_le_bytes());
+        bytes.extend_from_slice(&0i32.to_le_bytes());
+        bytes.extend_from_slice(&content);
+        let children = (bytes.len() - 20) as i32;
+        bytes[16..20].copy_from_slice(&children.to_le_bytes());
+        std::fs::write(&vox_path, bytes).unwrap();
+
+        let error = vox_structure(&vox_path).build(&context).err().unwrap();
+        assert!(error.contains("model 1 is rotated"), "{}", error);
+    }
+}
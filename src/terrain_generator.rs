@@ -0,0 +1,244 @@
+use nalgebra as na;
+use serde::Deserialize;
+
+use crate::map_3D::Map3D;
+use crate::world_generator::{Fbm, GenerateContext, WorldGenerator, PARTITION_LENGTH};
+
+// What a biome is made of and where it sits in the climate.
+// A column takes the biome whose climate is closest to its own
+#[derive(Deserialize)]
+pub struct Biome {
+    pub name: String,
+    pub temperature: f64,
+    pub moisture: f64,
+    // prefab names, the top cell of a column, the layers under it and everything below
+    pub surface: String,
+    pub sub_surface: String,
+    pub deep: String,
+    // cells of sub surface under the surface cell
+    #[serde(default = "default_sub_surface_depth")]
+    pub sub_surface_depth: i64,
+}
+
+fn default_sub_surface_depth() 
+-> i64 {
+    3
+}
+
+// How a generation config shapes the terrain, settings left out keep their defaults
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct TerrainConfig {
+    // surface height in cells where the heightmap is 0
+    pub base_height: f64,
+    // cells the surface rises and falls around base_height
+    pub height_amplitude: f64,
+    pub height: Fbm,
+    pub temperature: Fbm,
+    pub moisture: Fbm,
+    // temperature lost per cell of height above base_height
+    pub lapse_rate: f64,
+    pub biomes: Vec<Biome>,
+}
+
+impl Default for TerrainConfig {
+    fn default() 
+    -> TerrainConfig {
+        let biome = |name: &str, temperature, moisture, surface: &str, sub_surface: &str, deep: &str| Biome {
+            name: name.to_string(),
+            temperature,
+            moisture,
+            surface: surface.to_string(),
+            sub_surface: sub_surface.to_string(),
+            deep: deep.to_string(),
+            sub_surface_depth: default_sub_surface_depth(),
+        };
+        TerrainConfig {
+            base_height: 0.,
+            height_amplitude: 40.,
+            height: Fbm { octaves: 5, frequency: 0.004, ..Fbm::default() },
+            temperature: Fbm { octaves: 2, frequency: 0.002, ..Fbm::default() },
+            moisture: Fbm { octaves: 2, frequency: 0.002, ..Fbm::default() },
+            lapse_rate: 0.01,
+            biomes: vec![
+                biome("meadow", 0., 0.2, "grass", "ridged_stone", "inscribed_stone"),
+                biome("ruins", 0.3, -0.3, "bricks", "bricks", "ridged_stone"),
+                biome("highlands", -0.4, 0., "ridged_stone", "ridged_stone", "inscribed_stone"),
+            ],
+        }
+    }
+}
+
+// a biome's prefab names resolved to ids
+struct BiomeIds {
+    surface: u16,
+    sub_surface: u16,
+    deep: u16,
+    sub_surface_depth: i64,
+}
+
+// Columns of ground under an fBm heightmap, with the prefabs of each
+// column picked by a temperature and moisture biome map.
+// Noise and prefab ids are resolved once for the context it's built with
+pub struct HeightmapTerrainGenerator {
+    pub config: TerrainConfig,
+    biome_ids: Vec<BiomeIds>,
+    height_noise: noise::OpenSimplex,
+    temperature_noise: noise::OpenSimplex,
+    moisture_noise: noise::OpenSimplex,
+}
+
+impl HeightmapTerrainGenerator {
+    pub fn new(config: TerrainConfig, context: &GenerateContext) 
+    -> HeightmapTerrainGenerator {
+        let biome_ids = config.biomes.iter().map(|biome| BiomeIds {
+            surface: context.prefab_id(&biome.surface),
+            sub_surface: context.prefab_id(&biome.sub_surface),
+            deep: context.prefab_id(&biome.deep),
+            sub_surface_depth: biome.sub_surface_depth,
+        }).collect();
+
+        HeightmapTerrainGenerator {
+            config,
+            biome_ids,
+            height_noise: context.noise("height"),
+            temperature_noise: context.noise("temperature"),
+            moisture_noise: context.noise("moisture"),
+        }
+    }
+
+    // The top solid cell of a column and the index into biomes of its biome,
+    // None when there are no biomes
+    pub fn column(&self, x: i64, z: i64) 
+    -> (i64, Option<usize>) {
+        let config = &self.config;
+        let (x_f64, z_f64) = (x as f64, z as f64);
+        let height = (config.base_height + config.height.sample(&self.height_noise, x_f64, z_f64) * config.height_amplitude).floor() as i64;
+
+        // higher ground is colder
+        let temperature = 
+            config.temperature.sample(&self.temperature_noise, x_f64, z_f64)
+            - (height as f64 - config.base_height).max(0.) * config.lapse_rate;
+        let moisture = config.moisture.sample(&self.moisture_noise, x_f64, z_f64);
+
+        let distance_sqr = |biome: &Biome| (biome.temperature - temperature).powi(2) + (biome.moisture - moisture).powi(2);
+        let biome = (0..config.biomes.len())
+            .min_by(|&a, &b| distance_sqr(&config.biomes[a]).total_cmp(&distance_sqr(&config.biomes[b])));
+
+        (height, biome)
+    }
+
+    // fills cells of any size, origin is the world cell of cells' [0, 0, 0]
+    pub fn fill(&self, origin: na::Vector3<i64>, cells: &mut Map3D<u16>) {
+        let dims = cells.dims();
+
+        // the heightmap and biome map only depend on x and z, so are worked out once per column
+        let mut columns = Vec::with_capacity(dims[0] * dims[2]);
+        for z in 0..dims[2] {
+        for x in 0..dims[0] {
+            columns.push(self.column(origin.x + x as i64, origin.z + z as i64));
+        }}
+
+        cells.set_all(&|coords| {
+            let (height, biome) = columns[coords[0] + coords[2] * dims[0]];
+            let depth = height - (origin.y + coords[1] as i64);
+            match biome.map(|b| &self.biome_ids[b]) {
+                Some(_) if depth < 0 => u16::MAX,
+                Some(ids) if depth == 0 => ids.surface,
+                Some(ids) if depth <= ids.sub_surface_depth => ids.sub_surface,
+                Some(ids) => ids.deep,
+                None => u16::MAX,
+            }
+        });
+    }
+}
+
+impl WorldGenerator for HeightmapTerrainGenerator {
+    fn generate(&self, partition_coords: na::Vector3<i32>, _context: &GenerateContext, partition: &mut Map3D<u16>) {
+        self.fill(partition_coords.map(|c| c as i64 * PARTITION_LENGTH as i64), partition);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use nalgebra as na;
+
+    use super::{default_sub_surface_depth, Biome, HeightmapTerrainGenerator, TerrainConfig};
+    use crate::map_3D::Map3D;
+    use crate::prefab_registry::{PrefabRegistry, PREFAB_MANIFEST_PATH};
+    use crate::world_generator::GenerateContext;
+
+    fn biome(name: &str, temperature: f64, moisture: f64)
+    -> Biome {
+        Biome {
+            name: name.to_string(),
+            temperature,
+            moisture,
+            surface: "grass".to_string(),
+            sub_surface: "bricks".to_string(),
+            deep: "inscribed_stone".to_string(),
+            sub_surface_depth: default_sub_surface_depth(),
+        }
+    }
+
+    #[test]
+    fn flat_column_is_layered_by_depth() {
+        let registry = PrefabRegistry::load(PREFAB_MANIFEST_PATH).unwrap().0;
+        let context = GenerateContext::new(&registry, 11);
+        let config = TerrainConfig {
+            base_height: 5.5,
+            height_amplitude: 0.,
+            biomes: vec![Biome { sub_surface_depth: 2, ..biome("only", 0., 0.) }],
+            ..TerrainConfig::default()
+        };
+        let terrain = HeightmapTerrainGenerator::new(config, &context);
+        assert_eq!(terrain.column(-40, 13), (5, Some(0)));
+
+        let origin = na::Vector3::new(3, -2, -4);
+        let mut cells = Map3D::new([2, 10, 2]);
+        terrain.fill(origin, &mut cells);
+
+        let (grass, bricks, stone) = (context.prefab_id("grass"), context.prefab_id("bricks"), context.prefab_id("inscribed_stone"));
+        for (coords, value) in cells.iter() {
+            let expected = match origin.y + coords[1] as i64 {
+                6..=7 => u16::MAX,
+                5 => grass,
+                3..=4 => bricks,
+                _ => stone,
+            };
+            assert_eq!(value, expected, "{:?}", coords);
+        }
+    }
+
+    #[test]
+    fn columns_take_the_nearest_biome() {
+        let registry = PrefabRegistry::placeholder_only();
+        let context = GenerateContext::new(&registry, 11);
+
+        // climate noise stays around [-1, 1], so only height can carry a column to the far biome,
+        // listed first so a column doesn't just get the first one
+        let config = TerrainConfig {
+            lapse_rate: 100.,
+            biomes: vec![biome("peaks", -1000., 0.), biome("lowland", 0., 0.)],
+            ..TerrainConfig::default()
+        };
+        let terrain = HeightmapTerrainGenerator::new(config, &context);
+
+        let (mut peaks, mut lowland) = (0, 0);
+        for z in (0..2000).step_by(40) {
+        for x in (0..2000).step_by(40) {
+            let (height, biome) = terrain.column(x, z);
+            if height <= 0 {
+                assert_eq!(biome, Some(1), "{} {}", x, z);
+                lowland += 1;
+            } else if height >= 10 {
+                assert_eq!(biome, Some(0), "{} {}", x, z);
+                peaks += 1;
+            }
+        }}
+        assert!(peaks > 0 && lowland > 0, "{} peaks, {} lowland", peaks, lowland);
+
+        let no_biomes = HeightmapTerrainGenerator::new(TerrainConfig { biomes: Vec::new(), ..TerrainConfig::default() }, &context);
+        assert_eq!(no_biomes.column(0, 0).1, None);
+    }
+}
@@ -0,0 +1,484 @@
+// Scene graph of a MagicaVoxel file (nTRN, nGRP and nSHP chunks).
+// dot_vox only reads models and palettes, so the node chunks are read here
+// and flattened into one placed instance per shape model.
+// Instances are given in the wrapper's orientation (y and z swapped from MagicaVoxel)
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+// rotation is a signed permutation matrix, rows applied to column vectors
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VoxTransform {
+    pub rotation: [[i32 ; 3] ; 3],
+    pub translation: [i32 ; 3],
+}
+
+impl VoxTransform {
+    pub const IDENTITY: VoxTransform = VoxTransform {
+        rotation: [[1, 0, 0], [0, 1, 0], [0, 0, 1]],
+        translation: [0, 0, 0],
+    };
+
+    pub fn apply_rotation(&self, v: [i32 ; 3]) 
+    -> [i32 ; 3] {
+        let r = &self.rotation;
+        [
+            r[0][0] * v[0] + r[0][1] * v[1] + r[0][2] * v[2],
+            r[1][0] * v[0] + r[1][1] * v[1] + r[1][2] * v[2],
+            r[2][0] * v[0] + r[2][1] * v[1] + r[2][2] * v[2],
+        ]
+    }
+
+    pub fn apply(&self, v: [i32 ; 3]) 
+    -> [i32 ; 3] {
+        let rotated = self.apply_rotation(v);
+        [
+            rotated[0] + self.translation[0],
+            rotated[1] + self.translation[1],
+            rotated[2] + self.translation[2],
+        ]
+    }
+
+    // self applied after child
+    pub fn then(&self, child: &VoxTransform) 
+    -> VoxTransform {
+        let mut rotation = [[0 ; 3] ; 3];
+        for row in 0..3 {
+        for col in 0..3 {
+            rotation[row][col] = (0..3).map(|k| self.rotation[row][k] * child.rotation[k][col]).sum();
+        }}
+        VoxTransform {
+            rotation,
+            translation: self.apply(child.translation),
+        }
+    }
+
+    // the _r byte of a frame: row indices in bits 0-1 and 2-3, row signs in bits 4-6
+    fn from_rotation_byte(byte: u8) 
+    -> VoxTransform {
+        let first = (byte & 3) as usize;
+        let second = ((byte >> 2) & 3) as usize;
+        let third = (0..3).find(|&c| c != first && c != second).unwrap_or(2);
+        let mut rotation = [[0 ; 3] ; 3];
+        for (row, &col) in [first, second, third].iter().enumerate() {
+            rotation[row][col.min(2)] = if byte & (1 << (4 + row)) != 0 { -1 } else { 1 };
+        }
+        VoxTransform { rotation, translation: [0 ; 3] }
+    }
+
+    // swaps y and z on both sides, moving a MagicaVoxel transform into the wrapper's orientation
+    fn swap_y_z(&self) 
+    -> VoxTransform {
+        let s = [0, 2, 1];
+        let mut rotation = [[0 ; 3] ; 3];
+        for row in 0..3 {
+        for col in 0..3 {
+            rotation[row][col] = self.rotation[s[row]][s[col]];
+        }}
+        VoxTransform {
+            rotation,
+            translation: [self.translation[0], self.translation[2], self.translation[1]],
+        }
+    }
+}
+
+// a model placed by the scene graph
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelInstance {
+    pub model_index: usize,
+    // world transform of the model's center
+    pub transform: VoxTransform,
+    pub name: Option<String>,
+    pub hidden: bool,
+}
+
+impl ModelInstance {
+    // like MagicaVoxel, models rotate about the voxel at half their size
+    pub fn voxel_to_world(&self, local: [usize ; 3], model_dims: [usize ; 3]) 
+    -> [i32 ; 3] {
+        self.transform.apply([
+            local[0] as i32 - (model_dims[0] / 2) as i32,
+            local[1] as i32 - (model_dims[1] / 2) as i32,
+            local[2] as i32 - (model_dims[2] / 2) as i32,
+        ])
+    }
+
+    // inclusive min and exclusive max of the placed model
+    pub fn world_bounds(&self, model_dims: [usize ; 3]) 
+    -> ([i32 ; 3], [i32 ; 3]) {
+        let a = self.voxel_to_world([0 ; 3], model_dims);
+        let b = self.voxel_to_world(
+            [model_dims[0].max(1) - 1, model_dims[1].max(1) - 1, model_dims[2].max(1) - 1], model_dims);
+        let mut min = [0 ; 3];
+        let mut max = [0 ; 3];
+        for axis in 0..3 {
+            min[axis] = a[axis].min(b[axis]);
+            max[axis] = a[axis].max(b[axis]) + 1;
+        }
+        (min, max)
+    }
+}
+
+enum SceneNode {
+    Transform { attributes: HashMap<String, String>, child: i32, frame: HashMap<String, String> },
+    Group { children: Vec<i32> },
+    Shape { models: Vec<i32> },
+}
+
+// Flattens the scene graph of raw .vox bytes.
+// Files without a scene graph place every model at the origin
+pub fn parse_instances(bytes: &[u8], model_count: usize) 
+-> Result<Vec<ModelInstance>, &'static str> {
+    let nodes = parse_nodes(bytes)?;
+
+    if nodes.is_empty() {
+        return Ok(
+            (0..model_count)
+            .map(|model_index| ModelInstance {
+                model_index,
+                transform: VoxTransform::IDENTITY,
+                name: None,
+                hidden: false,
+            })
+            .collect()
+        );
+    }
+
+    let mut instances = Vec::new();
+    // (node id, parent transform, name, hidden)
+    let mut stack = vec![(0, VoxTransform::IDENTITY, None, false)];
+    let mut visited = 0;
+
+    while let Some((node_id, parent, name, hidden)) = stack.pop() {
+        visited += 1;
+        if visited > nodes.len() * 4 + 16 {
+            return Err("scene graph contains a cycle");
+        }
+
+        match nodes.get(&node_id).ok_or("scene graph references a missing node")? {
+            SceneNode::Transform { attributes, child, frame } => {
+                let mut local = 
+                    frame.get("_r")
+                    .and_then(|r| r.parse::<u8>().ok())
+                    .map_or(VoxTransform::IDENTITY, VoxTransform::from_rotation_byte);
+                if let Some(t) = frame.get("_t") {
+                    let values: Vec<i32> = t.split_whitespace().filter_map(|v| v.parse().ok()).collect();
+                    if values.len() == 3 {
+                        local.translation = [values[0], values[1], values[2]];
+                    }
+                }
+                let name = attributes.get("_name").cloned().or(name);
+                let hidden = hidden || attributes.get("_hidden").map_or(false, |h| h == "1");
+                stack.push((*child, parent.then(&local), name, hidden));
+            },
+            SceneNode::Group { children } => {
+                for &child in children.iter().rev() {
+                    stack.push((child, parent, name.clone(), hidden));
+                }
+            },
+            SceneNode::Shape { models } => {
+                for &model in models {
+                    if model < 0 || model as usize >= model_count {
+                        return Err("shape node references a missing model");
+                    }
+                    instances.push(ModelInstance {
+                        model_index: model as usize,
+                        transform: parent.swap_y_z(),
+                        name: name.clone(),
+                        hidden,
+                    });
+                }
+            },
+        }
+    }
+
+    Ok(instances)
+}
+
+fn parse_nodes(bytes: &[u8]) 
+-> Result<HashMap<i32, SceneNode>, &'static str> {
+    let mut reader = Reader { bytes, pos: 0 };
+    if reader.take(4)? != b"VOX " {
+        return Err("not a vox file");
+    }
+    let _version = reader.i32()?;
+
+    // MAIN holds every other chunk as a child
+    if reader.take(4)? != b"MAIN" {
+        return Err("missing MAIN chunk");
+    }
+    let main_content = reader.i32()? as usize;
+    let _main_children = reader.i32()?;
+    reader.take(main_content)?;
+
+    let mut nodes = HashMap::new();
+    while reader.pos < bytes.len() {
+        let id: [u8 ; 4] = reader.take(4)?.try_into().map_err(|_| "truncated chunk id")?;
+        let content_size = reader.i32()? as usize;
+        let children_size = reader.i32()? as usize;
+        let content = reader.take(content_size)?;
+        reader.take(children_size)?;
+
+        let mut chunk = Reader { bytes: content, pos: 0 };
+        match &id {
+            b"nTRN" => {
+                let node_id = chunk.i32()?;
+                let attributes = chunk.dict()?;
+                let child = chunk.i32()?;
+                let _reserved = chunk.i32()?;
+                let _layer = chunk.i32()?;
+                let frame_count = chunk.i32()?;
+                let frame = if frame_count > 0 { chunk.dict()? } else { HashMap::new() };
+                nodes.insert(node_id, SceneNode::Transform { attributes, child, frame });
+            },
+            b"nGRP" => {
+                let node_id = chunk.i32()?;
+                let _attributes = chunk.dict()?;
+                let child_count = chunk.i32()?;
+                let children = (0..child_count.max(0)).map(|_| chunk.i32()).collect::<Result<_, _>>()?;
+                nodes.insert(node_id, SceneNode::Group { children });
+            },
+            b"nSHP" => {
+                let node_id = chunk.i32()?;
+                let _attributes = chunk.dict()?;
+                let model_count = chunk.i32()?;
+                let mut models = Vec::new();
+                for _ in 0..model_count.max(0) {
+                    models.push(chunk.i32()?);
+                    let _model_attributes = chunk.dict()?;
+                }
+                nodes.insert(node_id, SceneNode::Shape { models });
+            },
+            _ => {},
+        }
+    }
+
+    Ok(nodes)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) 
+    -> Result<&'a [u8], &'static str> {
+        let end = self.pos.checked_add(len).ok_or("chunk size overflow")?;
+        let slice = self.bytes.get(self.pos..end).ok_or("unexpected end of vox data")?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn i32(&mut self) 
+    -> Result<i32, &'static str> {
+        let bytes = self.take(4)?;
+        Ok(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn string(&mut self) 
+    -> Result<String, &'static str> {
+        let len = self.i32()?;
+        if len < 0 {
+            return Err("negative string length");
+        }
+        Ok(String::from_utf8_lossy(self.take(len as usize)?).into_owned())
+    }
+
+    fn dict(&mut self) 
+    -> Result<HashMap<String, String>, &'static str> {
+        let count = self.i32()?;
+        let mut dict = HashMap::new();
+        for _ in 0..count.max(0) {
+            let key = self.string()?;
+            let value = self.string()?;
+            dict.insert(key, value);
+        }
+        Ok(dict)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dict(out: &mut Vec<u8>, entries: &[(&str, &str)]) {
+        out.extend_from_slice(&(entries.len() as i32).to_le_bytes());
+        for (key, value) in entries {
+            for s in [key, value].iter() {
+                out.extend_from_slice(&(s.len() as i32).to_le_bytes());
+                out.extend_from_slice(s.as_bytes());
+            }
+        }
+    }
+
+    fn ints(out: &mut Vec<u8>, values: &[i32]) {
+        for value in values {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn chunk(out: &mut Vec<u8>, id: &[u8 ; 4], content: &[u8]) {
+        out.extend_from_slice(id);
+        ints(out, &[content.len() as i32, 0]);
+        out.extend_from_slice(content);
+    }
+
+    fn transform(out: &mut Vec<u8>, node_id: i32, child: i32, attributes: &[(&str, &str)], frame: &[(&str, &str)]) {
+        let mut content = Vec::new();
+        ints(&mut content, &[node_id]);
+        dict(&mut content, attributes);
+        ints(&mut content, &[child, -1, 0, 1]);
+        dict(&mut content, frame);
+        chunk(out, b"nTRN", &content);
+    }
+
+    fn group(out: &mut Vec<u8>, node_id: i32, children: &[i32]) {
+        let mut content = Vec::new();
+        ints(&mut content, &[node_id]);
+        dict(&mut content, &[]);
+        ints(&mut content, &[children.len() as i32]);
+        ints(&mut content, children);
+        chunk(out, b"nGRP", &content);
+    }
+
+    fn shape(out: &mut Vec<u8>, node_id: i32, model: i32) {
+        let mut content = Vec::new();
+        ints(&mut content, &[node_id]);
+        dict(&mut content, &[]);
+        ints(&mut content, &[1, model]);
+        dict(&mut content, &[]);
+        chunk(out, b"nSHP", &content);
+    }
+
+    // the header and an empty MAIN, node chunks follow as its children
+    fn vox(nodes: &[u8])
+    -> Vec<u8> {
+        let mut bytes = b"VOX ".to_vec();
+        ints(&mut bytes, &[150]);
+        bytes.extend_from_slice(b"MAIN");
+        ints(&mut bytes, &[0, nodes.len() as i32]);
+        bytes.extend_from_slice(nodes);
+        bytes
+    }
+
+    type Dict<'a> = &'a [(&'a str, &'a str)];
+
+    // root transform and group, one child transform and shape per model,
+    // given the attributes and frame of each model's transform
+    fn scene(frames: &[(Dict, Dict)])
+    -> Vec<u8> {
+        let mut nodes = Vec::new();
+        transform(&mut nodes, 0, 1, &[], &[]);
+        let children: Vec<i32> = (0..frames.len() as i32).map(|i| 2 + 2 * i).collect();
+        group(&mut nodes, 1, &children);
+        for (i, (attributes, frame)) in frames.iter().enumerate() {
+            let node_id = 2 + 2 * i as i32;
+            transform(&mut nodes, node_id, node_id + 1, attributes, frame);
+            shape(&mut nodes, node_id + 1, i as i32);
+        }
+        vox(&nodes)
+    }
+
+    #[test]
+    fn rotation_bytes_decode_to_signed_permutations() {
+        let cases: [(u8, [[i32 ; 3] ; 3]) ; 4] = [
+            (4, [[1, 0, 0], [0, 1, 0], [0, 0, 1]]),
+            // 90 degrees about MagicaVoxel's z
+            (1 | 1 << 4, [[0, -1, 0], [1, 0, 0], [0, 0, 1]]),
+            (4 | 0b111 << 4, [[-1, 0, 0], [0, -1, 0], [0, 0, -1]]),
+            (2 | 1 << 2 | 1 << 6, [[0, 0, 1], [0, 1, 0], [-1, 0, 0]]),
+        ];
+        for &(byte, rotation) in cases.iter() {
+            assert_eq!(VoxTransform::from_rotation_byte(byte).rotation, rotation, "byte {}", byte);
+        }
+    }
+
+    #[test]
+    fn nested_transforms_compose_into_wrapper_orientation() {
+        let mut nodes = Vec::new();
+        transform(&mut nodes, 0, 1, &[], &[("_t", "10 0 0")]);
+        group(&mut nodes, 1, &[2]);
+        transform(&mut nodes, 2, 3, &[("_name", "door")], &[("_r", "17"), ("_t", "0 5 -2")]);
+        shape(&mut nodes, 3, 0);
+
+        let instances = parse_instances(&vox(&nodes), 1).unwrap();
+        assert_eq!(instances.len(), 1);
+        let instance = &instances[0];
+        assert_eq!(instance.name.as_deref(), Some("door"));
+        // the z turn of MagicaVoxel is a turn about y, its translation swapped to x, z, y
+        assert_eq!(instance.transform, VoxTransform {
+            rotation: [[0, 0, -1], [0, 1, 0], [1, 0, 0]],
+            translation: [10, -2, 5],
+        });
+        // model x goes to wrapper z, model z to wrapper -x
+        assert_eq!(instance.voxel_to_world([2, 1, 1], [3, 2, 3]), [10, -2, 6]);
+        assert_eq!(instance.voxel_to_world([1, 1, 2], [3, 2, 3]), [9, -2, 5]);
+        assert_eq!(instance.world_bounds([3, 2, 3]), ([9, -3, 4], [12, -1, 7]));
+    }
+
+    #[test]
+    fn hidden_transforms_hide_their_models() {
+        let bytes = scene(&[(&[], &[]), (&[("_hidden", "1")], &[]), (&[("_hidden", "0")], &[])]);
+        let instances = parse_instances(&bytes, 3).unwrap();
+        let hidden: Vec<(usize, bool)> = instances.iter().map(|i| (i.model_index, i.hidden)).collect();
+        assert_eq!(hidden, vec![(0, false), (1, true), (2, false)]);
+
+        // a hidden parent hides every model under it
+        let mut nodes = Vec::new();
+        transform(&mut nodes, 0, 1, &[("_hidden", "1")], &[]);
+        group(&mut nodes, 1, &[2, 3]);
+        shape(&mut nodes, 2, 0);
+        shape(&mut nodes, 3, 1);
+        let instances = parse_instances(&vox(&nodes), 2).unwrap();
+        assert_eq!(instances.len(), 2);
+        assert!(instances.iter().all(|i| i.hidden));
+    }
+
+    #[test]
+    fn files_without_nodes_place_models_at_the_origin() {
+        let instances = parse_instances(&vox(&[]), 2).unwrap();
+        assert_eq!(instances.len(), 2);
+        for (i, instance) in instances.iter().enumerate() {
+            assert_eq!(instance.model_index, i);
+            assert_eq!(instance.transform, VoxTransform::IDENTITY);
+        }
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        let bytes = scene(&[(&[], &[("_t", "1 2 3")])]);
+        assert!(parse_instances(&bytes, 1).is_ok());
+        // cut short of the first node there are no nodes at all, which is a file without a scene
+        let header = vox(&[]).len();
+        for len in 0..bytes.len() {
+            assert_eq!(parse_instances(&bytes[..len], 1).is_ok(), len == header, "cut at {}", len);
+        }
+    }
+
+    #[test]
+    fn malformed_files_are_errors() {
+        assert!(parse_instances(b"RIFF\x96\x00\x00\x00", 1).is_err());
+
+        // shape of a model the file doesn't have
+        assert!(parse_instances(&scene(&[(&[], &[])]), 0).is_err());
+
+        // group holding the root
+        let mut nodes = Vec::new();
+        transform(&mut nodes, 0, 1, &[], &[]);
+        group(&mut nodes, 1, &[0]);
+        assert!(parse_instances(&vox(&nodes), 1).is_err());
+
+        // negative string length in a dict
+        let mut content = Vec::new();
+        ints(&mut content, &[0, 1, -5]);
+        let mut nodes = Vec::new();
+        chunk(&mut nodes, b"nTRN", &content);
+        assert!(parse_instances(&vox(&nodes), 1).is_err());
+
+        // chunk claiming more content than the file has
+        let mut nodes = b"nGRP".to_vec();
+        ints(&mut nodes, &[-1, 0]);
+        assert!(parse_instances(&vox(&nodes), 1).is_err());
+    }
+}
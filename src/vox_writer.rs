@@ -0,0 +1,400 @@
+// Writes MagicaVoxel .vox files (version 150).
+// Volumes are given in the wrapper's orientation and swapped back to MagicaVoxel's z up on write.
+// Every model gets its own transform node under one group, so a file can hold a multi model region.
+// Colors and materials of all models share one palette of at most 255 entries,
+// palette index 0 is empty in MagicaVoxel
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use nalgebra as na;
+
+use crate::bit_voxels::BitVoxels;
+use crate::displaced_chunks::{ChunkData, DisplacedChunks};
+use crate::map_3D::Map3D;
+use crate::standard_voxel_prefab::StandardVoxelPrefab;
+use crate::voxel_material::{MaterialKind, VoxelMaterial};
+
+// largest model MagicaVoxel opens, bigger volumes are split
+const MAX_MODEL_LENGTH: usize = 256;
+const MAX_PALETTE_ENTRIES: usize = 255;
+const PARTITION_CUBE_LENGTH: i64 = 32 * 32;
+const PREFAB_LENGTH: i64 = 32;
+
+struct VoxModel {
+    // file palette index - 1, u8::MAX is empty
+    volume: Map3D<u8>,
+    // wrapper coordinates of the model's min corner
+    min: [i32 ; 3],
+}
+
+#[derive(Default)]
+pub struct VoxWriter {
+    models: Vec<VoxModel>,
+    entries: Vec<(u32, VoxelMaterial)>,
+    index_of_entry: HashMap<(u32, [u32 ; 7]), u8>,
+}
+
+impl VoxWriter {
+    pub fn new()
+    -> VoxWriter {
+        VoxWriter::default()
+    }
+
+    // Adds a volume placed with its min corner at min.
+    // entry_fn gives the color and material of a voxel, None is empty.
+    // A volume that does not fit the palette leaves the writer as it was
+    pub fn add_volume<T: Clone + Default + Copy>(&mut self, volume: &Map3D<T>, min: [i32 ; 3], entry_fn: impl Fn(T) -> Option<(u32, VoxelMaterial)>)
+    -> io::Result<()> {
+        let mut new_entries = Vec::new();
+        let mut new_indices = HashMap::new();
+        for coords in volume.coords_iter() {
+            let entry = match volume.get(coords).and_then(&entry_fn) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let key = (entry.0, entry.1.key());
+            if self.index_of_entry.contains_key(&key) || new_indices.contains_key(&key) {
+                continue;
+            }
+            let index = self.entries.len() + new_entries.len();
+            if index >= MAX_PALETTE_ENTRIES {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "more than 255 distinct colors and materials"));
+            }
+            new_indices.insert(key, index as u8);
+            new_entries.push(entry);
+        }
+        self.entries.extend(new_entries);
+        self.index_of_entry.extend(new_indices);
+
+        let dims = volume.dims();
+        for block_z in (0..dims[2]).step_by(MAX_MODEL_LENGTH) {
+        for block_y in (0..dims[1]).step_by(MAX_MODEL_LENGTH) {
+        for block_x in (0..dims[0]).step_by(MAX_MODEL_LENGTH) {
+            let block_min = [block_x, block_y, block_z];
+            let block_dims = [
+                (dims[0] - block_x).min(MAX_MODEL_LENGTH),
+                (dims[1] - block_y).min(MAX_MODEL_LENGTH),
+                (dims[2] - block_z).min(MAX_MODEL_LENGTH),
+            ];
+
+            let mut block = Map3D::new_with_default(block_dims, u8::MAX);
+            for coords in block.coords_iter().collect::<Vec<_>>() {
+                let source = [coords[0] + block_min[0], coords[1] + block_min[1], coords[2] + block_min[2]];
+                if let Some(entry) = volume.get(source).and_then(&entry_fn) {
+                    block.set(coords, self.index_of_entry[&(entry.0, entry.1.key())]).unwrap();
+                }
+            }
+
+            self.models.push(VoxModel {
+                volume: block,
+                min: [
+                    min[0] + block_min[0] as i32,
+                    min[1] + block_min[1] as i32,
+                    min[2] + block_min[2] as i32,
+                ],
+            });
+        }}}
+
+        Ok(())
+    }
+
+    #[allow(dead_code)]
+    pub fn add_prefab(&mut self, prefab: &StandardVoxelPrefab, min: [i32 ; 3])
+    -> io::Result<()> {
+        self.add_volume(&prefab.palette_volume, min, |i|
+            prefab_entry(prefab, i)
+        )
+    }
+
+    // palette_volume holds indices into palette and materials, u16::MAX is empty
+    #[allow(dead_code)]
+    pub fn add_palette_volume(&mut self, palette_volume: &Map3D<u16>, palette: &[u32 ; 256], materials: &[VoxelMaterial ; 256], min: [i32 ; 3])
+    -> io::Result<()> {
+        self.add_volume(palette_volume, min, |i|
+            palette.get(i as usize).map(|&color| (color, materials[i as usize]))
+        )
+    }
+
+    // BitVoxels carry no color, so every voxel gets the same one
+    #[allow(dead_code)]
+    pub fn add_bit_voxels(&mut self, bit_voxels: &BitVoxels, color: u32, min: [i32 ; 3])
+    -> io::Result<()> {
+        let mut volume = Map3D::new(bit_voxels.dims());
+        volume.set_all(&|coords| bit_voxels.get_voxel(coords));
+        self.add_volume(&volume, min, |present|
+            if present { Some((color, VoxelMaterial::default())) } else { None }
+        )
+    }
+
+    // Adds a box of the loaded world, size in cubes from min_world_voxel.
+    // Cubes of partitions that are not generated yet are left empty
+    pub fn add_world_region<T: ChunkData>(&mut self, chunks: &DisplacedChunks<T>, prefabs: &[StandardVoxelPrefab], min_world_voxel: na::Vector3<i64>, size: [usize ; 3])
+    -> io::Result<()> {
+        let mut region = Map3D::new_with_default(size, None);
+        region.set_all(&|coords| {
+            let world_voxel = min_world_voxel + na::Vector3::new(coords[0] as i64, coords[1] as i64, coords[2] as i64);
+            let partition_coords = world_voxel.map(|v| v.div_euclid(PARTITION_CUBE_LENGTH) as i32);
+            let in_partition = world_voxel.map(|v| v.rem_euclid(PARTITION_CUBE_LENGTH));
+
+            let chunk = chunks.chunk_data(chunks.partition_id(partition_coords)?)?;
+            let prefab = prefabs.get(chunk.voxel_index([
+                (in_partition.x / PREFAB_LENGTH) as usize,
+                (in_partition.y / PREFAB_LENGTH) as usize,
+                (in_partition.z / PREFAB_LENGTH) as usize,
+            ]) as usize)?;
+            let palette_index = prefab.palette_volume.get([
+                (in_partition.x % PREFAB_LENGTH) as usize,
+                (in_partition.y % PREFAB_LENGTH) as usize,
+                (in_partition.z % PREFAB_LENGTH) as usize,
+            ])?;
+
+            prefab_entry(prefab, palette_index)
+        });
+
+        // the file keeps the world position, wrapped into MagicaVoxel's i32 range
+        let min = [min_world_voxel.x as i32, min_world_voxel.y as i32, min_world_voxel.z as i32];
+        self.add_volume(&region, min, |entry| entry)
+    }
+
+    pub fn to_bytes(&self)
+    -> Vec<u8> {
+        let mut children = Vec::new();
+
+        for model in &self.models {
+            let dims = model.volume.dims();
+            // MagicaVoxel is z up
+            write_chunk(&mut children, b"SIZE", &i32_bytes(&[dims[0] as i32, dims[2] as i32, dims[1] as i32]));
+
+            let voxels: Vec<_> =
+                model.volume.coords_iter()
+                .filter_map(|coords| {
+                    let index = model.volume.get(coords)?;
+                    if index == u8::MAX { None } else { Some((coords, index)) }
+                })
+                .collect();
+            let mut xyzi = i32_bytes(&[voxels.len() as i32]);
+            for (coords, index) in voxels {
+                xyzi.extend_from_slice(&[coords[0] as u8, coords[2] as u8, coords[1] as u8, index + 1]);
+            }
+            write_chunk(&mut children, b"XYZI", &xyzi);
+        }
+
+        self.write_scene_graph(&mut children);
+
+        // entry i of RGBA is palette index i + 1
+        let mut rgba = Vec::with_capacity(256 * 4);
+        for i in 0..256 {
+            let color = self.entries.get(i).map_or(0, |entry| entry.0);
+            rgba.extend_from_slice(&color.to_le_bytes());
+        }
+        write_chunk(&mut children, b"RGBA", &rgba);
+
+        for (i, (_, material)) in self.entries.iter().enumerate() {
+            let mut matl = i32_bytes(&[i as i32 + 1]);
+            write_dict(&mut matl, &material_properties(material));
+            write_chunk(&mut children, b"MATL", &matl);
+        }
+
+        let mut bytes = Vec::with_capacity(children.len() + 20);
+        bytes.extend_from_slice(b"VOX ");
+        bytes.extend_from_slice(&150i32.to_le_bytes());
+        bytes.extend_from_slice(b"MAIN");
+        bytes.extend_from_slice(&0i32.to_le_bytes());
+        bytes.extend_from_slice(&(children.len() as i32).to_le_bytes());
+        bytes.extend_from_slice(&children);
+        bytes
+    }
+
+    // root transform (0) -> group (1) -> one transform (2 + 2i) and shape (3 + 2i) per model
+    fn write_scene_graph(&self, out: &mut Vec<u8>) {
+        write_transform(out, 0, 1, None);
+
+        let mut group = i32_bytes(&[1]);
+        write_dict(&mut group, &[]);
+        group.extend(i32_bytes(&[self.models.len() as i32]));
+        for i in 0..self.models.len() {
+            group.extend(i32_bytes(&[2 + 2 * i as i32]));
+        }
+        write_chunk(out, b"nGRP", &group);
+
+        for (i, model) in self.models.iter().enumerate() {
+            let dims = model.volume.dims();
+            // MagicaVoxel places a model by the voxel at half its size
+            let center = [
+                model.min[0] + (dims[0] / 2) as i32,
+                model.min[1] + (dims[1] / 2) as i32,
+                model.min[2] + (dims[2] / 2) as i32,
+            ];
+            let node_id = 2 + 2 * i as i32;
+            write_transform(out, node_id, node_id + 1, Some([center[0], center[2], center[1]]));
+
+            let mut shape = i32_bytes(&[node_id + 1]);
+            write_dict(&mut shape, &[]);
+            shape.extend(i32_bytes(&[1, i as i32]));
+            write_dict(&mut shape, &[]);
+            write_chunk(out, b"nSHP", &shape);
+        }
+
+        // the one layer every model transform is on
+        let mut layer = i32_bytes(&[0]);
+        write_dict(&mut layer, &[]);
+        layer.extend(i32_bytes(&[-1]));
+        write_chunk(out, b"LAYR", &layer);
+    }
+
+    pub fn write(&self, path: &str)
+    -> io::Result<()> {
+        std::fs::File::create(path)?.write_all(&self.to_bytes())
+    }
+}
+
+fn prefab_entry(prefab: &StandardVoxelPrefab, palette_index: u16)
+-> Option<(u32, VoxelMaterial)> {
+    let i = palette_index as usize;
+    prefab.palette.get(i).map(|&color| (color, prefab.materials[i]))
+}
+
+fn material_properties(material: &VoxelMaterial)
+-> Vec<(String, String)> {
+    let kind = match material.kind {
+        MaterialKind::Diffuse => "_diffuse",
+        MaterialKind::Metal => "_metal",
+        MaterialKind::Glass => "_glass",
+        MaterialKind::Emissive => "_emit",
+        MaterialKind::Blend => "_blend",
+        MaterialKind::Media => "_media",
+    };
+    vec![
+        ("_type".to_string(), kind.to_string()),
+        ("_rough".to_string(), material.roughness.to_string()),
+        ("_metal".to_string(), material.metalness.to_string()),
+        ("_trans".to_string(), material.transparency.to_string()),
+        ("_ri".to_string(), material.ior.to_string()),
+        ("_ior".to_string(), (material.ior - 1.0).to_string()),
+        ("_emit".to_string(), material.emission.to_string()),
+        ("_flux".to_string(), material.flux.to_string()),
+    ]
+}
+
+fn write_transform(out: &mut Vec<u8>, node_id: i32, child: i32, translation: Option<[i32 ; 3]>) {
+    let mut content = i32_bytes(&[node_id]);
+    write_dict(&mut content, &[]);
+    // child, reserved, layer, frame count
+    content.extend(i32_bytes(&[child, -1, if translation.is_some() { 0 } else { -1 }, 1]));
+    let frame: Vec<(String, String)> =
+        translation
+        .map(|t| ("_t".to_string(), format!("{} {} {}", t[0], t[1], t[2])))
+        .into_iter()
+        .collect();
+    write_dict(&mut content, &frame);
+    write_chunk(out, b"nTRN", &content);
+}
+
+fn write_chunk(out: &mut Vec<u8>, id: &[u8 ; 4], content: &[u8]) {
+    out.extend_from_slice(id);
+    out.extend_from_slice(&(content.len() as i32).to_le_bytes());
+    out.extend_from_slice(&0i32.to_le_bytes());
+    out.extend_from_slice(content);
+}
+
+fn write_dict(out: &mut Vec<u8>, dict: &[(String, String)]) {
+    out.extend(i32_bytes(&[dict.len() as i32]));
+    for (key, value) in dict {
+        for s in &[key, value] {
+            out.extend(i32_bytes(&[s.len() as i32]));
+            out.extend_from_slice(s.as_bytes());
+        }
+    }
+}
+
+fn i32_bytes(values: &[i32])
+-> Vec<u8> {
+    values.iter().flat_map(|v| v.to_le_bytes().to_vec()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vox_scene;
+
+    const RED: u32 = 0xff0000ff;
+    const GREEN: u32 = 0xff00ff00;
+    const BLUE: u32 = 0xffff0000;
+
+    #[test]
+    fn written_volume_reads_back() {
+        let mut palette = [0u32 ; 256];
+        palette[..3].copy_from_slice(&[RED, GREEN, BLUE]);
+        let mut materials = [VoxelMaterial::default() ; 256];
+        materials[1] = VoxelMaterial { kind: MaterialKind::Metal, roughness: 0.25, metalness: 0.5, ..VoxelMaterial::default() };
+
+        // non cubic, so a missed y/z swap shows up in the dims
+        let mut volume = Map3D::new_with_default([3, 2, 4], u16::MAX);
+        let voxels = [([0, 0, 0], 0), ([2, 1, 3], 1), ([1, 0, 2], 2), ([2, 0, 0], 1)];
+        for &(coords, index) in voxels.iter() {
+            volume.set(coords, index).unwrap();
+        }
+
+        let mut writer = VoxWriter::new();
+        writer.add_palette_volume(&volume, &palette, &materials, [5, -3, 7]).unwrap();
+        let bytes = writer.to_bytes();
+
+        let data = dot_vox::load_bytes(&bytes).unwrap();
+        assert_eq!(data.models.len(), 1);
+        let size = data.models[0].size;
+        assert_eq!([size.x, size.y, size.z], [3, 4, 2]);
+
+        // dot_vox gives file index - 1, which indexes RGBA directly
+        let mut read: Vec<([usize ; 3], u32)> = 
+            data.models[0].voxels
+            .iter()
+            .map(|v| ([v.x as usize, v.z as usize, v.y as usize], data.palette[v.i as usize]))
+            .collect();
+        read.sort();
+        let mut expected: Vec<([usize ; 3], u32)> = 
+            voxels.iter().map(|&(coords, index)| (coords, palette[index as usize])).collect();
+        expected.sort();
+        assert_eq!(read, expected);
+
+        // one MATL per palette entry, ids are file palette indices
+        assert_eq!(data.materials.len(), 3);
+        for material in &data.materials {
+            let color = data.palette[material.id as usize - 1];
+            let local = palette[..3].iter().position(|&c| c == color).unwrap();
+            assert_eq!(VoxelMaterial::from_properties(&material.properties), materials[local], "{:x}", color);
+        }
+
+        let instances = vox_scene::parse_instances(&bytes, 1).unwrap();
+        assert_eq!(instances.len(), 1);
+        assert_eq!(instances[0].model_index, 0);
+        assert!(!instances[0].hidden);
+        // the model is placed by the voxel at half its size
+        assert_eq!(instances[0].transform.translation, [6, -2, 9]);
+        assert_eq!(instances[0].world_bounds([3, 2, 4]), ([5, -3, 7], [8, -1, 11]));
+    }
+
+    #[test]
+    fn volume_over_the_palette_leaves_the_writer_unchanged() {
+        let colors = |first: u32, count: usize| {
+            let mut volume = Map3D::new([count, 1, 1]);
+            volume.set_all(&|coords| first + coords[0] as u32);
+            volume
+        };
+        let entry = |color: u32| Some((color, VoxelMaterial::default()));
+
+        let mut writer = VoxWriter::new();
+        writer.add_volume(&colors(0, 250), [0 ; 3], entry).unwrap();
+
+        let error = writer.add_volume(&colors(1000, 10), [0 ; 3], entry).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(writer.entries.len(), 250);
+        assert_eq!(writer.index_of_entry.len(), 250);
+        assert_eq!(writer.models.len(), 1);
+
+        // the five entries left still fit, colors already in the palette are free
+        writer.add_volume(&colors(245, 10), [0 ; 3], entry).unwrap();
+        assert_eq!(writer.entries.len(), MAX_PALETTE_ENTRIES);
+        assert_eq!(writer.models.len(), 2);
+    }
+}
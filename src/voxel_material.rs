@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+// Material types of MagicaVoxel's MATL chunk, the value is what the shaders branch on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MaterialKind
+{
+    Diffuse = 0,
+    Metal = 1,
+    Glass = 2,
+    Emissive = 3,
+    Blend = 4,
+    Media = 5,
+}
+
+// Surface properties of one palette entry.
+// Values missing from a MATL chunk keep the plain diffuse defaults
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VoxelMaterial
+{
+    pub kind : MaterialKind,
+    pub roughness : f32,
+    pub metalness : f32,
+    // 0 is opaque
+    pub transparency : f32,
+    // index of refraction itself, not MagicaVoxel's ior - 1
+    pub ior : f32,
+    pub emission : f32,
+    // MagicaVoxel's power exponent of emission
+    pub flux : f32
+}
+
+pub const DIFFUSE : VoxelMaterial = VoxelMaterial 
+{
+    kind : MaterialKind::Diffuse,
+    roughness : 1.0,
+    metalness : 0.0,
+    transparency : 0.0,
+    ior : 1.0,
+    emission : 0.0,
+    flux : 0.0,
+};
+
+// texels per material in the material texture
+pub const MATERIAL_TEXELS : usize = 2;
+
+impl Default for VoxelMaterial
+{
+    fn default()
+        -> VoxelMaterial
+    {
+        DIFFUSE
+    }
+}
+
+impl VoxelMaterial
+{
+    // from the properties dict of a MATL chunk
+    pub fn from_properties(properties : &HashMap<String, String>)
+        -> VoxelMaterial
+    {
+        let float = |key : &str| properties.get(key).and_then(|value| value.trim().parse::<f32>().ok());
+
+        let kind = match properties.get("_type").map(|s| s.as_str())
+        {
+            Some("_metal") => MaterialKind::Metal,
+            Some("_glass") => MaterialKind::Glass,
+            Some("_emit") => MaterialKind::Emissive,
+            Some("_blend") => MaterialKind::Blend,
+            Some("_media") => MaterialKind::Media,
+            _ => MaterialKind::Diffuse,
+        };
+
+        // older files only store the strength of the type as _weight
+        let weight = float("_weight");
+        let weight_for = |for_kind : MaterialKind| if kind == for_kind { weight } else { None };
+
+        VoxelMaterial 
+        {
+            kind,
+            roughness : float("_rough").unwrap_or(DIFFUSE.roughness),
+            metalness : float("_metal").or_else(|| weight_for(MaterialKind::Metal)).unwrap_or(DIFFUSE.metalness),
+            transparency : 
+                float("_trans")
+                .or_else(|| float("_alpha"))
+                .or_else(|| weight_for(MaterialKind::Glass))
+                .unwrap_or(DIFFUSE.transparency),
+            ior : 
+                float("_ri")
+                .or_else(|| float("_ior").map(|ior| ior + 1.0))
+                .unwrap_or(DIFFUSE.ior),
+            emission : float("_emit").or_else(|| weight_for(MaterialKind::Emissive)).unwrap_or(DIFFUSE.emission),
+            flux : float("_flux").unwrap_or(DIFFUSE.flux),
+        }
+    }
+
+    // identity of a material for deduplication, floats compared by their bits
+    pub fn key(&self)
+        -> [u32 ; 7]
+    {
+        [
+            self.kind as u32,
+            self.roughness.to_bits(),
+            self.metalness.to_bits(),
+            self.transparency.to_bits(),
+            self.ior.to_bits(),
+            self.emission.to_bits(),
+            self.flux.to_bits(),
+        ]
+    }
+
+    // the material's MATERIAL_TEXELS rgba texels:
+    // (kind, roughness, metalness, transparency), (ior, emission, flux, 0)
+    pub fn texels(&self)
+        -> [[f32 ; 4] ; MATERIAL_TEXELS]
+    {
+        [
+            [self.kind as u32 as f32, self.roughness, self.metalness, self.transparency],
+            [self.ior, self.emission, self.flux, 0.0],
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use std::collections::HashMap;
+
+    use super::{MaterialKind, VoxelMaterial, DIFFUSE};
+
+    #[test]
+    fn properties_become_materials()
+    {
+        let cases : &[(&[(&str, &str)], VoxelMaterial)] = &[
+            (&[], DIFFUSE),
+            (&[("_type", "_diffuse")], DIFFUSE),
+            (&[("_type", "_metal"), ("_rough", "0.25"), ("_metal", "0.75")],
+                VoxelMaterial { kind : MaterialKind::Metal, roughness : 0.25, metalness : 0.75, ..DIFFUSE }),
+            (&[("_type", "_emit"), ("_emit", "0.5"), ("_flux", "2")],
+                VoxelMaterial { kind : MaterialKind::Emissive, emission : 0.5, flux : 2.0, ..DIFFUSE }),
+            (&[("_type", "_glass"), ("_trans", "0.5"), ("_ri", "1.5")],
+                VoxelMaterial { kind : MaterialKind::Glass, transparency : 0.5, ior : 1.5, ..DIFFUSE }),
+            (&[("_type", "_blend")], VoxelMaterial { kind : MaterialKind::Blend, ..DIFFUSE }),
+            (&[("_type", "_media")], VoxelMaterial { kind : MaterialKind::Media, ..DIFFUSE }),
+            // _ior is stored as ior - 1, _ri is the index itself and wins
+            (&[("_ior", "0.3")], VoxelMaterial { ior : 1.3, ..DIFFUSE }),
+            (&[("_ior", "0.3"), ("_ri", "2")], VoxelMaterial { ior : 2.0, ..DIFFUSE }),
+            // older files only give a _weight for the type
+            (&[("_type", "_metal"), ("_weight", "0.5")], VoxelMaterial { kind : MaterialKind::Metal, metalness : 0.5, ..DIFFUSE }),
+            (&[("_type", "_glass"), ("_alpha", "0.25")], VoxelMaterial { kind : MaterialKind::Glass, transparency : 0.25, ..DIFFUSE }),
+            (&[("_weight", "0.5")], DIFFUSE),
+            (&[("_rough", " 0.5 ")], VoxelMaterial { roughness : 0.5, ..DIFFUSE }),
+            // malformed values keep the defaults
+            (&[("_type", "_plastic")], DIFFUSE),
+            (&[("_type", "_metal"), ("_metal", "shiny"), ("_rough", "")],
+                VoxelMaterial { kind : MaterialKind::Metal, ..DIFFUSE }),
+            (&[("_ri", "1,5"), ("_emit", "0.5.1"), ("_flux", "-")], DIFFUSE),
+        ];
+
+        for (properties, expected) in cases.iter()
+        {
+            let map : HashMap<String, String> = properties.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect();
+            assert_eq!(VoxelMaterial::from_properties(&map), *expected, "{:?}", properties);
+        }
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+use nalgebra as na;
+use noise::{NoiseFn, Seedable};
+use serde::Deserialize;
+
+use crate::map_3D::Map3D;
+use crate::prefab_registry::PrefabRegistry;
+
+pub const PARTITION_LENGTH: usize = 32;
+
+// Everything generation needs that outlives a single chunk,
+// shared by whichever generator is in use
+pub struct GenerateContext {
+    // the world seed, every random choice is derived from it
+    pub seed: u64,
+    // snapshot of the registry's names, generators look prefabs up by name
+    prefab_ids: HashMap<String, u16>,
+    // listed prefabs by the .vox path and model they come from
+    model_ids: HashMap<(String, usize), u16>,
+}
+
+impl GenerateContext {
+    pub fn new(registry: &PrefabRegistry, seed: u64) 
+    -> GenerateContext {
+        let model_ids = 
+            registry.ids_by_name()
+            .values()
+            .filter_map(|&id| registry.entry(id).map(|entry| ((entry.path.clone(), entry.model), id)))
+            .collect();
+        GenerateContext {
+            seed,
+            prefab_ids: registry.ids_by_name().clone(),
+            model_ids,
+        }
+    }
+
+    // Seed of one generator layer, so layers are independent of each other
+    // and adding a layer doesn't change the ones already there
+    pub fn sub_seed(&self, layer: &str) 
+    -> u64 {
+        mix(self.seed ^ hash_bytes(layer.as_bytes()))
+    }
+
+    // seed of a layer in one partition, for choices made per chunk
+    pub fn chunk_seed(&self, layer: &str, partition_coords: na::Vector3<i32>) 
+    -> u64 {
+        let mut seed = self.sub_seed(layer);
+        for &c in partition_coords.iter() {
+            seed = mix(seed ^ c as u32 as u64);
+        }
+        seed
+    }
+
+    // noise of a layer, builds its permutation tables so stages make theirs once up front
+    pub fn noise(&self, layer: &str) 
+    -> noise::OpenSimplex {
+        noise::OpenSimplex::new().set_seed(self.sub_seed(layer) as u32)
+    }
+
+    // u16::MAX (empty) when the manifest lacks the prefab
+    pub fn prefab_id(&self, name: &str) 
+    -> u16 {
+        self.prefab_ids.get(name).copied().unwrap_or(u16::MAX)
+    }
+
+    // the listed prefab made from a model of a .vox file, u16::MAX when none is
+    pub fn model_prefab_id(&self, path: &str, model: usize) 
+    -> u16 {
+        self.model_ids.get(&(path.to_string(), model)).copied().unwrap_or(u16::MAX)
+    }
+}
+
+// Fills partitions of the world with prefab ids.
+// Generators must give the same partition for the same coords and seed,
+// chunks are generated lazily, in any order and on several threads at once,
+// so nothing may carry over between calls
+pub trait WorldGenerator: Send + Sync {
+    // partition is PARTITION_LENGTH^3 and every cell must be written, u16::MAX is empty
+    fn generate(&self, partition_coords: na::Vector3<i32>, context: &GenerateContext, partition: &mut Map3D<u16>);
+}
+
+// Fractal noise settings, octaves are summed with rising frequency and falling amplitude
+#[derive(Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct Fbm {
+    pub octaves: u32,
+    // cells to noise space of the first octave
+    pub frequency: f64,
+    pub lacunarity: f64,
+    pub gain: f64,
+}
+
+impl Default for Fbm {
+    fn default() 
+    -> Fbm {
+        Fbm {
+            octaves: 1,
+            frequency: 0.1,
+            lacunarity: 2.,
+            gain: 0.5,
+        }
+    }
+}
+
+impl Fbm {
+    // roughly in [-1, 1] whatever the octave count
+    pub fn sample(&self, noise: &noise::OpenSimplex, x: f64, z: f64) 
+    -> f64 {
+        self.sum(|frequency| noise.get([x * frequency, z * frequency]))
+    }
+
+    pub fn sample_3d(&self, noise: &noise::OpenSimplex, coords: [f64 ; 3]) 
+    -> f64 {
+        self.sum(|frequency| noise.get([coords[0] * frequency, coords[1] * frequency, coords[2] * frequency]))
+    }
+
+    fn sum(&self, octave: impl Fn(f64) -> f64) 
+    -> f64 {
+        let (mut sum, mut total_amplitude) = (0., 0.);
+        let (mut frequency, mut amplitude) = (self.frequency, 1.);
+        for _ in 0..self.octaves {
+            sum += octave(frequency) * amplitude;
+            total_amplitude += amplitude;
+            frequency *= self.lacunarity;
+            amplitude *= self.gain;
+        }
+        if total_amplitude > 0. { sum / total_amplitude } else { 0. }
+    }
+}
+
+// A value for one cell of a layer, the same whichever chunk asks for it
+pub fn cell_hash(seed: u64, world_coords: na::Vector3<i64>) 
+-> u64 {
+    world_coords.iter().fold(seed, |hash, &c| mix(hash ^ c as u64))
+}
+
+// Stable across runs and platforms, for checking generation is deterministic
+pub fn chunk_hash(partition: &Map3D<u16>) 
+-> u64 {
+    let mut hash = FNV_OFFSET;
+    for &dim in partition.dims().iter() {
+        hash = fnv_step(hash, &(dim as u64).to_le_bytes());
+    }
+    for &value in partition.full_slice() {
+        hash = fnv_step(hash, &value.to_le_bytes());
+    }
+    hash
+}
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+// std's hashers are free to change between releases, so these are written out
+fn fnv_step(hash: u64, bytes: &[u8]) 
+-> u64 {
+    bytes.iter().fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(FNV_PRIME))
+}
+
+fn hash_bytes(bytes: &[u8]) 
+-> u64 {
+    fnv_step(FNV_OFFSET, bytes)
+}
+
+// splitmix64 finalizer, spreads nearby inputs across all bits
+fn mix(mut x: u64) 
+-> u64 {
+    x = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::thread;
+
+    use nalgebra as na;
+
+    use super::{chunk_hash, GenerateContext, WorldGenerator, PARTITION_LENGTH};
+    use crate::map_3D::Map3D;
+    use crate::prefab_registry::{PrefabRegistry, PREFAB_MANIFEST_PATH};
+    use crate::terrain_generator::{HeightmapTerrainGenerator, TerrainConfig};
+
+    // coords around the surface so chunks hold ground and air
+    fn coords()
+    -> Vec<na::Vector3<i32>> {
+        let mut coords = Vec::new();
+        for z in -1..2 {
+        for y in -1..1 {
+        for x in -1..2 {
+            coords.push(na::Vector3::new(x, y, z));
+        }}}
+        coords
+    }
+
+    fn hashes(generator: &dyn WorldGenerator, context: &GenerateContext, coords: &[na::Vector3<i32>])
+    -> HashMap<[i32 ; 3], u64> {
+        coords.iter().map(|c| {
+            let mut partition = Map3D::new([PARTITION_LENGTH ; 3]);
+            generator.generate(*c, context, &mut partition);
+            ([c.x, c.y, c.z], chunk_hash(&partition))
+        }).collect()
+    }
+
+    #[test]
+    fn chunk_hash_independent_of_order_and_thread() {
+        let registry = PrefabRegistry::load(PREFAB_MANIFEST_PATH).unwrap().0;
+        let context = Arc::new(GenerateContext::new(&registry, 1234));
+        let generator = Arc::new(HeightmapTerrainGenerator::new(TerrainConfig::default(), &context));
+
+        let forward = coords();
+        let mut backward = forward.clone();
+        backward.reverse();
+        let in_order = hashes(generator.as_ref(), &context, &forward);
+        assert_eq!(in_order, hashes(generator.as_ref(), &context, &backward));
+
+        // half the chunks each on two threads at once
+        let (front, back) = forward.split_at(forward.len() / 2);
+        let threads: Vec<_> = 
+            vec![front.to_vec(), back.to_vec()].into_iter()
+            .map(|coords| {
+                let (generator, context) = (generator.clone(), context.clone());
+                thread::spawn(move || hashes(generator.as_ref(), &context, &coords))
+            })
+            .collect();
+        let mut threaded = HashMap::new();
+        for thread in threads {
+            threaded.extend(thread.join().unwrap());
+        }
+        assert_eq!(in_order, threaded);
+
+        // not all empty or all solid, which would hash the same under any seed
+        let distinct: std::collections::HashSet<_> = in_order.values().collect();
+        assert!(distinct.len() > 2);
+    }
+
+    #[test]
+    fn chunk_hash_differs_between_seeds() {
+        let registry = PrefabRegistry::load(PREFAB_MANIFEST_PATH).unwrap().0;
+        let generate = |seed| {
+            let context = GenerateContext::new(&registry, seed);
+            let generator = HeightmapTerrainGenerator::new(TerrainConfig::default(), &context);
+            hashes(&generator, &context, &coords())
+        };
+
+        assert_ne!(generate(1234), generate(1235));
+    }
+}